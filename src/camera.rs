@@ -1,6 +1,6 @@
 use std::f32::consts::TAU;
 
-use cgmath::{vec3, Matrix4, Quaternion, Rotation3, Vector3, Vector4};
+use cgmath::{vec3, vec4, InnerSpace, Matrix, Matrix4, Quaternion, Rotation3, Vector3, Vector4};
 
 use crate::{symmetry::Symmetry, world::N};
 
@@ -70,6 +70,11 @@ impl Camera {
         (yaw * pitch) * vec3(0.0, 0.0, 1.0)
     }
 
+    /// What the camera sees on a screen of the given size in pixels.
+    pub fn view(&self, width: u32, height: u32) -> View {
+        View::new(self.symmetry(), self.fovy, width, height)
+    }
+
     pub fn view_matrix(&self) -> Matrix4<f32> {
         let yaw = Quaternion::from_angle_z(cgmath::Rad(self.yaw));
         let pitch = Quaternion::from_angle_y(cgmath::Rad(self.pitch));
@@ -96,3 +101,122 @@ pub fn perspective_matrix(fovy: f32, aspect: f32, near: f32, far: Option<f32>) -
     };
     m * Y_UP
 }
+
+/// Planes bounding the view of a camera, with their normals pointing inwards.
+/// There is no far plane, since the generation radius limits the view instead.
+#[derive(Debug, Clone, Copy)]
+pub struct Frustum([Vector4<f32>; 5]);
+
+impl Frustum {
+    /// Frustum of a model-view-projection matrix, in the model space of the matrix.
+    pub fn new(matrix: Matrix4<f32>) -> Self {
+        let m = matrix.transpose();
+        Self([
+            m[3] + m[0], // left
+            m[3] - m[0], // right
+            m[3] + m[1], // bottom
+            m[3] - m[1], // top
+            m[3] + m[2], // near
+        ])
+    }
+
+    /// Whether a box may be partially visible, which is the case if
+    /// ∀ plane ∈ planes: ∃ vertex ∈ box: dist(plane, vertex) > 0.
+    /// Boxes near the edges of the frustum may pass even if they are not visible.
+    pub fn intersects(&self, min: Vector3<f32>, max: Vector3<f32>) -> bool {
+        self.0.iter().all(|plane| {
+            (0..8).any(|i| {
+                let corner = |axis: usize| {
+                    if i >> axis & 1 == 1 {
+                        max[axis]
+                    } else {
+                        min[axis]
+                    }
+                };
+                plane.dot(vec4(corner(0), corner(1), corner(2), 1.0)) > 0.0
+            })
+        })
+    }
+}
+
+/// How the camera projects the world onto the screen, to judge how much a chunk matters.
+#[derive(Debug, Clone, Copy)]
+pub struct View {
+    pub position: Vector3<f32>,
+    pub frustum: Frustum,
+    /// Screen height in pixels over the height of the view at a distance of one voxel.
+    pixels_per_unit: f32,
+}
+
+impl View {
+    /// View through a camera with the given world-to-view symmetry and vertical field of
+    /// view in degrees, onto a screen of the given size in pixels.
+    pub fn new(symmetry: Symmetry, fovy: f32, width: u32, height: u32) -> Self {
+        let fovy = fovy.to_radians();
+        let proj = perspective_matrix(fovy, width as f32 / height as f32, 0.1, None);
+        Self {
+            position: symmetry.inverse().translation,
+            frustum: Frustum::new(proj * symmetry.matrix()),
+            pixels_per_unit: height as f32 / (2.0 * (0.5 * fovy).tan()),
+        }
+    }
+
+    /// Distance from the camera to the closest point of a box, zero if it lies within.
+    pub fn distance(&self, min: Vector3<f32>, max: Vector3<f32>) -> f32 {
+        let closest = vec3(
+            self.position.x.clamp(min.x, max.x),
+            self.position.y.clamp(min.y, max.y),
+            self.position.z.clamp(min.z, max.z),
+        );
+        (closest - self.position).magnitude()
+    }
+
+    /// Projected size in pixels of a geometric error at the point of a box closest to the
+    /// camera, which covers the screen if the camera lies within the box.
+    pub fn screen_space_error(&self, error: f32, min: Vector3<f32>, max: Vector3<f32>) -> f32 {
+        error * self.pixels_per_unit / self.distance(min, max).max(1.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn boxes_in_view() {
+        let camera = Camera {
+            translation: vec3(0.0, 0.0, 0.0),
+            yaw: 0.0,
+            pitch: 0.0,
+            fovy: 90.0,
+        };
+        let view = camera.view(800, 600);
+        let unit = vec3(1.0, 1.0, 1.0);
+        let forward = 10.0 * camera.forward();
+
+        assert!(view.frustum.intersects(forward - unit, forward + unit));
+        assert!(!view.frustum.intersects(-forward - unit, -forward + unit));
+        assert!(!view.frustum.intersects(
+            forward + 20.0 * camera.up() - unit,
+            forward + 20.0 * camera.up() + unit
+        ));
+        // Boxes around the camera are visible.
+        assert!(view.frustum.intersects(-unit, unit));
+
+        // With a vertical field of view of 90°, the view is 18 voxels high at the face of the
+        // box nine voxels away.
+        let error = view.screen_space_error(1.0, forward - unit, forward + unit);
+        assert!((error - 600.0 / 18.0).abs() < 1e-3);
+        assert_eq!(view.screen_space_error(1.0, -unit, unit), 300.0);
+
+        // The view is placed at the camera.
+        let translation = vec3(5.0, -3.0, 2.0);
+        let view = Camera {
+            translation,
+            yaw: 1.0,
+            ..camera
+        }
+        .view(800, 600);
+        assert!((view.position - translation).magnitude() < 1e-5);
+    }
+}
@@ -1,3 +1,5 @@
+pub mod bitmask;
+
 use std::fmt::Debug;
 
 use bitflags::bitflags;
@@ -101,6 +103,86 @@ impl<T: Copy, const D: usize> Field<T, D> {
     }
 }
 
+/// How the voxels of each block of 2ᴰ voxels are combined when a field is downsampled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Downsample {
+    /// Solid if any voxel is solid, so that thin features are kept.
+    /// Densities take the maximum.
+    #[default]
+    Conservative,
+    /// Solid if more than half of the voxels are solid.
+    /// Densities take the lower median, which is solid exactly if the majority is.
+    Majority,
+    /// Solid if at least half of the voxels are solid, i.e. the rounded mean.
+    /// Densities take the mean.
+    Average,
+}
+
+impl Downsample {
+    pub const ALL: [Downsample; 3] = [
+        Downsample::Conservative,
+        Downsample::Majority,
+        Downsample::Average,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Downsample::Conservative => "Conservative",
+            Downsample::Majority => "Majority",
+            Downsample::Average => "Average",
+        }
+    }
+}
+
+/// Values which can be combined by a [`Downsample`] policy.
+pub trait Reduce: Copy {
+    /// Combine the voxels of a block, whose order may change.
+    fn reduce(block: &mut [Self], policy: Downsample) -> Self;
+}
+
+impl Reduce for bool {
+    fn reduce(block: &mut [bool], policy: Downsample) -> bool {
+        let solid = block.iter().filter(|&&b| b).count();
+        match policy {
+            Downsample::Conservative => solid > 0,
+            Downsample::Majority => 2 * solid > block.len(),
+            Downsample::Average => 2 * solid >= block.len(),
+        }
+    }
+}
+
+impl Reduce for f32 {
+    fn reduce(block: &mut [f32], policy: Downsample) -> f32 {
+        match policy {
+            Downsample::Conservative => block.iter().copied().fold(f32::NEG_INFINITY, f32::max),
+            Downsample::Majority => {
+                block.sort_by(|a, b| b.total_cmp(a));
+                block[block.len() / 2]
+            }
+            Downsample::Average => block.iter().sum::<f32>() / block.len() as f32,
+        }
+    }
+}
+
+impl<T: Reduce, const D: usize> Field<T, D> {
+    /// Halve the extent, combining each block of 2ᴰ voxels with the given policy.
+    /// The block of voxel `c` starts at `2 * c`.
+    pub fn downsample(&self, policy: Downsample) -> Self {
+        assert!(
+            self.extent.is_multiple_of(2),
+            "only fields with an even extent can be downsampled"
+        );
+        let mut block = Vec::with_capacity(1 << D);
+        Field::new(self.extent / 2, |c| {
+            block.clear();
+            block.extend(
+                (0..1 << D).map(|i| self[std::array::from_fn(|a| 2 * c[a] + (i >> a & 1))]),
+            );
+            T::reduce(&mut block, policy)
+        })
+    }
+}
+
 bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
     pub struct Vis: u8 {
@@ -146,48 +228,63 @@ bitflags! {
     }
 }
 
+impl Env {
+    /// Flag of the neighbour at the given offset, whose components are -1, 0 or 1.
+    pub fn neighbour(offset: [isize; 3]) -> Env {
+        // Flags are ordered like base 3 numbers with digits Z = 0, P = 1 and N = 2.
+        let index = offset
+            .iter()
+            .fold(0, |index, &o| 3 * index + o.rem_euclid(3));
+        Env::from_bits_retain(1 << index)
+    }
+}
+
 impl Field<bool, 3> {
     /// Compute the direct neighourhood of each voxel.
     pub fn environment(&self) -> Field<Env, 3> {
-        self.map_with_coordinate(|set, [x, y, z]| {
-            let mut env = Env::empty();
-
-            let xp = x < self.extent - 1;
-            let xn = x > 0;
-            let yp = y < self.extent - 1;
-            let yn = y > 0;
-            let zp = z < self.extent - 1;
-            let zn = z > 0;
+        self.map_with_coordinate(|_, c| self.environment_at(c))
+    }
 
-            env.set(Env::ZZZ, set);
-            env.set(Env::ZZP, zp && self[[x, y, z + 1]]);
-            env.set(Env::ZZN, zn && self[[x, y, z - 1]]);
-            env.set(Env::ZPZ, yp && self[[x, y + 1, z]]);
-            env.set(Env::ZPP, yp && zp && self[[x, y + 1, z + 1]]);
-            env.set(Env::ZPN, yp && zn && self[[x, y + 1, z - 1]]);
-            env.set(Env::ZNZ, yn && self[[x, y - 1, z]]);
-            env.set(Env::ZNP, yn && zp && self[[x, y - 1, z + 1]]);
-            env.set(Env::ZNN, yn && zn && self[[x, y - 1, z - 1]]);
-            env.set(Env::PZZ, xp && self[[x + 1, y, z]]);
-            env.set(Env::PZP, xp && zp && self[[x + 1, y, z + 1]]);
-            env.set(Env::PZN, xp && zn && self[[x + 1, y, z - 1]]);
-            env.set(Env::PPZ, xp && yp && self[[x + 1, y + 1, z]]);
-            env.set(Env::PPP, xp && yp && zp && self[[x + 1, y + 1, z + 1]]);
-            env.set(Env::PPN, xp && yp && zn && self[[x + 1, y + 1, z - 1]]);
-            env.set(Env::PNZ, xp && yn && self[[x + 1, y - 1, z]]);
-            env.set(Env::PNP, xp && yn && zp && self[[x + 1, y - 1, z + 1]]);
-            env.set(Env::PNN, xp && yn && zn && self[[x + 1, y - 1, z - 1]]);
-            env.set(Env::NZZ, xn && self[[x - 1, y, z]]);
-            env.set(Env::NZP, xn && zp && self[[x - 1, y, z + 1]]);
-            env.set(Env::NZN, xn && zn && self[[x - 1, y, z - 1]]);
-            env.set(Env::NPZ, xn && yp && self[[x - 1, y + 1, z]]);
-            env.set(Env::NPP, xn && yp && zp && self[[x - 1, y + 1, z + 1]]);
-            env.set(Env::NPN, xn && yp && zn && self[[x - 1, y + 1, z - 1]]);
-            env.set(Env::NNZ, xn && yn && self[[x - 1, y - 1, z]]);
-            env.set(Env::NNP, xn && yn && zp && self[[x - 1, y - 1, z + 1]]);
-            env.set(Env::NNN, xn && yn && zn && self[[x - 1, y - 1, z - 1]]);
-            env
-        })
+    /// Direct neighbourhood of a single voxel, see [`Field::environment`].
+    pub fn environment_at(&self, [x, y, z]: [usize; 3]) -> Env {
+        let set = self[[x, y, z]];
+        let mut env = Env::empty();
+
+        let xp = x < self.extent - 1;
+        let xn = x > 0;
+        let yp = y < self.extent - 1;
+        let yn = y > 0;
+        let zp = z < self.extent - 1;
+        let zn = z > 0;
+
+        env.set(Env::ZZZ, set);
+        env.set(Env::ZZP, zp && self[[x, y, z + 1]]);
+        env.set(Env::ZZN, zn && self[[x, y, z - 1]]);
+        env.set(Env::ZPZ, yp && self[[x, y + 1, z]]);
+        env.set(Env::ZPP, yp && zp && self[[x, y + 1, z + 1]]);
+        env.set(Env::ZPN, yp && zn && self[[x, y + 1, z - 1]]);
+        env.set(Env::ZNZ, yn && self[[x, y - 1, z]]);
+        env.set(Env::ZNP, yn && zp && self[[x, y - 1, z + 1]]);
+        env.set(Env::ZNN, yn && zn && self[[x, y - 1, z - 1]]);
+        env.set(Env::PZZ, xp && self[[x + 1, y, z]]);
+        env.set(Env::PZP, xp && zp && self[[x + 1, y, z + 1]]);
+        env.set(Env::PZN, xp && zn && self[[x + 1, y, z - 1]]);
+        env.set(Env::PPZ, xp && yp && self[[x + 1, y + 1, z]]);
+        env.set(Env::PPP, xp && yp && zp && self[[x + 1, y + 1, z + 1]]);
+        env.set(Env::PPN, xp && yp && zn && self[[x + 1, y + 1, z - 1]]);
+        env.set(Env::PNZ, xp && yn && self[[x + 1, y - 1, z]]);
+        env.set(Env::PNP, xp && yn && zp && self[[x + 1, y - 1, z + 1]]);
+        env.set(Env::PNN, xp && yn && zn && self[[x + 1, y - 1, z - 1]]);
+        env.set(Env::NZZ, xn && self[[x - 1, y, z]]);
+        env.set(Env::NZP, xn && zp && self[[x - 1, y, z + 1]]);
+        env.set(Env::NZN, xn && zn && self[[x - 1, y, z - 1]]);
+        env.set(Env::NPZ, xn && yp && self[[x - 1, y + 1, z]]);
+        env.set(Env::NPP, xn && yp && zp && self[[x - 1, y + 1, z + 1]]);
+        env.set(Env::NPN, xn && yp && zn && self[[x - 1, y + 1, z - 1]]);
+        env.set(Env::NNZ, xn && yn && self[[x - 1, y - 1, z]]);
+        env.set(Env::NNP, xn && yn && zp && self[[x - 1, y - 1, z + 1]]);
+        env.set(Env::NNN, xn && yn && zn && self[[x - 1, y - 1, z - 1]]);
+        env
     }
 
     pub fn shell(&self, env: &Field<Env, 3>) -> Field<bool, 3> {
@@ -276,25 +373,22 @@ impl<const D: usize> Field<Vector3<f32>, D> {
 }
 
 impl Field<f32, 2> {
+    /// Upward normals of a height field, whose heights are in units of the sample spacing.
     pub fn normal(&self) -> Field<Vector3<f32>, 2> {
+        let slope = |a: [usize; 2], b: [usize; 2], d: usize| (self[b] - self[a]) / d as f32;
         Field::new(self.extent, |[x, y]| {
-            let dx = self[[(x + 1).min(self.extent - 1), y]] - self[[x.saturating_sub(1), y]];
-            let dy = self[[x, (y + 1).min(self.extent - 1)]] - self[[x, y.saturating_sub(1)]];
-            vec3(dx, dy, 1.0).normalize()
+            let (x0, x1) = (x.saturating_sub(1), (x + 1).min(self.extent - 1));
+            let (y0, y1) = (y.saturating_sub(1), (y + 1).min(self.extent - 1));
+            let dx = slope([x0, y], [x1, y], x1 - x0);
+            let dy = slope([x, y0], [x, y1], y1 - y0);
+            vec3(-dx, -dy, 1.0).normalize()
         })
     }
 }
 
 impl Field<f32, 2> {
     pub fn blur(&self, sigma: f32) -> Self {
-        let kernel: Vec<f32> = (-3 * sigma.ceil() as isize..=3 * sigma.ceil() as isize)
-            .map(|x| {
-                let x = x as f32;
-                let a = 1.0 / (std::f32::consts::TAU * sigma * sigma).sqrt();
-                let b = -x * x / (2.0 * sigma * sigma);
-                a * b.exp()
-            })
-            .collect();
+        let kernel = gaussian_kernel(sigma);
 
         let blur_x = Field::new(self.extent, |[x, y]| {
             let mut acc = 0.0;
@@ -318,6 +412,18 @@ impl Field<f32, 2> {
     }
 }
 
+/// Weights of a Gaussian kernel covering three standard deviations on each side.
+pub fn gaussian_kernel(sigma: f32) -> Vec<f32> {
+    (-3 * sigma.ceil() as isize..=3 * sigma.ceil() as isize)
+        .map(|x| {
+            let x = x as f32;
+            let a = 1.0 / (std::f32::consts::TAU * sigma * sigma).sqrt();
+            let b = -x * x / (2.0 * sigma * sigma);
+            a * b.exp()
+        })
+        .collect()
+}
+
 impl<T: Copy + std::ops::AddAssign<T> + std::ops::DivAssign<f32>> Field<T, 3> {
     pub fn smooth(&self, mask: &Field<bool, 3>, env: &Field<Env, 3>) -> Field<T, 3> {
         self.map_with_coordinate(|mut v, c| {
@@ -371,3 +477,52 @@ impl<T: Copy + std::ops::AddAssign<T> + std::ops::DivAssign<f32>> Field<T, 3> {
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn downsample_policies() {
+        // Three of the eight voxels of the first block are solid.
+        let density = Field::new(4, |[x, y, z]| match [x, y, z] {
+            [0, 0, 0] => 4.0,
+            [1, 0, 0] | [0, 1, 1] => 1.0,
+            _ => -1.0,
+        });
+        let mask = density.map(|d| d >= 0.0);
+
+        let reduced = |policy| density.downsample(policy)[[0, 0, 0]];
+        assert_eq!(reduced(Downsample::Conservative), 4.0);
+        assert_eq!(reduced(Downsample::Majority), -1.0);
+        assert_eq!(reduced(Downsample::Average), 0.125);
+        assert_eq!(density.downsample(Downsample::Conservative).extent(), 2);
+
+        assert!(mask.downsample(Downsample::Conservative)[[0, 0, 0]]);
+        assert!(!mask.downsample(Downsample::Majority)[[0, 0, 0]]);
+        assert!(!mask.downsample(Downsample::Average)[[0, 0, 0]]);
+        assert!(!mask.downsample(Downsample::Conservative)[[1, 0, 0]]);
+
+        // Ties are solid only on average.
+        let mask = Field::new(2, |[x, _]| x == 0);
+        assert!(!mask.downsample(Downsample::Majority)[[0, 0]]);
+        assert!(mask.downsample(Downsample::Average)[[0, 0]]);
+    }
+
+    #[test]
+    fn downsampled_density_matches_mask() {
+        let mut rng = StdRng::seed_from_u64(0);
+        let density = Field::new(16, |_: [usize; 3]| rng.gen_range(-1.0..1.0));
+        let mask = density.map(|d| d >= 0.0);
+        for policy in [Downsample::Conservative, Downsample::Majority] {
+            let (mut density, mut mask) = (density.clone(), mask.clone());
+            for _ in 0..3 {
+                density = density.downsample(policy);
+                mask = mask.downsample(policy);
+                assert!(mask.coordinates().all(|c| mask[c] == (density[c] >= 0.0)));
+            }
+        }
+    }
+}
@@ -0,0 +1,336 @@
+//! Boolean fields of at most 64³ voxels, stored as one `u64` per column along z.
+//! Neighbours along z are found by shifting a column, and neighbours along x and y
+//! by combining adjacent columns, so that 64 voxels are processed at once.
+//!
+//! Fields are surrounded by a one-voxel apron, such that the voxels at the field boundary
+//! know their true neighbours. Along x and y the apron consists of additional columns,
+//! along z of one additional bit below and above each column.
+
+use super::{Field, Vis};
+
+/// Columns along z of a boolean field and its apron.
+/// Bit `z` of a column is the voxel at `[x, y, z]`, bits beyond the extent are clear.
+#[derive(Clone, Debug)]
+pub struct Bitmask {
+    extent: usize,
+    /// Columns including the apron along x and y, indexed by `x * (extent + 2) + y`,
+    /// where the first column of each axis is part of the apron.
+    columns: Vec<u64>,
+    /// Apron voxels below and above each column, in bit 0 and 1.
+    ends: Vec<u8>,
+}
+
+impl Bitmask {
+    /// A field without any set voxel in its apron, so that all faces at the boundary are visible.
+    pub fn new(extent: usize, mut f: impl FnMut([usize; 3]) -> bool) -> Self {
+        Self::with_apron(extent, |c| {
+            c.iter().all(|&i| (1..=extent).contains(&i)) && f(c.map(|i| i - 1))
+        })
+    }
+
+    /// A field whose apron is the outermost layer of voxels of the given field.
+    pub fn padded(field: &Field<bool, 3>) -> Self {
+        puffin::profile_function!();
+        Self::with_apron(field.extent() - 2, |c| field[c])
+    }
+
+    /// Coordinates passed to `f` include the apron, such that `[1, 1, 1]` is the first
+    /// voxel of the field.
+    fn with_apron(extent: usize, mut f: impl FnMut([usize; 3]) -> bool) -> Self {
+        assert!(
+            extent <= u64::BITS as usize,
+            "bitmasks hold at most 64 voxels per column"
+        );
+        let padded = extent + 2;
+        let mut columns = Vec::with_capacity(padded * padded);
+        let mut ends = Vec::with_capacity(padded * padded);
+        for x in 0..padded {
+            for y in 0..padded {
+                let mut column = 0;
+                for z in 0..extent {
+                    column |= (f([x, y, z + 1]) as u64) << z;
+                }
+                columns.push(column);
+                ends.push(f([x, y, 0]) as u8 | (f([x, y, padded - 1]) as u8) << 1);
+            }
+        }
+        Self {
+            extent,
+            columns,
+            ends,
+        }
+    }
+
+    pub fn extent(&self) -> usize {
+        self.extent
+    }
+
+    pub fn get(&self, [x, y, z]: [usize; 3]) -> bool {
+        self.column(x + 1, y + 1) >> z & 1 == 1
+    }
+
+    /// Column at `[x, y]`, in coordinates including the apron.
+    fn column(&self, x: usize, y: usize) -> u64 {
+        self.columns[x * (self.extent + 2) + y]
+    }
+
+    /// Voxels below each voxel of the column at `[x, y]`, in coordinates including the apron.
+    fn below(&self, x: usize, y: usize) -> u64 {
+        let i = x * (self.extent + 2) + y;
+        self.columns[i] << 1 | (self.ends[i] & 1) as u64
+    }
+
+    /// Voxels above each voxel of the column at `[x, y]`, in coordinates including the apron.
+    fn above(&self, x: usize, y: usize) -> u64 {
+        let i = x * (self.extent + 2) + y;
+        self.columns[i] >> 1 | ((self.ends[i] >> 1) as u64) << (self.extent - 1)
+    }
+
+    /// Clear the apron voxels at the given side, in the order of [`Faces`], which lie less
+    /// than `depth` voxels below an unset voxel of their column. Faces towards them become
+    /// visible and form a skirt, which covers the cracks towards a neighbour whose surface
+    /// is sampled at a different resolution. Below and above the field, the whole apron
+    /// layer is cleared.
+    pub fn erode_apron(&mut self, side: usize, depth: usize) {
+        let e = self.extent;
+        let padded = e + 2;
+        if side >= 4 {
+            let bit = 1 << (side - 4);
+            for end in &mut self.ends {
+                *end &= !bit;
+            }
+            return;
+        }
+
+        for i in 0..padded {
+            let (x, y) = match side {
+                0 => (0, i),
+                1 => (padded - 1, i),
+                2 => (i, 0),
+                _ => (i, padded - 1),
+            };
+            let index = x * padded + y;
+            let above = (self.ends[index] >> 1) as u64;
+            for _ in 0..depth.min(e) {
+                let column = self.columns[index];
+                self.columns[index] &= column >> 1 | above << (e - 1);
+            }
+        }
+    }
+
+    /// Bits of a column which lie within the field.
+    fn full(&self) -> u64 {
+        u64::MAX >> (u64::BITS as usize - self.extent)
+    }
+
+    /// Set voxels which have at least one unset voxel among their 26 neighbours.
+    /// Equal to [`Field::shell`] within the field.
+    pub fn shell(&self) -> Bitmask {
+        puffin::profile_function!();
+
+        let mut shell = self.clone();
+        for x in 1..=self.extent {
+            for y in 1..=self.extent {
+                let mut interior = u64::MAX;
+                for nx in x - 1..=x + 1 {
+                    for ny in y - 1..=y + 1 {
+                        interior &= self.column(nx, ny) & self.below(nx, ny) & self.above(nx, ny);
+                    }
+                }
+                shell.columns[x * (self.extent + 2) + y] &= !interior;
+            }
+        }
+        shell
+    }
+
+    /// Faces of set voxels which are visible, see [`Faces`].
+    pub fn faces(&self) -> Faces {
+        puffin::profile_function!();
+
+        let e = self.extent;
+        let full = self.full();
+        let mut exposed: [Vec<u64>; 6] = Default::default();
+        let mut faces: [Vec<u64>; 6] = Default::default();
+
+        for x in 1..=e {
+            for y in 1..=e {
+                let column = self.column(x, y);
+                let neighbours = [
+                    self.column(x - 1, y),
+                    self.column(x + 1, y),
+                    self.column(x, y - 1),
+                    self.column(x, y + 1),
+                    self.below(x, y),
+                    self.above(x, y),
+                ];
+                for (face, neighbour) in neighbours.into_iter().enumerate() {
+                    exposed[face].push(!neighbour & full);
+                    faces[face].push(column & !neighbour);
+                }
+            }
+        }
+
+        Faces {
+            extent: e,
+            exposed,
+            faces,
+        }
+    }
+
+    /// Number of set voxels, excluding the apron.
+    pub fn count(&self) -> usize {
+        (1..=self.extent)
+            .flat_map(|x| (1..=self.extent).map(move |y| (x, y)))
+            .map(|(x, y)| self.column(x, y).count_ones() as usize)
+            .sum()
+    }
+}
+
+/// Visible faces of the voxels of a [`Bitmask`], as one column bitmask per face direction.
+/// Faces are ordered -x, +x, -y, +y, -z, +z. A face is visible if its voxel is set and the
+/// neighbour behind the face, which may be part of the apron, is unset.
+pub struct Faces {
+    extent: usize,
+    /// Faces whose neighbour is unset, regardless of the voxel itself.
+    exposed: [Vec<u64>; 6],
+    faces: [Vec<u64>; 6],
+}
+
+impl Faces {
+    pub fn extent(&self) -> usize {
+        self.extent
+    }
+
+    pub fn visible(&self, [x, y, z]: [usize; 3], face: usize) -> bool {
+        self.column(face, x, y) >> z & 1 == 1
+    }
+
+    /// Visible faces of the given direction in the column at `[x, y]`.
+    pub fn column(&self, face: usize, x: usize, y: usize) -> u64 {
+        self.faces[face][x * self.extent + y]
+    }
+
+    /// Voxels with at least one visible face in the column at `[x, y]`.
+    pub fn any(&self, x: usize, y: usize) -> u64 {
+        (0..6).fold(0, |any, face| any | self.column(face, x, y))
+    }
+
+    /// Total number of visible faces.
+    pub fn count(&self) -> usize {
+        self.faces
+            .iter()
+            .flatten()
+            .map(|c| c.count_ones() as usize)
+            .sum()
+    }
+
+    /// Faces which are not internal. Equal to [`Field::visibility`] of the field with its apron.
+    pub fn visibility(&self) -> Field<Vis, 3> {
+        puffin::profile_function!();
+
+        const FLAGS: [Vis; 6] = [Vis::XN, Vis::XP, Vis::YN, Vis::YP, Vis::ZN, Vis::ZP];
+        Field::new(self.extent, |[x, y, z]| {
+            let i = x * self.extent + y;
+            let mut vis = Vis::empty();
+            for (face, flag) in FLAGS.into_iter().enumerate() {
+                vis.set(flag, self.exposed[face][i] >> z & 1 == 1);
+            }
+            vis
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+
+    fn random(extent: usize, density: f64) -> Field<bool, 3> {
+        let mut rng = StdRng::seed_from_u64(extent as u64);
+        Field::new(extent, |_| rng.gen_bool(density))
+    }
+
+    /// Coordinate within a field with apron.
+    fn padded(c: [usize; 3]) -> [usize; 3] {
+        c.map(|i| i + 1)
+    }
+
+    #[test]
+    fn roundtrip() {
+        let field = random(64, 0.5);
+        let bitmask = Bitmask::new(64, |c| field[c]);
+        assert!(field.coordinates().all(|c| bitmask.get(c) == field[c]));
+        assert_eq!(
+            bitmask.count(),
+            field.coordinates().filter(|&c| field[c]).count()
+        );
+
+        let field = random(66, 0.5);
+        let bitmask = Bitmask::padded(&field);
+        assert_eq!(bitmask.extent(), 64);
+        let inner = Field::new(64, |c| field[padded(c)]);
+        assert!(inner.coordinates().all(|c| bitmask.get(c) == inner[c]));
+    }
+
+    #[test]
+    fn matches_environment() {
+        for (extent, density) in [(1, 1.0), (5, 0.9), (16, 0.5), (64, 0.95)] {
+            let field = random(extent + 2, density);
+            let env = field.environment();
+            let shell = field.shell(&env);
+            let vis = env.visibility();
+
+            let bitmask = Bitmask::padded(&field);
+            let bitmask_shell = bitmask.shell();
+            let faces = bitmask.faces();
+            let visibility = faces.visibility();
+            for c in visibility.coordinates() {
+                let p = padded(c);
+                assert_eq!(bitmask_shell.get(c), shell[p]);
+                assert_eq!(visibility[c], vis[p]);
+                for (face, flag) in [Vis::XN, Vis::XP, Vis::YN, Vis::YP, Vis::ZN, Vis::ZP]
+                    .into_iter()
+                    .enumerate()
+                {
+                    assert_eq!(faces.visible(c, face), shell[p] && vis[p].contains(flag));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn apron_culls_boundary_faces() {
+        let faces = Bitmask::new(4, |_| true).faces();
+        // Only the outside of the cube.
+        assert_eq!(faces.count(), 6 * 4 * 4);
+        assert!(faces.visible([0, 1, 2], 0));
+        assert!(!faces.visible([0, 1, 2], 1));
+        assert!(faces.visible([2, 1, 3], 5));
+        assert_eq!(faces.visibility()[[0, 1, 2]], Vis::XN);
+
+        // Solid below and to the sides, empty above.
+        let faces = Bitmask::padded(&Field::new(6, |[_, _, z]| z < 5)).faces();
+        assert_eq!(faces.count(), 4 * 4);
+        assert!((0..4).all(|x| faces.visible([x, 0, 3], 5)));
+    }
+
+    #[test]
+    fn eroded_apron_exposes_skirt() {
+        // Two solid layers, with the surface of the neighbours at the same height.
+        let field = Field::new(6, |[_, _, z]| z < 3);
+        for (depth, skirt) in [(0, 0), (1, 4), (2, 8), (9, 8)] {
+            let mut bitmask = Bitmask::padded(&field);
+            bitmask.erode_apron(0, depth);
+            let faces = bitmask.faces();
+            assert_eq!(faces.count(), 4 * 4 + skirt);
+            assert_eq!(faces.visible([0, 2, 1], 0), depth > 0);
+            assert!(!faces.visible([3, 2, 1], 1));
+        }
+
+        let mut bitmask = Bitmask::padded(&Field::new(6, |_| true));
+        bitmask.erode_apron(4, 1);
+        assert_eq!(bitmask.faces().count(), 4 * 4);
+        assert!((0..4).all(|x| bitmask.faces().visible([x, 1, 0], 4)));
+    }
+}
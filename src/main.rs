@@ -3,30 +3,51 @@
 mod camera;
 mod field;
 mod renderer;
+mod scheduler;
 mod symmetry;
+mod terrain_lang;
 mod util;
 mod world;
 
+use camera::View;
 use cgmath::{vec2, vec3, InnerSpace, Vector3, Zero};
 use egui::mutex::Mutex;
+use field::Downsample;
 use itertools::Itertools;
 use pollster::FutureExt;
+use renderer::voxels::{MeshOptions, Meshing};
+use scheduler::Scheduler;
+use std::cmp::Reverse;
 use std::collections::HashMap;
 use std::f32::consts::TAU;
 use std::sync::{mpsc, Arc};
 use std::thread;
 use std::time::{Duration, Instant};
+use terrain_lang::watch::ProgramFile;
 use winit::{
     event::*,
     event_loop::{ControlFlow, EventLoop},
     window::WindowBuilder,
 };
-use world::{Chunk, N};
+use world::{
+    generator::{Caves, Flat, Heightmap, Program, TerrainGenerator, Warped},
+    material::{Material, Palette, Rules},
+    Chunk, ChunkData, Contents, Neighbours, N,
+};
 
 use crate::world::K;
 
 pub const FRAME_TIME: f32 = 1.0 / 60.0;
 
+/// How often the terrain program file is checked for changes.
+pub const PROGRAM_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Share of the screen-space error which counts towards the priority of chunks outside of the view.
+pub const OFFSCREEN_PRIORITY: f32 = 0.1;
+
+/// Angle in radians by which the camera turns before the chunk tasks are prioritized again.
+pub const REPRIORITIZE_ANGLE: f32 = 0.2;
+
 fn main() {
     run().block_on()
 }
@@ -82,7 +103,8 @@ async fn run() {
     let mut renderer = renderer::Renderer::new(&window).await;
 
     let mut camera = camera::Camera::default();
-    let player_cell = Arc::new(Mutex::new(Vector3::zero()));
+    // Camera cell and direction when the chunk tasks were last prioritized
+    let mut prioritized_view = (Vector3::zero(), Vector3::zero());
     let mut w_down = false;
     let mut s_down = false;
     let mut a_down = false;
@@ -92,10 +114,12 @@ async fn run() {
 
     let mut events = vec![];
 
-    let (chunk_sender, chunk_receiver) = mpsc::channel::<(Vector3<isize>, Chunk)>();
+    let (chunk_sender, chunk_receiver) = mpsc::channel::<ChunkData>();
     let mut world = world::World::default();
     let mut max_lod = K >> 1;
     let mut lod_shift = 2;
+    let mut mesh_options = MeshOptions::default();
+    let mut palette = Palette::default();
     let mut enable_gizmos = false;
     let mut invert_x_axis = false;
     let mut invert_y_axis = false;
@@ -106,81 +130,101 @@ async fn run() {
 
     let chunk_generation_time = Arc::new(Mutex::new(0.0));
 
-    #[derive(Default)]
-    struct Tasks {
-        task_list: HashMap<Vector3<isize>, usize>,
-        in_progress: HashMap<Vector3<isize>, usize>,
+    // The terrain program is passed as the first argument, otherwise the built-in terrain is used.
+    let mut program_file = std::env::args().nth(1).map(ProgramFile::new);
+    // Material rules are passed as the second argument, otherwise the default rules are used.
+    let mut rules_error = None;
+    let rules = Arc::new(match std::env::args().nth(2) {
+        Some(path) => Rules::load(path).unwrap_or_else(|error| {
+            rules_error = Some(error);
+            Rules::default()
+        }),
+        None => Rules::default(),
+    });
+    let mut last_program_poll = Instant::now();
+
+    let mut generators: Vec<Arc<dyn TerrainGenerator>> = vec![
+        Arc::new(Heightmap::default()),
+        Arc::new(Warped),
+        Arc::new(Caves::default()),
+        Arc::new(Flat),
+    ];
+    let mut generator_index = 0;
+    if let Some(program) = program_file.as_mut().and_then(ProgramFile::poll) {
+        generators.push(Arc::new(Program(program)));
+        generator_index = generators.len() - 1;
     }
-    let tasks: Arc<Mutex<Tasks>> = Default::default();
-
-    // Spawn chunk worker threads
-    for i in 0..8 {
-        let device = renderer.device.clone();
-        let tasks = tasks.clone();
-        let player_cell = player_cell.clone();
-        let chunk_sender = chunk_sender.clone();
-        let chunk_generation_time = chunk_generation_time.clone();
-        thread::Builder::new()
-            .name(format!("Worker #{i}"))
-            .spawn(move || loop {
-                let (key, lod) = {
-                    let mut tasks = tasks.lock();
-                    let player_cell = *player_cell.lock();
-
-                    // Get next task, order by distance to camera
-                    let Some((&key, &lod)) = tasks
-                        .task_list
-                        .iter()
-                        .filter(|(key, &lod)| {
-                            if let Some(&in_progress_lod) = tasks.in_progress.get(key) {
-                                lod != in_progress_lod
-                            } else {
-                                true
-                            }
-                        })
-                        .min_by_key(|(&key, &lod)| {
-                            let distance = (key - player_cell).magnitude2();
-                            (distance, lod)
-                        })
-                    else {
-                        // No task available, sleep for a bit
-                        thread::yield_now();
-                        continue;
-                    };
 
-                    tasks.in_progress.insert(key, lod);
-                    (key, lod)
-                };
-
-                // Generate the chunk. This can take a long time.
-                let start = Instant::now();
-                let chunk = world::Chunk::new(key, lod, &device);
-                let elapsed = start.elapsed().as_millis();
-                if lod == 0 {
-                    let mut chunk_generation_time = chunk_generation_time.lock();
-                    *chunk_generation_time = 0.9 * *chunk_generation_time + 0.1 * elapsed as f32;
-                }
-
-                {
-                    // Check if the task is still valid
-                    let mut tasks = tasks.lock();
-                    if let Some(&new_lod) = tasks.in_progress.get(&key) {
-                        if lod == new_lod {
-                            // Worker generated the chunk we wanted
-                            tasks.in_progress.remove(&key);
-                        }
-                    };
-                    if let Some(&new_lod) = tasks.task_list.get(&key) {
-                        if lod == new_lod {
-                            // Worker generated the chunk we wanted
-                            tasks.task_list.remove(&key);
-                            chunk_sender.send((key, chunk)).unwrap();
-                        }
-                    };
-                }
-            })
-            .unwrap();
+    /// What chunks are generated from, until the terrain changes.
+    type Terrain = (Arc<dyn TerrainGenerator>, Arc<Rules>);
+    /// LoD of a chunk, LoDs of its neighbours and the options of its mesh.
+    type Task = (usize, Neighbours, MeshOptions);
+    /// Chunks which resolve the largest screen-space error are generated first, where those
+    /// outside of the view count less. Missing chunks leave an error of their whole extent.
+    /// Chunks closest to the camera go first among those of equal error.
+    fn priority(
+        key: Vector3<isize>,
+        (lod, ..): Task,
+        current_lod: Option<usize>,
+        view: &View,
+    ) -> (Reverse<u32>, u32) {
+        let min = N as f32 * key.cast::<f32>().unwrap();
+        let max = min + N as f32 * vec3(1.0, 1.0, 1.0);
+        let error = current_lod.map_or(N, |current| (1 << current) - (1 << lod.min(current)));
+        let mut pixels = view.screen_space_error(error as f32, min, max);
+        if !view.frustum.intersects(min, max) {
+            pixels *= OFFSCREEN_PRIORITY;
+        }
+        (Reverse(pixels as u32), view.distance(min, max) as u32)
     }
+    let scheduler = Arc::new(Scheduler::<_, Task, _, Terrain>::new((
+        generators[generator_index].clone(),
+        rules.clone(),
+    )));
+
+    // Spawn chunk worker threads, leaving a core to the main thread
+    let worker_count =
+        thread::available_parallelism().map_or(4, |n| n.get().saturating_sub(1).max(1));
+    let mut workers: Vec<_> = (0..worker_count)
+        .map(|i| {
+            let scheduler = scheduler.clone();
+            let chunk_sender = chunk_sender.clone();
+            let chunk_generation_time = chunk_generation_time.clone();
+            thread::Builder::new()
+                .name(format!("Worker #{i}"))
+                .spawn(move || {
+                    while let Some(job) = scheduler.next() {
+                        let (lod, neighbours, mesh_options) = job.task;
+                        let (generator, rules) = &job.context;
+
+                        // Generate the chunk. This can take a long time.
+                        let start = Instant::now();
+                        let Ok(chunk) = ChunkData::new(
+                            job.key,
+                            lod,
+                            neighbours,
+                            generator.as_ref(),
+                            rules,
+                            mesh_options,
+                            &job.token,
+                        ) else {
+                            continue;
+                        };
+                        let elapsed = start.elapsed().as_millis();
+                        if lod == 0 {
+                            let mut chunk_generation_time = chunk_generation_time.lock();
+                            *chunk_generation_time =
+                                0.9 * *chunk_generation_time + 0.1 * elapsed as f32;
+                        }
+
+                        // Chunks are sent while the scheduler is locked, so that none of them
+                        // arrive after a restart.
+                        scheduler.finish(&job, || chunk_sender.send(chunk).unwrap());
+                    }
+                })
+                .unwrap()
+        })
+        .collect();
 
     event_loop.run(move |event, _, control_flow| match event {
         Event::NewEvents(winit::event::StartCause::Init) => {
@@ -317,6 +361,7 @@ async fn run() {
                 ..Default::default()
             };
 
+            let previous_generator_index = generator_index;
             let ui_output = renderer.ctx().run(input, |ctx| {
                 puffin::profile_scope!("UI");
                 egui::Window::new("Inspector").show(ctx, |ui| {
@@ -404,8 +449,25 @@ async fn run() {
                         .default_open(true)
                         .show(ui, |ui| {
                             ui.label(format!("Side Extent: {N}"));
-                            ui.label(format!("Total: {}", world.chunks.len()));
+                            ui.label(format!("Total: {}", world.chunks().len()));
+                            let count = |contents: fn(&Chunk) -> bool| {
+                                world
+                                    .chunks()
+                                    .values()
+                                    .filter(|chunk| contents(chunk))
+                                    .count()
+                            };
+                            ui.label(format!(
+                                "Empty: {}",
+                                count(|chunk| matches!(chunk.contents, Contents::Empty))
+                            ));
+                            ui.label(format!(
+                                "Full: {}",
+                                count(|chunk| matches!(chunk.contents, Contents::Full))
+                            ));
                             ui.label(format!("Rendered: {}", stats.chunk_count));
+                            ui.label(format!("Queued: {}", scheduler.pending_count()));
+                            ui.label(format!("Cancelled: {}", scheduler.cancelled_count()));
                             ui.label(format!("Generation Radius: {}", max_lod << lod_shift));
                             ui.label(format!(
                                 "Generation Time: {:.0}ms",
@@ -413,6 +475,126 @@ async fn run() {
                             ));
                             ui.add(egui::Slider::new(&mut max_lod, 0..=K).text("Max LoD"));
                             ui.add(egui::Slider::new(&mut lod_shift, 0..=6).text("LoD Exp Scale"));
+
+                            egui::ComboBox::from_label("Meshing")
+                                .selected_text(mesh_options.meshing.name())
+                                .show_ui(ui, |ui| {
+                                    for option in Meshing::ALL {
+                                        ui.selectable_value(
+                                            &mut mesh_options.meshing,
+                                            option,
+                                            option.name(),
+                                        );
+                                    }
+                                });
+                            ui.checkbox(&mut mesh_options.ambient_occlusion, "Ambient Occlusion");
+                            egui::ComboBox::from_label("LoD Downsampling")
+                                .selected_text(
+                                    mesh_options.downsample.map_or("Off", Downsample::name),
+                                )
+                                .show_ui(ui, |ui| {
+                                    ui.selectable_value(&mut mesh_options.downsample, None, "Off");
+                                    for option in Downsample::ALL {
+                                        ui.selectable_value(
+                                            &mut mesh_options.downsample,
+                                            Some(option),
+                                            option.name(),
+                                        );
+                                    }
+                                });
+                            let triangles: usize = world
+                                .chunks()
+                                .values()
+                                .filter_map(Chunk::voxel_mesh)
+                                .map(|mesh| mesh.triangle_count())
+                                .sum();
+                            ui.label(format!("Triangles: {triangles}"));
+                            let memory: u64 = world
+                                .chunks()
+                                .values()
+                                .filter_map(Chunk::voxel_mesh)
+                                .map(|mesh| mesh.size())
+                                .sum();
+                            ui.label(format!(
+                                "GPU Memory: {:.1} MiB",
+                                memory as f64 / (1 << 20) as f64
+                            ));
+                            ui.label(format!(
+                                "Chunk Memory: {:.1} / {} MiB",
+                                world.size() as f64 / (1 << 20) as f64,
+                                world.budget() >> 20
+                            ));
+                            ui.label(format!(
+                                "Cached: {} ({:.1} MiB)",
+                                world.cache().len(),
+                                world.cache().size() as f64 / (1 << 20) as f64
+                            ));
+                            let mut budget = world.budget() >> 20;
+                            ui.add(
+                                egui::Slider::new(&mut budget, 0..=4096)
+                                    .text("Memory Budget (MiB)"),
+                            );
+                            world.set_budget(budget << 20);
+                            // Surface nets do not mesh voxel faces, so only blocky chunks
+                            // are compared with their faces.
+                            let (blocky_triangles, faces) = world
+                                .chunks()
+                                .values()
+                                .filter(|chunk| chunk.options.meshing != Meshing::Smooth)
+                                .fold((0, 0), |(triangles, faces), chunk| {
+                                    let mesh_triangles =
+                                        chunk.voxel_mesh().map_or(0, |mesh| mesh.triangle_count());
+                                    (triangles + mesh_triangles, faces + chunk.faces)
+                                });
+                            if faces > 0 {
+                                ui.label(format!(
+                                    "Triangle Reduction: {:.1}%",
+                                    100.0 * (1.0 - blocky_triangles as f32 / (2 * faces) as f32)
+                                ));
+                            }
+                        });
+
+                    egui::CollapsingHeader::new("Materials").show(ui, |ui| {
+                        if let Some(error) = &rules_error {
+                            ui.colored_label(ui.visuals().error_fg_color, error);
+                        }
+                        // The palette is applied when rendering, so chunks need not be rebuilt.
+                        for material in Material::ALL {
+                            ui.horizontal(|ui| {
+                                let color = &mut palette.0[material as usize];
+                                ui.color_edit_button_rgb(color.as_mut());
+                                ui.label(material.name());
+                            });
+                        }
+                        if ui.button("Reset").clicked() {
+                            palette = Palette::default();
+                        }
+                    });
+
+                    egui::CollapsingHeader::new("Terrain")
+                        .default_open(true)
+                        .show(ui, |ui| {
+                            egui::ComboBox::from_label("Generator")
+                                .selected_text(generators[generator_index].name())
+                                .show_index(ui, &mut generator_index, generators.len(), |i| {
+                                    generators[i].name().to_string()
+                                });
+                            let Some(program_file) = &program_file else {
+                                return;
+                            };
+                            ui.label(format!("Program: {}", program_file.path().display()));
+                            if let Some(error) = &program_file.error {
+                                ui.colored_label(
+                                    ui.visuals().error_fg_color,
+                                    egui::RichText::new(error).monospace(),
+                                );
+                            }
+                            for warning in &program_file.warnings {
+                                ui.colored_label(
+                                    ui.visuals().warn_fg_color,
+                                    egui::RichText::new(warning).monospace(),
+                                );
+                            }
                         });
 
                     egui::CollapsingHeader::new("Misc")
@@ -476,64 +658,154 @@ async fn run() {
                 .cast()
                 .unwrap();
 
-            while let Ok((key, chunk)) = chunk_receiver.try_recv() {
-                world.chunks.insert(key, chunk);
+            let mut regenerate = generator_index != previous_generator_index;
+
+            // Switch to the terrain program whenever it changes
+            if let Some(program_file) = &mut program_file {
+                if last_program_poll.elapsed() >= PROGRAM_POLL_INTERVAL {
+                    last_program_poll = Instant::now();
+                    if let Some(program) = program_file.poll() {
+                        let program: Arc<dyn TerrainGenerator> = Arc::new(Program(program));
+                        match generators.iter().position(|g| g.name() == program.name()) {
+                            Some(index) => generators[index] = program,
+                            None => generators.push(program),
+                        }
+                        generator_index = generators.len() - 1;
+                        regenerate = true;
+                    }
+                }
+            }
+
+            // Regenerate all chunks when the generator changes
+            if regenerate {
+                scheduler.restart((generators[generator_index].clone(), rules.clone()));
+                // Chunks are sent while holding the lock, so the remaining ones are outdated
+                while chunk_receiver.try_recv().is_ok() {}
+                world.clear();
+            }
+
+            {
+                puffin::profile_scope!("Upload Chunks");
+                for data in chunk_receiver.try_iter() {
+                    world.insert(data.key, Chunk::new(&data, &renderer.device));
+                }
             }
 
             let mut required_chunks = HashMap::new();
 
-            *player_cell.lock() = camera_index;
+            // What the camera looks at is generated first
+            let view = renderer.view();
+            if prioritized_view.0 != camera_index
+                || prioritized_view.1.dot(camera.forward()) < REPRIORITIZE_ANGLE.cos()
+            {
+                prioritized_view = (camera_index, camera.forward());
+                scheduler.reprioritize(|key, task| {
+                    priority(
+                        key,
+                        task,
+                        world.chunks().get(&key).map(|chunk| chunk.lod),
+                        &view,
+                    )
+                });
+            }
 
             // Gather all required chunks and their LoDs based on the camera position
-            let generation_radius = (K << lod_shift) as isize;
-            for x in -generation_radius..=generation_radius {
-                for y in -generation_radius..=generation_radius {
-                    for z in 0..=1 {
-                        let c = vec3(camera_index.x + x, camera_index.y + y, z);
-                        let lod = ((x.pow(2) + y.pow(2)) as f32).sqrt() as usize;
-                        let lod = lod >> lod_shift;
-                        if lod <= max_lod {
-                            required_chunks.insert(c, lod);
+            {
+                puffin::profile_scope!("Gather Chunks");
+
+                let generation_radius = (K << lod_shift) as isize;
+                let lod = |key: Vector3<isize>| {
+                    let offset = key - camera_index;
+                    let within = [offset.x, offset.y, offset.z]
+                        .iter()
+                        .all(|i| i.abs() <= generation_radius);
+                    let lod = world::lod(
+                        offset.cast::<f32>().unwrap().magnitude(),
+                        lod_shift,
+                        world.chunks().get(&key).map(|chunk| chunk.lod),
+                    );
+                    (within && lod <= max_lod).then_some(lod)
+                };
+
+                // Start at the ground layer and the camera, and follow the surface into
+                // the adjacent chunks wherever the generated chunks report that it continues.
+                // Chunks without surface are not generated unless they are a starting point.
+                let ground = 0.clamp(
+                    camera_index.z - generation_radius,
+                    camera_index.z + generation_radius,
+                );
+                let mut queue = vec![camera_index];
+                for x in -generation_radius..=generation_radius {
+                    for y in -generation_radius..=generation_radius {
+                        queue.push(vec3(camera_index.x + x, camera_index.y + y, ground));
+                    }
+                }
+                while let Some(key) = queue.pop() {
+                    if required_chunks.contains_key(&key) {
+                        continue;
+                    }
+                    let Some(lod) = lod(key) else {
+                        continue;
+                    };
+                    required_chunks.insert(key, lod);
+
+                    if let Some(chunk) = world.chunks().get(&key) {
+                        for (neighbour, continues) in
+                            world::neighbour_keys(key).into_iter().zip(chunk.surface)
+                        {
+                            if continues {
+                                queue.push(neighbour);
+                            }
                         }
                     }
                 }
             }
 
-            // Delete chunks that are outside the generation radius
-            world
-                .chunks
-                .retain(|key, _| required_chunks.contains_key(key));
+            // Cache chunks that are outside the generation radius
+            world.retain(|key| required_chunks.contains_key(&key));
 
             // Record new chunk generation tasks
             {
                 puffin::profile_scope!("Record Tasks");
 
-                let mut tasks = tasks.lock();
+                // Chunks are stitched to neighbours of a different LoD
+                let required_tasks: HashMap<_, _> = required_chunks
+                    .iter()
+                    .map(|(&key, &lod)| {
+                        let neighbours = world::neighbour_keys(key).map(|neighbour| {
+                            required_chunks.get(&neighbour).copied().unwrap_or(lod)
+                        });
+                        (key, (lod, neighbours, mesh_options))
+                    })
+                    .collect();
 
-                // Cancel outdated tasks which are not yet in progress
-                tasks
-                    .task_list
-                    .retain(|key, lod| required_chunks.get(key).copied() == Some(*lod));
+                // Cancel outdated tasks
+                scheduler.retain(|key, task| required_tasks.get(&key) == Some(&task));
 
-                for (key, lod) in required_chunks {
-                    // Check if the task is already in progress
-                    if let Some(&old_lod) = tasks.task_list.get(&key) {
-                        if lod == old_lod {
-                            continue;
-                        }
+                for (key, task) in required_tasks {
+                    // Check if the task is already scheduled
+                    if scheduler.is_scheduled(key, task) {
+                        continue;
                     }
 
                     // Check if the task is already done
-                    if let Some(chunk) = world.chunks.get(&key) {
-                        if chunk.lod == lod {
+                    if let Some(chunk) = world.chunks().get(&key) {
+                        if (chunk.lod, chunk.neighbours, chunk.options) == task {
                             continue;
                         }
                     }
 
-                    tasks.task_list.insert(key, lod);
+                    // Check if the chunk was built before
+                    let (lod, neighbours, options) = task;
+                    if world.restore(key, lod, neighbours, options) {
+                        continue;
+                    }
+
+                    let current_lod = world.chunks().get(&key).map(|chunk| chunk.lod);
+                    scheduler.push(key, task, priority(key, task, current_lod, &view));
                 }
 
-                for key in tasks.in_progress.keys() {
+                for key in scheduler.in_progress() {
                     renderer.gizmos.aabb(
                         N as f32 * key.cast().unwrap(),
                         N as f32 * (key + vec3(1, 1, 1)).cast().unwrap(),
@@ -545,7 +817,8 @@ async fn run() {
             match renderer.render(
                 camera,
                 ui_output,
-                &world.chunks,
+                world.chunks(),
+                &palette,
                 window.scale_factor() as f32,
                 enable_gizmos,
             ) {
@@ -570,6 +843,14 @@ async fn run() {
             }
         }
 
+        Event::LoopDestroyed => {
+            // Workers stop their current chunk at its next stage
+            scheduler.shutdown();
+            for worker in workers.drain(..) {
+                worker.join().unwrap();
+            }
+        }
+
         _ => {}
     });
 }
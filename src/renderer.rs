@@ -3,15 +3,14 @@ pub mod voxels;
 
 use std::{collections::HashMap, sync::Arc};
 
-use cgmath::{vec4, InnerSpace, Matrix, Vector3};
-use itertools::Itertools;
+use cgmath::{vec3, Vector3};
 use winit::window::Window;
 
 use crate::{
-    camera,
+    camera::{self, Frustum, View},
     symmetry::Symmetry,
     util,
-    world::{Chunk, N},
+    world::{material::Palette, Chunk, N},
 };
 
 use self::{
@@ -148,6 +147,16 @@ impl Renderer {
         );
     }
 
+    /// What is drawn, with the camera movement and zoom smoothed like in [`Self::render`].
+    pub fn view(&self) -> View {
+        View::new(
+            self.camera_symmetry,
+            self.camera_fovy,
+            self.config.width,
+            self.config.height,
+        )
+    }
+
     pub fn ctx(&self) -> &egui::Context {
         &self.ui_ctx
     }
@@ -157,6 +166,7 @@ impl Renderer {
         camera: camera::Camera,
         ui_output: egui::FullOutput,
         chunks: &HashMap<Vector3<isize>, Chunk>,
+        palette: &Palette,
         scale_factor: f32,
         enable_gizmos: bool,
     ) -> Result<RenderStats, wgpu::SurfaceError> {
@@ -211,35 +221,13 @@ impl Renderer {
 
         let chunks: Vec<_> = {
             puffin::profile_scope!("Cull Chunks");
-            let view_proj = proj * self.camera_symmetry.matrix();
+            let frustum = Frustum::new(proj * self.camera_symmetry.matrix());
             chunks
                 .values()
-                .filter(|chunk| {
-                    // Since this is the full model-view-projection matrix, the extracted
-                    // planes are in model space.
-                    let m = (view_proj * chunk.voxel_mesh.symmetry.matrix()).transpose();
-
-                    // We do not test against the far plane because that is handled by the generation radius.
-                    let planes = [
-                        m[3] + m[0], // left
-                        m[3] - m[0], // right
-                        m[3] + m[1], // bottom
-                        m[3] - m[1], // top
-                        m[3] + m[2], // near
-                    ];
-
-                    // The extent of the unit cube we are testing against
-                    let max = N as f32 / chunk.voxel_mesh.symmetry.scale;
-
-                    // A chunk is partially visible if:
-                    // ∀ plane ∈ planes: ∃ vertex ∈ chunk: dist(plane, vertex) > 0
-                    planes.into_iter().all(|plane| {
-                        [0.0, max]
-                            .into_iter()
-                            .cartesian_product([0.0, max])
-                            .cartesian_product([0.0, max])
-                            .any(|((x, y), z)| plane.dot(vec4(x, y, z, 1.0)) > 0.0)
-                    })
+                .filter_map(Chunk::voxel_mesh)
+                .filter(|mesh| {
+                    let min = mesh.symmetry.translation;
+                    frustum.intersects(min, min + N as f32 * vec3(1.0, 1.0, 1.0))
                 })
                 .collect()
         };
@@ -293,6 +281,7 @@ impl Renderer {
                 bytemuck::cast_slice(&[voxels::Uniforms {
                     view: self.camera_symmetry.matrix(),
                     proj,
+                    palette: voxels::Uniforms::palette(palette),
                     light: camera.translation,
                 }]),
             );
@@ -300,8 +289,8 @@ impl Renderer {
 
             let uniforms: Vec<_> = chunks
                 .iter()
-                .map(|chunk| voxels::ChunkUniforms {
-                    model: chunk.voxel_mesh.symmetry.matrix(),
+                .map(|mesh| voxels::ChunkUniforms {
+                    model: mesh.symmetry.matrix(),
                 })
                 .collect();
 
@@ -315,9 +304,17 @@ impl Renderer {
         {
             puffin::profile_scope!("Render Chunks");
 
-            render_pass.set_pipeline(&self.voxel_pipeline.pipeline);
+            let mut smooth = None;
+            for (i, mesh) in chunks.iter().enumerate() {
+                if smooth != Some(mesh.smooth) {
+                    smooth = Some(mesh.smooth);
+                    render_pass.set_pipeline(if mesh.smooth {
+                        &self.voxel_pipeline.smooth_pipeline
+                    } else {
+                        &self.voxel_pipeline.pipeline
+                    });
+                }
 
-            for (i, chunk) in chunks.iter().enumerate() {
                 {
                     puffin::profile_scope!("Record Render Pass");
                     render_pass.set_bind_group(
@@ -326,8 +323,13 @@ impl Renderer {
                         &[(i * std::mem::size_of::<voxels::ChunkUniforms>())
                             as wgpu::DynamicOffset],
                     );
-                    render_pass.set_vertex_buffer(0, chunk.voxel_mesh.buffer.slice(..));
-                    render_pass.draw(0..chunk.voxel_mesh.count as u32, 0..1);
+                    if mesh.count == 0 {
+                        continue;
+                    }
+                    render_pass.set_vertex_buffer(0, mesh.vertex_buffer.slice(..));
+                    render_pass
+                        .set_index_buffer(mesh.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                    render_pass.draw_indexed(0..mesh.count as u32, 0, 0..1);
                 }
             }
         }
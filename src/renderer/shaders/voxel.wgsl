@@ -1,6 +1,8 @@
 struct Uniforms {
     view: mat4x4<f32>,
     proj: mat4x4<f32>,
+    /// Colors of the materials, indexed like `Material::ALL`.
+    palette: array<vec4<f32>, 6>,
     light: vec3<f32>,
 }
 
@@ -8,9 +10,9 @@ struct Uniforms {
 @group(1) @binding(0) var<uniform> model: mat4x4<f32>;
 
 struct In {
-    @location(0) position: vec3<f32>,
-    @location(1) normal: vec3<f32>,
-    @location(2) color: u32,
+    /// Position with 7 bits per axis, followed by the face index and the ambient occlusion.
+    @location(0) data: u32,
+    @location(1) material: u32,
 }
 
 struct Out {
@@ -18,19 +20,55 @@ struct Out {
     @location(0) position: vec3<f32>,
     @location(1) color: vec3<f32>,
     @location(2) normal: vec3<f32>,
+    @location(3) occlusion: f32,
 }
 
+/// Normals of the voxel faces, pointing into the voxel.
+var<private> face_normals: array<vec3<f32>, 6> = array<vec3<f32>, 6>(
+    vec3(1.0, 0.0, 0.0),
+    vec3(-1.0, 0.0, 0.0),
+    vec3(0.0, 1.0, 0.0),
+    vec3(0.0, -1.0, 0.0),
+    vec3(0.0, 0.0, 1.0),
+    vec3(0.0, 0.0, -1.0),
+);
+
 @vertex
 fn vertex(in: In) -> Out {
     var out: Out;
 
-    let position = model * hom(in.position);
+    let local = vec3(in.data, in.data >> 7u, in.data >> 14u) & vec3(0x7fu);
+    let position = model * hom(vec3<f32>(local));
+    out.clip_position = uniforms.proj * uniforms.view * position;
+    out.position = dehom(position);
+
+    out.color = uniforms.palette[in.material].rgb;
+
+    out.normal = face_normals[(in.data >> 21u) & 0x7u];
+    out.occlusion = f32((in.data >> 24u) & 0x3u) / 3.0;
+
+    return out;
+}
+
+struct SmoothIn {
+    /// Position offset by one voxel, in units of 1/256 voxels.
+    @location(0) position: vec4<u32>,
+    @location(1) normal: vec4<f32>,
+    @location(2) material: u32,
+}
+
+@vertex
+fn vertex_smooth(in: SmoothIn) -> Out {
+    var out: Out;
+
+    let position = model * hom(vec3<f32>(in.position.xyz) / 256.0 - 1.0);
     out.clip_position = uniforms.proj * uniforms.view * position;
     out.position = dehom(position);
 
-    out.color = unpack4x8unorm(in.color).rgb;
+    out.color = uniforms.palette[in.material].rgb;
 
-    out.normal = in.normal;
+    out.normal = normalize(in.normal.xyz);
+    out.occlusion = 0.0;
 
     return out;
 }
@@ -43,6 +81,8 @@ fn fragment(out: Out) -> @location(0) vec4<f32> {
     let v = normalize(out.position - uniforms.light);
     let nov = clamp(dot(out.normal, v), 0.0, 1.0);
 
-    let color = nov * light_intensity * out.color + ambient_light;
+    let ambient_occlusion = 1.0 - 0.6 * out.occlusion;
+
+    let color = (nov * light_intensity * out.color + ambient_light) * ambient_occlusion;
     return vec4(color, 1.0);
 }
@@ -1,13 +1,20 @@
+pub mod greedy;
+pub mod surface_nets;
+
 use crate::{
-    field::{Field, Vis},
+    field::{bitmask::Faces, Downsample, Env, Field},
     symmetry::Symmetry,
     util,
+    world::material::{Material, Palette},
 };
-use cgmath::{vec3, InnerSpace, Matrix4, Quaternion, Vector3};
+use cgmath::{vec3, ElementWise, InnerSpace, Matrix4, Quaternion, Vector3, Vector4};
+use std::collections::HashMap;
 use wgpu::util::DeviceExt;
 
 pub struct VoxelPipeline {
     pub(super) pipeline: wgpu::RenderPipeline,
+    /// Renders meshes with [`SmoothVertex`] instead of [`PackedVertex`].
+    pub(super) smooth_pipeline: wgpu::RenderPipeline,
     pub(super) bind_group_layout: wgpu::BindGroupLayout,
     pub(super) bind_group: wgpu::BindGroup,
     pub(super) uniform_buffer: wgpu::Buffer,
@@ -20,9 +27,17 @@ pub struct VoxelPipeline {
 pub(super) struct Uniforms {
     pub view: Matrix4<f32>,
     pub proj: Matrix4<f32>,
+    /// Colors of the materials, see [`Palette`].
+    pub palette: [Vector4<f32>; Material::ALL.len()],
     pub light: Vector3<f32>,
 }
 
+impl Uniforms {
+    pub fn palette(palette: &Palette) -> [Vector4<f32>; Material::ALL.len()] {
+        palette.0.map(|color| color.extend(1.0))
+    }
+}
+
 unsafe impl bytemuck::Pod for Uniforms {}
 unsafe impl bytemuck::Zeroable for Uniforms {}
 
@@ -42,19 +57,189 @@ unsafe impl bytemuck::Zeroable for ChunkUniforms {}
 #[derive(Debug)]
 pub struct VoxelMesh {
     pub symmetry: Symmetry,
-    pub(super) buffer: wgpu::Buffer,
+    pub(super) vertex_buffer: wgpu::Buffer,
+    pub(super) index_buffer: wgpu::Buffer,
     pub(super) count: usize,
+    /// Whether the vertices are [`SmoothVertex`] instead of [`PackedVertex`].
+    pub(super) smooth: bool,
 }
 
-#[derive(Clone, Copy, Debug)]
-struct Vertex {
-    position: Vector3<f32>,
-    normal: Vector3<f32>,
-    color: u32,
+/// Vertex of a triangle list as produced by [`mesh`], in voxel coordinates.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Vertex {
+    pub position: Vector3<f32>,
+    pub normal: Vector3<f32>,
+    pub material: Material,
+    /// Ambient occlusion from 0 to 3, see [`face_occlusion`].
+    pub occlusion: u8,
+}
+
+/// Compact vertex of a [`PackedMesh`], decoded in `voxel.wgsl`.
+/// `data` holds the position within the chunk with 7 bits per axis in bits 0..21,
+/// followed by the face index (see [`FACE_NORMALS`]) in bits 21..24
+/// and the ambient occlusion in bits 24..26.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct PackedVertex {
+    pub data: u32,
+    /// Index of the [`Material`] in the palette.
+    pub material: u32,
+}
+
+unsafe impl bytemuck::Pod for PackedVertex {}
+unsafe impl bytemuck::Zeroable for PackedVertex {}
+
+impl PackedVertex {
+    pub fn pack(vertex: &Vertex) -> Self {
+        let face = FACE_NORMALS
+            .iter()
+            .position(|&n| n == vertex.normal)
+            .expect("voxel faces are axis aligned");
+        let [x, y, z] = [0, 1, 2].map(|i| {
+            let p = vertex.position[i] as u32;
+            debug_assert!(p < 1 << 7 && p as f32 == vertex.position[i]);
+            p
+        });
+        Self {
+            data: x | y << 7 | z << 14 | (face as u32) << 21 | (vertex.occlusion as u32) << 24,
+            material: vertex.material as u32,
+        }
+    }
+
+    pub fn unpack(self) -> Vertex {
+        let [x, y, z] = [0, 7, 14].map(|shift| (self.data >> shift & 0x7f) as f32);
+        Vertex {
+            position: vec3(x, y, z),
+            normal: FACE_NORMALS[(self.data >> 21 & 0x7) as usize],
+            material: Material::from_index(self.material),
+            occlusion: (self.data >> 24 & 0x3) as u8,
+        }
+    }
 }
 
-unsafe impl bytemuck::Pod for Vertex {}
-unsafe impl bytemuck::Zeroable for Vertex {}
+/// Vertex of a [`SmoothMesh`], decoded in `voxel.wgsl`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SmoothVertex {
+    /// Position in voxel coordinates, offset by one voxel and in units of 1/256 voxels,
+    /// so that vertices within the apron can be represented.
+    pub position: [u16; 4],
+    /// Normalized normal in the `xyz` components.
+    pub normal: [i8; 4],
+    /// Index of the [`Material`] in the palette.
+    pub material: u32,
+}
+
+unsafe impl bytemuck::Pod for SmoothVertex {}
+unsafe impl bytemuck::Zeroable for SmoothVertex {}
+
+impl SmoothVertex {
+    const SCALE: f32 = 256.0;
+
+    pub fn pack(vertex: &Vertex) -> Self {
+        let p = vertex
+            .position
+            .map(|x| ((x + 1.0) * Self::SCALE).round() as u16);
+        let n = vertex.normal.map(|x| (x * i8::MAX as f32).round() as i8);
+        Self {
+            position: [p.x, p.y, p.z, 0],
+            normal: [n.x, n.y, n.z, 0],
+            material: vertex.material as u32,
+        }
+    }
+
+    pub fn unpack(self) -> Vertex {
+        let [x, y, z, _] = self.position.map(|x| x as f32 / Self::SCALE - 1.0);
+        let [nx, ny, nz, _] = self.normal.map(|x| x as f32 / i8::MAX as f32);
+        Vertex {
+            position: vec3(x, y, z),
+            normal: vec3(nx, ny, nz),
+            material: Material::from_index(self.material),
+            occlusion: 0,
+        }
+    }
+}
+
+/// Indexed triangle list of a chunk with a smooth surface, see [`surface_nets`].
+#[derive(Clone, Debug, Default)]
+pub struct SmoothMesh {
+    pub vertices: Vec<SmoothVertex>,
+    pub indices: Vec<u32>,
+}
+
+/// Mesh of a chunk in the vertex format of its meshing.
+#[derive(Clone, Debug)]
+pub enum ChunkMesh {
+    Blocky(PackedMesh),
+    Smooth(SmoothMesh),
+}
+
+impl ChunkMesh {
+    pub fn indices(&self) -> &[u32] {
+        match self {
+            ChunkMesh::Blocky(mesh) => &mesh.indices,
+            ChunkMesh::Smooth(mesh) => &mesh.indices,
+        }
+    }
+
+    fn vertex_data(&self) -> &[u8] {
+        match self {
+            ChunkMesh::Blocky(mesh) => bytemuck::cast_slice(&mesh.vertices),
+            ChunkMesh::Smooth(mesh) => bytemuck::cast_slice(&mesh.vertices),
+        }
+    }
+
+    /// Unindexed triangle list of the mesh.
+    pub fn triangles(&self) -> Vec<Vertex> {
+        match self {
+            ChunkMesh::Blocky(mesh) => mesh.triangles(),
+            ChunkMesh::Smooth(mesh) => mesh
+                .indices
+                .iter()
+                .map(|&i| mesh.vertices[i as usize].unpack())
+                .collect(),
+        }
+    }
+}
+
+/// Indexed triangle list of a chunk, in which equal vertices of adjacent quads are shared.
+#[derive(Clone, Debug, Default)]
+pub struct PackedMesh {
+    pub vertices: Vec<PackedVertex>,
+    pub indices: Vec<u32>,
+}
+
+impl PackedMesh {
+    pub fn new(triangles: &[Vertex]) -> Self {
+        puffin::profile_function!();
+
+        let mut mesh = Self::default();
+        let mut indices = HashMap::new();
+        for vertex in triangles {
+            let vertex = PackedVertex::pack(vertex);
+            let index = *indices.entry(vertex).or_insert_with(|| {
+                mesh.vertices.push(vertex);
+                mesh.vertices.len() as u32 - 1
+            });
+            mesh.indices.push(index);
+        }
+        mesh
+    }
+
+    /// Unindexed triangle list of the mesh.
+    pub fn triangles(&self) -> Vec<Vertex> {
+        self.indices
+            .iter()
+            .map(|&i| self.vertices[i as usize].unpack())
+            .collect()
+    }
+
+    /// Size of the vertex and index buffers in bytes.
+    pub fn size(&self) -> usize {
+        std::mem::size_of_val(self.vertices.as_slice())
+            + std::mem::size_of_val(self.indices.as_slice())
+    }
+}
 
 impl VoxelPipeline {
     pub fn new(
@@ -104,67 +289,96 @@ impl VoxelPipeline {
             ),
         });
 
-        let pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: None,
-            layout: Some(
-                &device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
-                    bind_group_layouts: &[&bind_group_layout, &chunk_bind_group_layout],
-                    ..Default::default()
+        let layout = device.create_pipeline_layout(&wgpu::PipelineLayoutDescriptor {
+            bind_group_layouts: &[&bind_group_layout, &chunk_bind_group_layout],
+            ..Default::default()
+        });
+        let create_pipeline = |entry_point: &str, buffer: wgpu::VertexBufferLayout| {
+            device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+                label: None,
+                layout: Some(&layout),
+                vertex: wgpu::VertexState {
+                    module: &shader,
+                    entry_point,
+                    buffers: &[buffer],
+                },
+                fragment: Some(wgpu::FragmentState {
+                    module: &shader,
+                    entry_point: "fragment",
+                    targets: &[Some(wgpu::ColorTargetState {
+                        format: color_format,
+                        blend: Some(wgpu::BlendState::REPLACE),
+                        write_mask: wgpu::ColorWrites::ALL,
+                    })],
                 }),
-            ),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: "vertex",
-                buffers: &[wgpu::VertexBufferLayout {
-                    array_stride: std::mem::size_of::<Vertex>() as wgpu::BufferAddress,
-                    step_mode: wgpu::VertexStepMode::Vertex,
-                    attributes: &[
-                        wgpu::VertexAttribute {
-                            offset: memoffset::offset_of!(Vertex, position) as wgpu::BufferAddress,
-                            shader_location: 0,
-                            format: wgpu::VertexFormat::Float32x3,
-                        },
-                        wgpu::VertexAttribute {
-                            offset: memoffset::offset_of!(Vertex, normal) as wgpu::BufferAddress,
-                            shader_location: 1,
-                            format: wgpu::VertexFormat::Float32x3,
-                        },
-                        wgpu::VertexAttribute {
-                            offset: memoffset::offset_of!(Vertex, color) as wgpu::BufferAddress,
-                            shader_location: 2,
-                            format: wgpu::VertexFormat::Uint32,
-                        },
-                    ],
-                }],
+                primitive: wgpu::PrimitiveState {
+                    topology: wgpu::PrimitiveTopology::TriangleList,
+                    strip_index_format: None,
+                    front_face: wgpu::FrontFace::Ccw,
+                    cull_mode: Some(wgpu::Face::Back),
+                    polygon_mode: wgpu::PolygonMode::Fill,
+                    unclipped_depth: false,
+                    conservative: false,
+                },
+                multisample: wgpu::MultisampleState::default(),
+                depth_stencil: Some(wgpu::DepthStencilState {
+                    format: depth_format,
+                    depth_write_enabled: true,
+                    depth_compare: wgpu::CompareFunction::LessEqual,
+                    stencil: Default::default(),
+                    bias: Default::default(),
+                }),
+                multiview: None,
+            })
+        };
+
+        let pipeline = create_pipeline(
+            "vertex",
+            wgpu::VertexBufferLayout {
+                array_stride: std::mem::size_of::<PackedVertex>() as wgpu::BufferAddress,
+                step_mode: wgpu::VertexStepMode::Vertex,
+                attributes: &[
+                    wgpu::VertexAttribute {
+                        offset: memoffset::offset_of!(PackedVertex, data) as wgpu::BufferAddress,
+                        shader_location: 0,
+                        format: wgpu::VertexFormat::Uint32,
+                    },
+                    wgpu::VertexAttribute {
+                        offset: memoffset::offset_of!(PackedVertex, material)
+                            as wgpu::BufferAddress,
+                        shader_location: 1,
+                        format: wgpu::VertexFormat::Uint32,
+                    },
+                ],
             },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "fragment",
-                targets: &[Some(wgpu::ColorTargetState {
-                    format: color_format,
-                    blend: Some(wgpu::BlendState::REPLACE),
-                    write_mask: wgpu::ColorWrites::ALL,
-                })],
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: Some(wgpu::Face::Back),
-                polygon_mode: wgpu::PolygonMode::Fill,
-                unclipped_depth: false,
-                conservative: false,
+        );
+
+        let smooth_pipeline = create_pipeline(
+            "vertex_smooth",
+            wgpu::VertexBufferLayout {
+                array_stride: std::mem::size_of::<SmoothVertex>() as wgpu::BufferAddress,
+                step_mode: wgpu::VertexStepMode::Vertex,
+                attributes: &[
+                    wgpu::VertexAttribute {
+                        offset: memoffset::offset_of!(SmoothVertex, position)
+                            as wgpu::BufferAddress,
+                        shader_location: 0,
+                        format: wgpu::VertexFormat::Uint16x4,
+                    },
+                    wgpu::VertexAttribute {
+                        offset: memoffset::offset_of!(SmoothVertex, normal) as wgpu::BufferAddress,
+                        shader_location: 1,
+                        format: wgpu::VertexFormat::Snorm8x4,
+                    },
+                    wgpu::VertexAttribute {
+                        offset: memoffset::offset_of!(SmoothVertex, material)
+                            as wgpu::BufferAddress,
+                        shader_location: 2,
+                        format: wgpu::VertexFormat::Uint32,
+                    },
+                ],
             },
-            multisample: wgpu::MultisampleState::default(),
-            depth_stencil: Some(wgpu::DepthStencilState {
-                format: depth_format,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::LessEqual,
-                stencil: Default::default(),
-                bias: Default::default(),
-            }),
-            multiview: None,
-        });
+        );
 
         let uniform_buffer = device.create_buffer(&wgpu::BufferDescriptor {
             label: None,
@@ -184,6 +398,7 @@ impl VoxelPipeline {
 
         Self {
             pipeline,
+            smooth_pipeline,
             bind_group_layout,
             chunk_bind_group_layout,
             bind_group,
@@ -195,60 +410,20 @@ impl VoxelPipeline {
 impl VoxelMesh {
     pub fn new(
         device: &wgpu::Device,
-        mask: &Field<bool, 3>,
-        vis: &Field<Vis, 3>,
-        color: &Field<Vector3<f32>, 3>,
+        mesh: &ChunkMesh,
         translation: Vector3<f32>,
         scale: f32,
     ) -> Self {
-        let mut vertices: Vec<Vertex> = Vec::new();
-
-        for [x, y, z] in mask.coordinates() {
-            if mask[[x, y, z]] {
-                let position = vec3(x as f32, y as f32, z as f32);
-                let mut faces = Vec::with_capacity(6);
-                if x == 0 || vis[[x, y, z]].contains(Vis::XN) {
-                    faces.push(CUBE_FACE_X_0);
-                }
-                if x == vis.extent() - 1 || vis[[x, y, z]].contains(Vis::XP) {
-                    faces.push(CUBE_FACE_X_1);
-                }
-                if y == 0 || vis[[x, y, z]].contains(Vis::YN) {
-                    faces.push(CUBE_FACE_Y_0);
-                }
-                if y == vis.extent() - 1 || vis[[x, y, z]].contains(Vis::YP) {
-                    faces.push(CUBE_FACE_Y_1);
-                }
-                if z == 0 || vis[[x, y, z]].contains(Vis::ZN) {
-                    faces.push(CUBE_FACE_Z_0);
-                }
-                if z == vis.extent() - 1 || vis[[x, y, z]].contains(Vis::ZP) {
-                    faces.push(CUBE_FACE_Z_1);
-                }
-                for face in faces {
-                    for [i, j, k] in face {
-                        let vs = [
-                            CUBE_VERTICES[i as usize],
-                            CUBE_VERTICES[j as usize],
-                            CUBE_VERTICES[k as usize],
-                        ];
-                        let normal = (vs[2] - vs[0]).cross(vs[1] - vs[0]).normalize();
-                        let color = util::pack(color[[x, y, z]]);
-                        vertices.extend(vs.into_iter().map(|v| Vertex {
-                            position: position + v,
-                            normal,
-                            color,
-                        }));
-                    }
-                }
-            }
-        }
-
-        let buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+        let vertex_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
             label: None,
-            contents: bytemuck::cast_slice(&vertices),
+            contents: mesh.vertex_data(),
             usage: wgpu::BufferUsages::VERTEX,
         });
+        let index_buffer = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::cast_slice(mesh.indices()),
+            usage: wgpu::BufferUsages::INDEX,
+        });
 
         Self {
             symmetry: Symmetry {
@@ -256,9 +431,185 @@ impl VoxelMesh {
                 translation,
                 scale,
             },
-            buffer,
-            count: vertices.len(),
+            vertex_buffer,
+            index_buffer,
+            count: mesh.indices().len(),
+            smooth: matches!(mesh, ChunkMesh::Smooth(_)),
+        }
+    }
+
+    pub fn triangle_count(&self) -> usize {
+        self.count / 3
+    }
+
+    /// Size of the GPU buffers in bytes.
+    pub fn size(&self) -> u64 {
+        self.vertex_buffer.size() + self.index_buffer.size()
+    }
+}
+
+/// Settings of chunk meshes, chunks are rebuilt when they change.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MeshOptions {
+    pub meshing: Meshing,
+    pub ambient_occlusion: bool,
+    /// Build coarser LODs by downsampling the voxels of LOD 0 with the given policy,
+    /// instead of sampling the terrain at coarser positions.
+    pub downsample: Option<Downsample>,
+}
+
+/// How chunk meshes are built from voxels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Meshing {
+    /// Two triangles per visible voxel face.
+    #[default]
+    Naive,
+    /// Coplanar adjacent faces of equal material are merged into larger quads, see [`greedy`].
+    Greedy,
+    /// Smooth isosurface of the density instead of voxel faces, see [`surface_nets`].
+    Smooth,
+}
+
+impl Meshing {
+    pub const ALL: [Meshing; 3] = [Meshing::Naive, Meshing::Greedy, Meshing::Smooth];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Meshing::Naive => "Naive",
+            Meshing::Greedy => "Greedy",
+            Meshing::Smooth => "Smooth",
+        }
+    }
+
+    /// How voxel faces are meshed, unless the mesh is smooth.
+    pub fn blocky(self) -> Option<BlockyMeshing> {
+        match self {
+            Meshing::Naive => Some(BlockyMeshing::Naive),
+            Meshing::Greedy => Some(BlockyMeshing::Greedy),
+            Meshing::Smooth => None,
+        }
+    }
+}
+
+/// The [`Meshing`]s which build meshes from voxel faces, see [`mesh`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockyMeshing {
+    Naive,
+    Greedy,
+}
+
+/// Triangulate the visible faces of all voxels, in voxel coordinates.
+/// Vertices are occluded by the neighbours in `env`, which is only needed for voxels with
+/// visible faces. Without it, there is no ambient occlusion.
+/// Smooth meshes are extracted with [`surface_nets::mesh`] instead.
+pub fn mesh(
+    faces: &Faces,
+    materials: &Field<Material, 3>,
+    env: Option<&Field<Env, 3>>,
+    meshing: BlockyMeshing,
+) -> Vec<Vertex> {
+    match meshing {
+        BlockyMeshing::Naive => naive(faces, materials, env),
+        BlockyMeshing::Greedy => greedy::mesh(faces, materials, env),
+    }
+}
+
+fn naive(
+    faces: &Faces,
+    materials: &Field<Material, 3>,
+    env: Option<&Field<Env, 3>>,
+) -> Vec<Vertex> {
+    puffin::profile_function!();
+
+    let e = faces.extent();
+    let mut vertices: Vec<Vertex> = Vec::new();
+
+    for x in 0..e {
+        for y in 0..e {
+            let mut any = faces.any(x, y);
+            while any != 0 {
+                let z = any.trailing_zeros() as usize;
+                any &= any - 1;
+
+                let position = vec3(x as f32, y as f32, z as f32);
+                for face in 0..6 {
+                    if faces.visible([x, y, z], face) {
+                        push_quad(
+                            &mut vertices,
+                            position,
+                            vec3(1.0, 1.0, 1.0),
+                            face,
+                            materials[[x, y, z]],
+                            env.map_or([0; 4], |env| face_occlusion(env[[x, y, z]], face)),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    vertices
+}
+
+/// Ambient occlusion of the four corners of a voxel face, indexed like in [`push_quad`].
+/// A corner is occluded by the two neighbours beside it and the one diagonal to it,
+/// in the layer in front of the face. Both neighbours beside it occlude it fully,
+/// since they also hide the diagonal one.
+pub fn face_occlusion(env: Env, face: usize) -> [u8; 4] {
+    let axis = face / 2;
+    let (u_axis, v_axis) = ((axis + 1) % 3, (axis + 2) % 3);
+    let mut front = [0; 3];
+    front[axis] = if face % 2 == 1 { 1 } else { -1 };
+
+    [0, 1, 2, 3].map(|corner| {
+        let (mut u, mut v) = (front, front);
+        u[u_axis] = if corner & 1 == 1 { 1 } else { -1 };
+        v[v_axis] = if corner & 2 == 2 { 1 } else { -1 };
+        let mut diagonal = u;
+        diagonal[v_axis] = v[v_axis];
+
+        let [u, v, diagonal] = [u, v, diagonal].map(|o| env.contains(Env::neighbour(o)));
+        if u && v {
+            3
+        } else {
+            u as u8 + v as u8 + diagonal as u8
         }
+    })
+}
+
+/// Emit the two triangles of a face of a box.
+/// The occlusion of each corner is indexed by its position along the two axes following
+/// the face normal, with one bit per axis. The quad is split along the diagonal whose
+/// corners are occluded most, so that occlusion is interpolated the same way on all faces.
+fn push_quad(
+    vertices: &mut Vec<Vertex>,
+    position: Vector3<f32>,
+    size: Vector3<f32>,
+    face: usize,
+    material: Material,
+    occlusion: [u8; 4],
+) {
+    let axis = face / 2;
+    let (u_axis, v_axis) = ((axis + 1) % 3, (axis + 2) % 3);
+    let corner_occlusion = |i: u16| occlusion[(i >> u_axis & 1 | (i >> v_axis & 1) << 1) as usize];
+
+    // Corners in winding order, split along the first and third corner.
+    let [[a, b, c], [_, _, d]] = CUBE_FACES[face];
+    let mut quad = [a, b, c, d];
+    let occlusion = quad.map(corner_occlusion);
+    if occlusion[0] + occlusion[2] < occlusion[1] + occlusion[3] {
+        quad.rotate_left(1);
+    }
+
+    for [i, j, k] in [[quad[0], quad[1], quad[2]], [quad[0], quad[2], quad[3]]] {
+        let vs = [i, j, k].map(|i| CUBE_VERTICES[i as usize].mul_element_wise(size));
+        let normal = (vs[2] - vs[0]).cross(vs[1] - vs[0]).normalize();
+        vertices.extend([i, j, k].into_iter().zip(vs).map(|(i, v)| Vertex {
+            position: position + v,
+            normal,
+            material,
+            occlusion: corner_occlusion(i),
+        }));
     }
 }
 
@@ -279,3 +630,147 @@ const CUBE_FACE_Y_0: [[u16; 3]; 2] = [[4, 0, 1], [4, 1, 5]];
 const CUBE_FACE_Y_1: [[u16; 3]; 2] = [[7, 3, 2], [7, 2, 6]];
 const CUBE_FACE_Z_0: [[u16; 3]; 2] = [[0, 2, 3], [0, 3, 1]];
 const CUBE_FACE_Z_1: [[u16; 3]; 2] = [[4, 5, 7], [4, 7, 6]];
+
+/// Normals of the triangles in [`CUBE_FACES`], which point into the cube.
+pub const FACE_NORMALS: [Vector3<f32>; 6] = [
+    vec3(1.0, 0.0, 0.0),
+    vec3(-1.0, 0.0, 0.0),
+    vec3(0.0, 1.0, 0.0),
+    vec3(0.0, -1.0, 0.0),
+    vec3(0.0, 0.0, 1.0),
+    vec3(0.0, 0.0, -1.0),
+];
+
+/// Ordered like the faces of [`Faces`].
+const CUBE_FACES: [[[u16; 3]; 2]; 6] = [
+    CUBE_FACE_X_0,
+    CUBE_FACE_X_1,
+    CUBE_FACE_Y_0,
+    CUBE_FACE_Y_1,
+    CUBE_FACE_Z_0,
+    CUBE_FACE_Z_1,
+];
+
+#[cfg(test)]
+mod test {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::field::{bitmask::Bitmask, Vis};
+
+    #[test]
+    fn single_voxel() {
+        let faces = Bitmask::new(3, |c| c == [1, 1, 1]).faces();
+        let materials = Field::new(3, |_| Material::Sand);
+        let vertices = mesh(&faces, &materials, None, BlockyMeshing::Naive);
+
+        assert_eq!(vertices.len(), 6 * 6);
+        for v in &vertices {
+            assert!((0..3).all(|i| (1.0..=2.0).contains(&v.position[i])));
+            assert_eq!(v.material, Material::Sand);
+        }
+        // Each face has its own normal.
+        for axis in 0..3 {
+            for sign in [-1.0, 1.0] {
+                let count = vertices.iter().filter(|v| v.normal[axis] == sign).count();
+                assert_eq!(count, 6);
+            }
+        }
+    }
+
+    /// Naive mesh of the shell, with visibility looked up in the voxel environments.
+    /// The mask includes a one-voxel apron.
+    fn reference(mask: &Field<bool, 3>, materials: &Field<Material, 3>) -> Vec<Vertex> {
+        let env = mask.environment();
+        let shell = mask.shell(&env);
+        let vis = env.visibility();
+        let flags = [Vis::XN, Vis::XP, Vis::YN, Vis::YP, Vis::ZN, Vis::ZP];
+
+        let mut vertices = Vec::new();
+        for c in materials.coordinates() {
+            let p = c.map(|i| i + 1);
+            for (face, flag) in flags.into_iter().enumerate() {
+                if shell[p] && vis[p].contains(flag) {
+                    let position = vec3(c[0], c[1], c[2]).cast().unwrap();
+                    push_quad(
+                        &mut vertices,
+                        position,
+                        vec3(1.0, 1.0, 1.0),
+                        face,
+                        materials[c],
+                        [0; 4],
+                    );
+                }
+            }
+        }
+        vertices
+    }
+
+    #[test]
+    fn identical_to_environment_mesh() {
+        let mut rng = StdRng::seed_from_u64(0);
+        for extent in [4, 17, 64] {
+            let mask = Field::new(extent + 2, |_| rng.gen_bool(0.7));
+            let faces = Bitmask::padded(&mask).faces();
+            let materials = Field::new(extent, |_| Material::ALL[rng.gen_range(0..6)]);
+
+            let vertices = mesh(&faces, &materials, None, BlockyMeshing::Naive);
+            assert_eq!(vertices, reference(&mask, &materials));
+            assert_eq!(vertices.len(), 6 * faces.count());
+        }
+    }
+
+    #[test]
+    fn packed_roundtrip() {
+        let mask = Field::new(64, |[x, y, z]| (x + 2 * y) % 7 < 3 && z < x);
+        let faces = Bitmask::new(64, |c| mask[c]).faces();
+        let env = mask.environment();
+        let materials = Field::new(64, |[x, y, z]| Material::ALL[(x / 8 + y / 8 + z) % 6]);
+
+        for meshing in [BlockyMeshing::Naive, BlockyMeshing::Greedy] {
+            let triangles = mesh(&faces, &materials, Some(&env), meshing);
+            let packed = PackedMesh::new(&triangles);
+            assert_eq!(packed.triangles(), triangles);
+            assert_eq!(packed.indices.len(), triangles.len());
+            // Both triangles of a quad share their diagonal.
+            assert!(packed.vertices.len() <= triangles.len() * 4 / 6);
+        }
+    }
+
+    #[test]
+    fn occlusion_of_top_face() {
+        // A voxel with a neighbour above it towards +x, and one towards -y.
+        let mask = Field::new(3, |c| [[1, 1, 1], [2, 1, 2], [1, 0, 2]].contains(&c));
+        let env = mask.environment()[[1, 1, 1]];
+        assert_eq!(Env::neighbour([1, 0, 1]), Env::PZP);
+        assert_eq!(Env::neighbour([0, -1, 1]), Env::ZNP);
+
+        // Corners along x then y, the one at +x and -y is fully occluded and
+        // the one at -x and +y is not occluded.
+        assert_eq!(face_occlusion(env, 5), [1, 3, 0, 1]);
+        assert_eq!(face_occlusion(env, 4), [0; 4]);
+        assert_eq!(face_occlusion(env, 1), [0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn quads_split_along_occluded_diagonal() {
+        for corner in 0..4 {
+            let mut occlusion = [0; 4];
+            occlusion[corner] = 2;
+            let mut vertices = Vec::new();
+            push_quad(
+                &mut vertices,
+                vec3(0.0, 0.0, 0.0),
+                vec3(1.0, 1.0, 1.0),
+                5,
+                Material::Stone,
+                occlusion,
+            );
+
+            // The occluded corner lies on the diagonal, shared by both triangles.
+            let occluded: Vec<_> = vertices.iter().filter(|v| v.occlusion == 2).collect();
+            assert_eq!(occluded.len(), 2);
+            assert!(vertices.iter().all(|v| v.normal == FACE_NORMALS[5]));
+        }
+    }
+}
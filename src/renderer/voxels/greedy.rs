@@ -0,0 +1,211 @@
+//! Greedy meshing: visible faces in each slice of the chunk are merged into maximal
+//! rectangles of equal material, growing first along rows and then across them.
+//! Faces with varying ambient occlusion across their corners are not merged, since the
+//! occlusion would be stretched across the whole rectangle.
+
+use super::{face_occlusion, push_quad, Vertex};
+use crate::{
+    field::{bitmask::Faces, Env, Field},
+    world::material::Material,
+};
+
+pub fn mesh(
+    faces: &Faces,
+    materials: &Field<Material, 3>,
+    env: Option<&Field<Env, 3>>,
+) -> Vec<Vertex> {
+    puffin::profile_function!();
+
+    let e = faces.extent();
+    let mut vertices = Vec::new();
+    // Materials and corner occlusion of the visible faces in the current slice,
+    // indexed by `u * e + v`.
+    let mut slice: Vec<Option<(Material, [u8; 4])>> = vec![None; e * e];
+
+    for face in 0..6 {
+        let axis = face / 2;
+        let (u_axis, v_axis) = ((axis + 1) % 3, (axis + 2) % 3);
+        let coordinate = |d: usize, u: usize, v: usize| {
+            let mut c = [0; 3];
+            c[axis] = d;
+            c[u_axis] = u;
+            c[v_axis] = v;
+            c
+        };
+
+        for d in 0..e {
+            for u in 0..e {
+                for v in 0..e {
+                    let c = coordinate(d, u, v);
+                    slice[u * e + v] = faces.visible(c, face).then(|| {
+                        let occlusion = env.map_or([0; 4], |env| face_occlusion(env[c], face));
+                        (materials[c], occlusion)
+                    });
+                }
+            }
+
+            for u in 0..e {
+                let mut v = 0;
+                while v < e {
+                    let Some(quad) = slice[u * e + v] else {
+                        v += 1;
+                        continue;
+                    };
+                    let (material, occlusion) = quad;
+                    let (mut width, mut height) = (1, 1);
+
+                    if occlusion.iter().all(|&o| o == occlusion[0]) {
+                        while v + width < e && slice[u * e + v + width] == Some(quad) {
+                            width += 1;
+                        }
+                        while u + height < e
+                            && (v..v + width).all(|w| slice[(u + height) * e + w] == Some(quad))
+                        {
+                            height += 1;
+                        }
+                    }
+
+                    for row in u..u + height {
+                        slice[row * e + v..row * e + v + width].fill(None);
+                    }
+
+                    let position = coordinate(d, u, v).map(|x| x as f32);
+                    let mut size = [1.0; 3];
+                    size[u_axis] = height as f32;
+                    size[v_axis] = width as f32;
+                    push_quad(
+                        &mut vertices,
+                        position.into(),
+                        size.into(),
+                        face,
+                        material,
+                        occlusion,
+                    );
+
+                    v += width;
+                }
+            }
+        }
+    }
+
+    vertices
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use cgmath::{vec2, InnerSpace};
+
+    use super::*;
+    use crate::{
+        field::bitmask::Bitmask,
+        renderer::voxels::{mesh as build, BlockyMeshing},
+        util,
+    };
+
+    /// Minimum corner, normal and material of a unit face.
+    type Face = ([i32; 3], [i32; 3], Material);
+
+    /// Unit faces covered by a mesh, along with the total area.
+    fn coverage(vertices: &[Vertex]) -> (HashSet<Face>, f32) {
+        let mut faces = HashSet::new();
+        let mut area = 0.0;
+        for triangle in vertices.chunks(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| triangle[i].position);
+            area += 0.5 * (b - a).cross(c - a).magnitude();
+
+            let normal = triangle[0].normal;
+            let axis = (0..3).find(|&i| normal[i] != 0.0).unwrap();
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+
+            // Rasterize the triangle at cell centers, including its edges.
+            let edge = |p: [f32; 2], q: [f32; 2], r: [f32; 2]| {
+                (q[0] - p[0]) * (r[1] - p[1]) - (q[1] - p[1]) * (r[0] - p[0])
+            };
+            let [a, b, c] = [a, b, c].map(|p| [p[u], p[v]]);
+            let min = |i: usize| a[i].min(b[i]).min(c[i]) as i32;
+            let max = |i: usize| a[i].max(b[i]).max(c[i]) as i32;
+            for cu in min(0)..max(0) {
+                for cv in min(1)..max(1) {
+                    let p = [cu as f32 + 0.5, cv as f32 + 0.5];
+                    let signs = [edge(a, b, p), edge(b, c, p), edge(c, a, p)];
+                    if signs.iter().all(|&s| s >= 0.0) || signs.iter().all(|&s| s <= 0.0) {
+                        let mut corner = [0; 3];
+                        corner[axis] = triangle[0].position[axis] as i32;
+                        corner[u] = cu;
+                        corner[v] = cv;
+                        faces.insert((
+                            corner,
+                            normal.map(|x| x as i32).into(),
+                            triangle[0].material,
+                        ));
+                    }
+                }
+            }
+        }
+        (faces, area)
+    }
+
+    fn terrain(extent: usize, materials: usize) -> (Faces, Field<Material, 3>, Field<Env, 3>) {
+        let mask = Field::new(extent, |[x, y, z]| {
+            let h = 4.0 * util::perlin(vec2(x as f32, y as f32) / 5.0) + 4.0;
+            z as f32 <= h
+        });
+        let faces = Bitmask::new(extent, |c| mask[c]).faces();
+        let material = Field::new(extent, |[x, y, _]| {
+            Material::ALL[(x / 3 * 3 + y / 3) % materials]
+        });
+        (faces, material, mask.environment())
+    }
+
+    #[test]
+    fn covers_same_surface_as_naive() {
+        for (materials, occlusion) in [(1, false), (3, false), (1, true)] {
+            let (faces, material, env) = terrain(12, materials);
+            let env = occlusion.then_some(&env);
+            let naive = build(&faces, &material, env, BlockyMeshing::Naive);
+            let greedy = build(&faces, &material, env, BlockyMeshing::Greedy);
+
+            let (naive_faces, naive_area) = coverage(&naive);
+            let (greedy_faces, greedy_area) = coverage(&greedy);
+            assert_eq!(naive_faces.len(), faces.count());
+            assert_eq!(naive_faces, greedy_faces);
+            assert_eq!(naive_area, greedy_area);
+            assert!(greedy.len() < naive.len());
+
+            // Vertices keep the occlusion of the face corners they lie on.
+            let occlusion = |vertices: &[Vertex]| {
+                let mut occlusion: Vec<_> = vertices
+                    .iter()
+                    .map(|v| {
+                        (
+                            v.position.map(|x| x as i32),
+                            v.normal.map(|x| x as i32),
+                            v.occlusion,
+                        )
+                    })
+                    .map(|(p, n, o)| ([p.x, p.y, p.z], [n.x, n.y, n.z], o))
+                    .collect();
+                occlusion.sort();
+                occlusion.dedup();
+                occlusion
+            };
+            let naive_occlusion = occlusion(&naive);
+            assert!(occlusion(&greedy)
+                .iter()
+                .all(|o| naive_occlusion.contains(o)));
+        }
+    }
+
+    #[test]
+    fn merges_flat_layer() {
+        let faces = Bitmask::new(8, |[_, _, z]| z == 0).faces();
+        let material = Field::new(8, |_| Material::Grass);
+        let greedy = build(&faces, &material, None, BlockyMeshing::Greedy);
+
+        // One quad for each side of the layer.
+        assert_eq!(greedy.len(), 6 * 6);
+        assert_eq!(faces.count(), 2 * 8 * 8 + 4 * 8);
+    }
+}
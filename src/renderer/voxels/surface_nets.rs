@@ -0,0 +1,287 @@
+//! Surface Nets: one vertex in each cell of eight density samples whose signs differ,
+//! placed at the mean of the surface crossings of the cell edges, and one quad for each
+//! edge between samples crossing the surface, connecting the vertices of the four cells
+//! around it.
+//!
+//! Samples are taken at voxel centers. A chunk owns the edges starting at its own samples,
+//! whose surrounding cells reach into the one-voxel apron of the density. Adjacent chunks
+//! therefore compute the same vertices along their seam, which keeps it watertight.
+//!
+//! Towards neighbours of a different LOD the vertices along the seam differ, so the
+//! boundary of the mesh is extended by a skirt reaching into the solid.
+
+use std::collections::{HashMap, HashSet};
+
+use cgmath::{vec3, InnerSpace, Vector3, Zero};
+
+use super::{SmoothMesh, SmoothVertex, Vertex};
+use crate::{field::Field, world::material::Material};
+
+/// Extract the isosurface at zero of a density including a one-voxel apron,
+/// in voxel coordinates of the chunk without apron. Sides with a non-zero skirt depth,
+/// in the order -x, +x, -y, +y, -z, +z, get a skirt of that many voxels.
+/// Vertices take the material of the solid sample of their cell which is closest to the
+/// surface.
+pub fn mesh(
+    density: &Field<f32, 3>,
+    materials: &Field<Material, 3>,
+    skirts: [usize; 6],
+) -> SmoothMesh {
+    puffin::profile_function!();
+
+    let p = density.extent();
+    let cells = p - 1;
+    let solid = |c: [usize; 3]| density[c] >= 0.0;
+
+    let mut mesh = SmoothMesh::default();
+    // Index of the vertex of each cell, once it is used by a quad.
+    let mut cell_vertices = vec![u32::MAX; cells.pow(3)];
+    // Cell of each vertex.
+    let mut vertex_cells = Vec::new();
+    let mut vertex = |mesh: &mut SmoothMesh, c: [usize; 3]| {
+        let index = &mut cell_vertices[(c[0] * cells + c[1]) * cells + c[2]];
+        if *index == u32::MAX {
+            *index = mesh.vertices.len() as u32;
+            vertex_cells.push(c);
+            mesh.vertices
+                .push(SmoothVertex::pack(&cell_vertex(density, materials, c)));
+        }
+        *index
+    };
+
+    for s in density.coordinates() {
+        // Only edges starting within the chunk, the apron belongs to the neighbours.
+        if s.iter().any(|&i| i == 0 || i == p - 1) {
+            continue;
+        }
+        for axis in 0..3 {
+            let mut t = s;
+            t[axis] += 1;
+            if solid(s) == solid(t) {
+                continue;
+            }
+
+            let (u, v) = ((axis + 1) % 3, (axis + 2) % 3);
+            let cell = |du: usize, dv: usize| {
+                let mut c = s;
+                c[u] -= 1 - du;
+                c[v] -= 1 - dv;
+                c
+            };
+            // Counterclockwise around the outward direction, which is +axis if `s` is solid.
+            let mut quad = [cell(0, 0), cell(1, 0), cell(1, 1), cell(0, 1)];
+            if !solid(s) {
+                quad.reverse();
+            }
+
+            let [a, b, c, d] = quad.map(|c| vertex(&mut mesh, c));
+            mesh.indices.extend([a, b, c, a, c, d]);
+        }
+    }
+
+    add_skirts(&mut mesh, &vertex_cells, cells, skirts);
+    mesh
+}
+
+/// Extend the boundary of the mesh at each side by a skirt of the given depth, whose
+/// vertices are moved into the solid along the normal. Skirts cover the cracks towards
+/// neighbours of a different LOD, whose surface passes through different positions.
+fn add_skirts(
+    mesh: &mut SmoothMesh,
+    vertex_cells: &[[usize; 3]],
+    cells: usize,
+    skirts: [usize; 6],
+) {
+    if skirts.iter().all(|&depth| depth == 0) {
+        return;
+    }
+    puffin::profile_function!();
+
+    let edges: HashSet<(u32, u32)> = mesh
+        .indices
+        .chunks(3)
+        .flat_map(|t| [(t[0], t[1]), (t[1], t[2]), (t[2], t[0])])
+        .collect();
+    // Edges used by a single triangle, in the direction of that triangle.
+    let boundary: Vec<(u32, u32)> = mesh
+        .indices
+        .chunks(3)
+        .flat_map(|t| [(t[0], t[1]), (t[1], t[2]), (t[2], t[0])])
+        .filter(|&(a, b)| !edges.contains(&(b, a)))
+        .collect();
+
+    let on_side = |vertex: u32, side: usize| {
+        let c = vertex_cells[vertex as usize][side / 2];
+        match side % 2 {
+            0 => c == 0,
+            _ => c == cells - 1,
+        }
+    };
+    // Skirts stay within the apron, where positions can still be packed.
+    let extent = (cells - 1) as f32;
+    let mut skirt_vertices = HashMap::new();
+    let mut skirt_vertex = |mesh: &mut SmoothMesh, vertex: u32, depth: usize| {
+        *skirt_vertices.entry((vertex, depth)).or_insert_with(|| {
+            let mut v = mesh.vertices[vertex as usize].unpack();
+            v.position += depth as f32 * v.normal;
+            v.position = v.position.map(|x| x.clamp(-1.0, extent + 1.0));
+            mesh.vertices.push(SmoothVertex::pack(&v));
+            mesh.vertices.len() as u32 - 1
+        })
+    };
+
+    for (a, b) in boundary {
+        for (side, &depth) in skirts.iter().enumerate() {
+            if depth == 0 || !on_side(a, side) || !on_side(b, side) {
+                continue;
+            }
+            let (c, d) = (skirt_vertex(mesh, a, depth), skirt_vertex(mesh, b, depth));
+            // The skirt continues the triangle across its boundary edge.
+            mesh.indices.extend([b, a, c, b, c, d]);
+        }
+    }
+}
+
+/// Vertex of the cell whose lowest sample is `c`.
+fn cell_vertex(density: &Field<f32, 3>, materials: &Field<Material, 3>, c: [usize; 3]) -> Vertex {
+    let corner = |i: usize| [c[0] + (i & 1), c[1] + (i >> 1 & 1), c[2] + (i >> 2 & 1)];
+    let offset = |i: usize| vec3(i & 1, i >> 1 & 1, i >> 2 & 1).cast::<f32>().unwrap();
+    let d: [f32; 8] = std::array::from_fn(|i| density[corner(i)]);
+
+    let mut sum = Vector3::<f32>::zero();
+    let mut crossings = 0;
+    let mut gradient = Vector3::<f32>::zero();
+    for i in 0..8 {
+        for axis in 0..3 {
+            let j = i | 1 << axis;
+            if i == j {
+                continue;
+            }
+            gradient[axis] += d[j] - d[i];
+            if (d[i] >= 0.0) != (d[j] >= 0.0) {
+                let t = (d[i] / (d[i] - d[j])).clamp(0.0, 1.0);
+                let t = if t.is_nan() { 0.5 } else { t };
+                sum += offset(i) + t * (offset(j) - offset(i));
+                crossings += 1;
+            }
+        }
+    }
+
+    // Solid voxels have a positive density, so the gradient points inwards like the normals
+    // of the blocky meshes.
+    let normal = if gradient.magnitude2() > 0.0 && gradient.magnitude2().is_finite() {
+        gradient.normalize()
+    } else {
+        vec3(0.0, 0.0, -1.0)
+    };
+
+    // Samples are at voxel centers, and the first sample lies within the apron.
+    let position = vec3(c[0], c[1], c[2]).cast::<f32>().unwrap() - vec3(0.5, 0.5, 0.5)
+        + sum / crossings.max(1) as f32;
+
+    let material = (0..8)
+        .filter(|&i| d[i] >= 0.0)
+        .min_by(|&i, &j| d[i].total_cmp(&d[j]))
+        .map_or(Material::default(), |i| materials[corner(i)]);
+
+    Vertex {
+        position,
+        normal,
+        material,
+        occlusion: 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::world::{generator::TerrainGenerator, sample_position, N};
+
+    /// Rolling hills, which continue seamlessly across chunks.
+    struct Hills;
+
+    impl TerrainGenerator for Hills {
+        fn name(&self) -> &str {
+            "Hills"
+        }
+
+        fn sample(&self, origin: Vector3<isize>, lod: usize, extent: usize) -> Field<f32, 3> {
+            Field::new(extent, |c| {
+                let p = sample_position(origin, lod, c);
+                12.0 + 8.0 * (p.x / 9.0).sin() * (p.y / 7.0).cos() + 0.2 * p.x - p.z
+            })
+        }
+    }
+
+    #[test]
+    fn flat_surface() {
+        // Solid at and below the third layer.
+        let density = Field::new(6, |[_, _, z]| 2.0 - z as f32);
+        let materials = Field::new(6, |[_, _, z]| Material::ALL[z]);
+        let triangles: Vec<_> = {
+            let mesh = mesh(&density, &materials, [0; 6]);
+            mesh.indices
+                .iter()
+                .map(|&i| mesh.vertices[i as usize].unpack())
+                .collect()
+        };
+
+        // One quad for each of the 4x4 voxel columns.
+        assert_eq!(triangles.len(), 6 * 4 * 4);
+        for v in &triangles {
+            // Centered on the third sample, which is the second voxel of the chunk.
+            assert_eq!(v.position.z, 1.5);
+            assert_eq!(v.normal, vec3(0.0, 0.0, -1.0));
+            // The material of the top solid layer.
+            assert_eq!(v.material, Material::ALL[2]);
+        }
+        for triangle in triangles.chunks(3) {
+            let [a, b, c] = [0, 1, 2].map(|i| triangle[i].position);
+            // Counterclockwise from above.
+            assert!((b - a).cross(c - a).z > 0.0);
+        }
+    }
+
+    #[test]
+    fn watertight_seams() {
+        let lod = 1;
+        let keys = [vec3(0, 0, 0), vec3(1, 0, 0), vec3(0, 1, 0), vec3(1, 1, 0)];
+
+        // Number of triangles using each edge, keyed by the world space positions of its ends.
+        let mut edges = HashMap::<[[i32; 3]; 2], usize>::new();
+        for key in keys {
+            let density = Hills.density(key, lod);
+            let mesh = mesh(
+                &density,
+                &Field::new(density.extent(), |_| Material::Stone),
+                [0; 6],
+            );
+            for triangle in mesh.indices.chunks(3) {
+                let [a, b, c] = [0, 1, 2].map(|i| {
+                    let v = mesh.vertices[triangle[i] as usize].unpack();
+                    let origin = sample_position(N as isize * key, lod, [0; 3]);
+                    let p = origin + (1 << lod) as f32 * v.position;
+                    p.map(|x| (x * 64.0).round() as i32).into()
+                });
+                for edge in [[a, b], [b, c], [c, a]] {
+                    let mut edge: [[i32; 3]; 2] = edge;
+                    edge.sort();
+                    *edges.entry(edge).or_default() += 1;
+                }
+            }
+        }
+
+        // Every edge away from the outer boundary of the four chunks is shared by two triangles.
+        let inner = |p: [i32; 3]| (0..2).all(|i| (2 * 64..(2 * N as i32 - 2) * 64).contains(&p[i]));
+        let inner_edges: Vec<_> = edges
+            .iter()
+            .filter(|(edge, _)| edge.iter().all(|&p| inner(p)))
+            .collect();
+        assert!(inner_edges.len() > 1000);
+        for (edge, &count) in inner_edges {
+            assert_eq!(count, 2, "edge {edge:?}");
+        }
+    }
+}
@@ -0,0 +1,387 @@
+//! Work queue of the chunk workers. Tasks are started in order of their priority, and
+//! workers block while there is nothing to do.
+
+use std::{
+    cmp::{Ordering, Reverse},
+    collections::{BinaryHeap, HashMap},
+    hash::Hash,
+    sync::{
+        atomic::{self, AtomicBool},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+};
+
+/// Set once the result of a job is no longer needed, which long running jobs check
+/// between their stages to stop early.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken(Arc<AtomicBool>);
+
+/// Returned by work which stopped because its [`CancellationToken`] was cancelled.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cancelled;
+
+impl CancellationToken {
+    pub fn cancel(&self) {
+        self.0.store(true, atomic::Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Fails once the token is cancelled, to be used with `?` between stages of work.
+    pub fn check(&self) -> Result<(), Cancelled> {
+        match self.is_cancelled() {
+            true => Err(Cancelled),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Distributes tasks, one per key, to worker threads. Tasks with the smallest priority are
+/// started first. The context is shared by all tasks until the scheduler is restarted.
+pub struct Scheduler<K, T, P, C> {
+    state: Mutex<State<K, T, P, C>>,
+    /// Notified whenever a task is added or the scheduler shuts down.
+    available: Condvar,
+}
+
+struct State<K, T, P, C> {
+    /// Tasks which are not started yet, along with the serial number of their heap entry.
+    pending: HashMap<K, (T, u64)>,
+    /// Priorities of the pending tasks. Entries of replaced or cancelled tasks remain until
+    /// they are popped, and are recognized by their serial number.
+    heap: BinaryHeap<Entry<K, P>>,
+    in_progress: HashMap<K, (T, CancellationToken)>,
+    /// Jobs which were cancelled while in progress.
+    cancelled: usize,
+    serial: u64,
+    context: C,
+    shutdown: bool,
+}
+
+struct Entry<K, P> {
+    priority: Reverse<P>,
+    serial: u64,
+    key: K,
+}
+
+impl<K, P: Ord> PartialEq for Entry<K, P> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<K, P: Ord> Eq for Entry<K, P> {}
+
+impl<K, P: Ord> PartialOrd for Entry<K, P> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K, P: Ord> Ord for Entry<K, P> {
+    /// Earlier tasks go first among those of equal priority.
+    fn cmp(&self, other: &Self) -> Ordering {
+        (&self.priority, Reverse(self.serial)).cmp(&(&other.priority, Reverse(other.serial)))
+    }
+}
+
+/// A task taken by a worker, see [`Scheduler::finish`].
+pub struct Job<K, T, C> {
+    pub key: K,
+    pub task: T,
+    pub context: C,
+    /// Cancelled along with the task, or once another job of the same key starts.
+    pub token: CancellationToken,
+}
+
+impl<K, T, P, C> State<K, T, P, C> {
+    fn cancel(&mut self, token: &CancellationToken) {
+        token.cancel();
+        self.cancelled += 1;
+    }
+}
+
+impl<K, T, P, C> Scheduler<K, T, P, C>
+where
+    K: Copy + Eq + Hash,
+    T: Copy + PartialEq,
+    P: Ord,
+    C: Clone,
+{
+    pub fn new(context: C) -> Self {
+        Self {
+            state: Mutex::new(State {
+                pending: HashMap::new(),
+                heap: BinaryHeap::new(),
+                in_progress: HashMap::new(),
+                cancelled: 0,
+                serial: 0,
+                context,
+                shutdown: false,
+            }),
+            available: Condvar::new(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State<K, T, P, C>> {
+        self.state.lock().unwrap()
+    }
+
+    /// Schedule a task, replacing the pending task of the same key.
+    pub fn push(&self, key: K, task: T, priority: P) {
+        let mut state = self.state();
+        state.serial += 1;
+        let serial = state.serial;
+        state.pending.insert(key, (task, serial));
+        state.heap.push(Entry {
+            priority: Reverse(priority),
+            serial,
+            key,
+        });
+        self.available.notify_one();
+    }
+
+    /// Recompute the priorities of all pending tasks.
+    pub fn reprioritize(&self, mut priority: impl FnMut(K, T) -> P) {
+        let mut state = self.state();
+        let heap = state
+            .pending
+            .iter()
+            .map(|(&key, &(task, serial))| Entry {
+                priority: Reverse(priority(key, task)),
+                serial,
+                key,
+            })
+            .collect();
+        state.heap = heap;
+    }
+
+    /// Cancel the tasks for which the predicate does not hold. Jobs of cancelled tasks
+    /// which are already in progress are discarded once they finish.
+    pub fn retain(&self, mut f: impl FnMut(K, T) -> bool) {
+        let mut state = self.state();
+        state.pending.retain(|&key, &mut (task, _)| f(key, task));
+        let cancelled: Vec<_> = state
+            .in_progress
+            .iter()
+            .filter(|&(&key, &(task, _))| !f(key, task))
+            .map(|(&key, _)| key)
+            .collect();
+        for key in cancelled {
+            let (_, token) = state.in_progress.remove(&key).unwrap();
+            state.cancel(&token);
+        }
+    }
+
+    /// Discard all tasks, and run the following ones with a new context.
+    /// Jobs in progress are cancelled.
+    pub fn restart(&self, context: C) {
+        let mut state = self.state();
+        state.pending.clear();
+        state.heap.clear();
+        for (_, (_, token)) in std::mem::take(&mut state.in_progress) {
+            state.cancel(&token);
+        }
+        state.context = context;
+    }
+
+    /// Wake up all workers and stop handing out jobs. Jobs in progress are cancelled.
+    pub fn shutdown(&self) {
+        let mut state = self.state();
+        state.shutdown = true;
+        for (_, (_, token)) in std::mem::take(&mut state.in_progress) {
+            state.cancel(&token);
+        }
+        self.available.notify_all();
+    }
+
+    /// Whether the task is pending or in progress, with the current context.
+    pub fn is_scheduled(&self, key: K, task: T) -> bool {
+        let state = self.state();
+        state.pending.get(&key).map(|&(task, _)| task) == Some(task)
+            || state.in_progress.get(&key).map(|&(task, _)| task) == Some(task)
+    }
+
+    pub fn in_progress(&self) -> Vec<K> {
+        self.state().in_progress.keys().copied().collect()
+    }
+
+    pub fn pending_count(&self) -> usize {
+        self.state().pending.len()
+    }
+
+    /// Number of jobs which were cancelled while in progress, see [`Job::token`].
+    pub fn cancelled_count(&self) -> usize {
+        self.state().cancelled
+    }
+
+    /// Take the pending task with the smallest priority, blocking until there is one.
+    /// Returns `None` once the scheduler shuts down.
+    pub fn next(&self) -> Option<Job<K, T, C>> {
+        let mut state = self.state();
+        loop {
+            if state.shutdown {
+                return None;
+            }
+            while let Some(entry) = state.heap.pop() {
+                let Some(&(task, serial)) = state.pending.get(&entry.key) else {
+                    continue;
+                };
+                if serial != entry.serial {
+                    continue;
+                }
+                state.pending.remove(&entry.key);
+                let token = CancellationToken::default();
+                if let Some((_, replaced)) =
+                    state.in_progress.insert(entry.key, (task, token.clone()))
+                {
+                    state.cancel(&replaced);
+                }
+                return Some(Job {
+                    key: entry.key,
+                    task,
+                    context: state.context.clone(),
+                    token,
+                });
+            }
+            state = self.available.wait(state).unwrap();
+        }
+    }
+
+    /// Mark a job as done. Unless it was cancelled or replaced by another job of the same
+    /// key in the meantime, `complete` is called before any further change to the tasks,
+    /// and the result is returned.
+    pub fn finish<R>(&self, job: &Job<K, T, C>, complete: impl FnOnce() -> R) -> Option<R> {
+        let mut state = self.state();
+        if job.token.is_cancelled() {
+            return None;
+        }
+        state.in_progress.remove(&job.key);
+        Some(complete())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{sync::Arc, thread, time::Duration};
+
+    use super::*;
+
+    type TestScheduler = Scheduler<i32, usize, i32, &'static str>;
+
+    #[test]
+    fn priority_order() {
+        let scheduler = TestScheduler::new("a");
+        for (key, priority) in [(1, 5), (2, 1), (3, 3), (4, 3)] {
+            scheduler.push(key, 0, priority);
+        }
+        // Replacing a task also replaces its priority.
+        scheduler.push(1, 7, 2);
+
+        let order: Vec<_> = (0..4)
+            .map(|_| {
+                let job = scheduler.next().unwrap();
+                (job.key, job.task)
+            })
+            .collect();
+        assert_eq!(order, [(2, 0), (1, 7), (3, 0), (4, 0)]);
+        assert_eq!(scheduler.pending_count(), 0);
+    }
+
+    #[test]
+    fn reprioritize() {
+        let scheduler = TestScheduler::new("a");
+        for key in 0..5 {
+            scheduler.push(key, 0, key);
+        }
+        // Closest to 3 first, and earlier tasks first among equally close ones.
+        scheduler.reprioritize(|key, _| (key - 3).abs());
+        let order: Vec<_> = (0..5).map(|_| scheduler.next().unwrap().key).collect();
+        assert_eq!(order, [3, 2, 4, 1, 0]);
+    }
+
+    #[test]
+    fn cancellation() {
+        let scheduler = TestScheduler::new("a");
+        for key in 0..4 {
+            scheduler.push(key, 0, key);
+        }
+        let started = scheduler.next().unwrap();
+        let replaced = scheduler.next().unwrap();
+        assert_eq!((started.key, replaced.key), (0, 1));
+        assert!(scheduler.is_scheduled(0, 0));
+
+        // Cancelled tasks are neither handed out nor completed.
+        scheduler.retain(|key, _| key != 0 && key != 2);
+        assert!(!scheduler.is_scheduled(0, 0));
+        assert!(started.token.is_cancelled());
+        assert_eq!(started.token.check(), Err(Cancelled));
+        assert_eq!(scheduler.finish(&started, || ()), None);
+
+        // A job is outdated once another one of the same key starts.
+        scheduler.push(1, 1, 0);
+        assert!(!replaced.token.is_cancelled());
+        let replacement = scheduler.next().unwrap();
+        assert_eq!((replacement.key, replacement.task), (1, 1));
+        assert!(replaced.token.is_cancelled());
+        assert_eq!(scheduler.finish(&replaced, || ()), None);
+        assert_eq!(scheduler.finish(&replacement, || "done"), Some("done"));
+        assert_eq!(replacement.token.check(), Ok(()));
+
+        // Restarting discards everything and changes the context.
+        let job = scheduler.next().unwrap();
+        assert_eq!((job.key, job.context), (3, "a"));
+        scheduler.push(4, 0, 0);
+        scheduler.restart("b");
+        assert_eq!(scheduler.pending_count(), 0);
+        assert!(job.token.is_cancelled());
+        assert_eq!(scheduler.finish(&job, || ()), None);
+        assert_eq!(scheduler.cancelled_count(), 3);
+        scheduler.push(3, 0, 0);
+        let job = scheduler.next().unwrap();
+        assert_eq!(job.context, "b");
+        assert_eq!(scheduler.finish(&job, || ()), Some(()));
+
+        // Shutting down cancels the jobs in progress.
+        scheduler.push(3, 1, 0);
+        let job = scheduler.next().unwrap();
+        scheduler.shutdown();
+        assert!(job.token.is_cancelled());
+        assert!(scheduler.next().is_none());
+    }
+
+    #[test]
+    fn workers_block_until_work_or_shutdown() {
+        let scheduler = Arc::new(TestScheduler::new("a"));
+        let workers: Vec<_> = (0..3)
+            .map(|_| {
+                let scheduler = scheduler.clone();
+                thread::spawn(move || {
+                    let mut done = Vec::new();
+                    while let Some(job) = scheduler.next() {
+                        done.extend(scheduler.finish(&job, || job.key));
+                    }
+                    done
+                })
+            })
+            .collect();
+
+        thread::sleep(Duration::from_millis(20));
+        for key in 0..10 {
+            scheduler.push(key, 0, 0);
+        }
+        while scheduler.pending_count() > 0 || !scheduler.in_progress().is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        scheduler.shutdown();
+
+        let mut done: Vec<_> = workers
+            .into_iter()
+            .flat_map(|worker| worker.join().unwrap())
+            .collect();
+        done.sort();
+        assert_eq!(done, (0..10).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,40 @@
+//! A declarative language for terrain generation, see the Readme.
+//!
+//! A program declares fields, each of which is a pipeline of stages:
+//!
+//! ```text
+//! height: field<float, 2>
+//! |> {
+//!     n = fbm(@x, @y);
+//!     n * 50
+//! }
+//! ```
+//!
+//! Blocks are evaluated per voxel, where `@x`, `@y` and `@z` are world-space coordinates
+//! and `$` is the output of the previous stage. A pipeline starting with a named stage
+//! such as `normal` operates on the preceding declaration.
+//!
+//! Checked programs are evaluated either on the CPU with [`ir::Module::evaluate`],
+//! or on the GPU with [`gpu::GpuProgram`], which runs one compute pass per stage.
+//! [`analysis::analyze`] warns about operations which may produce NaN or infinity.
+
+pub mod analysis;
+pub mod ast;
+pub mod builtin;
+pub mod check;
+pub mod diagnostic;
+pub mod eval;
+pub mod gpu;
+pub mod ir;
+pub mod lexer;
+pub mod parser;
+pub mod watch;
+pub mod wgsl;
+
+use diagnostic::Diagnostic;
+use ir::Module;
+
+/// Parse and check a terrain program.
+pub fn compile(source: &str) -> Result<Module, Diagnostic> {
+    check::check(&parser::parse(source)?)
+}
@@ -0,0 +1,635 @@
+use std::fmt::Display;
+
+use super::{
+    ast::{BinaryOp, UnaryOp},
+    builtin::Builtin,
+    diagnostic::Diagnostic,
+    ir::{Kernel, Module, Node, NodeKind, Stage, Value},
+};
+
+/// A closed range of real numbers. Infinite bounds mean the range is unbounded,
+/// not that the value itself can be infinite.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Interval {
+    pub lo: f32,
+    pub hi: f32,
+}
+
+/// The values an expression or field may take.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Range {
+    Float(Interval),
+    Bool,
+    Vec3([Interval; 3]),
+}
+
+/// Value ranges of all fields, and warnings about operations which may yield NaN or infinity.
+#[derive(Debug, Clone)]
+pub struct Analysis {
+    pub ranges: Vec<Range>,
+    pub warnings: Vec<Diagnostic>,
+}
+
+/// Propagate value ranges through a program with interval arithmetic.
+///
+/// Coordinates are arbitrary finite numbers, and noise functions are bounded.
+/// Every operation that is undefined or infinite for some values in the range of its arguments
+/// is reported once, after which only the valid part of the range is propagated.
+/// Overflow of large finite values is only reported for `exp`.
+pub fn analyze(module: &Module) -> Analysis {
+    let mut analyzer = Analyzer {
+        ranges: Vec::new(),
+        warnings: Vec::new(),
+    };
+
+    for field in &module.fields {
+        let mut current = field.source.map(|source| analyzer.ranges[source]);
+        for stage in &field.stages {
+            current = Some(match stage {
+                Stage::Kernel(kernel) => analyzer.kernel(kernel, current),
+                Stage::Normal => Range::Vec3([
+                    Interval::new(-1.0, 1.0),
+                    Interval::new(-1.0, 1.0),
+                    Interval::new(0.0, 1.0),
+                ]),
+                // A weighted average stays within the range of its input.
+                Stage::Blur => current.unwrap(),
+            });
+        }
+        analyzer.ranges.push(current.unwrap());
+    }
+
+    Analysis {
+        ranges: analyzer.ranges,
+        warnings: analyzer.warnings,
+    }
+}
+
+impl Interval {
+    pub const REALS: Interval = Interval {
+        lo: f32::NEG_INFINITY,
+        hi: f32::INFINITY,
+    };
+
+    pub fn new(lo: f32, hi: f32) -> Self {
+        // Bounds are NaN where unbounded ranges cancel out, as in `inf - inf`.
+        Self {
+            lo: if lo.is_nan() { f32::NEG_INFINITY } else { lo },
+            hi: if hi.is_nan() { f32::INFINITY } else { hi },
+        }
+    }
+
+    pub fn point(x: f32) -> Self {
+        Self::new(x, x)
+    }
+
+    /// The smallest interval containing all the given numbers.
+    fn hull_of(xs: impl IntoIterator<Item = f32>) -> Self {
+        xs.into_iter().fold(
+            Interval {
+                lo: f32::INFINITY,
+                hi: f32::NEG_INFINITY,
+            },
+            |acc, x| Interval {
+                lo: acc.lo.min(x),
+                hi: acc.hi.max(x),
+            },
+        )
+    }
+
+    pub fn hull(self, other: Self) -> Self {
+        Self::new(self.lo.min(other.lo), self.hi.max(other.hi))
+    }
+
+    pub fn contains(self, x: f32) -> bool {
+        self.lo <= x && x <= self.hi
+    }
+
+    pub fn is_point(self) -> bool {
+        self.lo == self.hi
+    }
+
+    /// Apply a non-decreasing function.
+    fn monotone(self, f: impl Fn(f32) -> f32) -> Self {
+        Self::new(f(self.lo), f(self.hi))
+    }
+
+    fn neg(self) -> Self {
+        Self::new(-self.hi, -self.lo)
+    }
+
+    fn add(self, other: Self) -> Self {
+        Self::new(self.lo + other.lo, self.hi + other.hi)
+    }
+
+    fn sub(self, other: Self) -> Self {
+        self.add(other.neg())
+    }
+
+    fn mul(self, other: Self) -> Self {
+        // Zero times an unbounded number is still zero.
+        let product = |a: f32, b: f32| if a == 0.0 || b == 0.0 { 0.0 } else { a * b };
+        Self::hull_of([
+            product(self.lo, other.lo),
+            product(self.lo, other.hi),
+            product(self.hi, other.lo),
+            product(self.hi, other.hi),
+        ])
+    }
+
+    /// Division by an interval not containing zero.
+    fn div(self, other: Self) -> Self {
+        self.mul(Self::new(1.0 / other.hi, 1.0 / other.lo))
+    }
+
+    fn abs(self) -> Self {
+        if self.lo >= 0.0 {
+            self
+        } else if self.hi <= 0.0 {
+            self.neg()
+        } else {
+            Self::new(0.0, self.hi.max(-self.lo))
+        }
+    }
+
+    fn square(self) -> Self {
+        let abs = self.abs();
+        abs.mul(abs)
+    }
+
+    fn min(self, other: Self) -> Self {
+        Self::new(self.lo.min(other.lo), self.hi.min(other.hi))
+    }
+
+    fn max(self, other: Self) -> Self {
+        Self::new(self.lo.max(other.lo), self.hi.max(other.hi))
+    }
+
+    /// The part of the interval at or above `lo`, assuming there is one.
+    fn at_least(self, lo: f32) -> Self {
+        Self::new(self.lo.max(lo), self.hi.max(lo))
+    }
+}
+
+impl Display for Interval {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}, {}]", self.lo, self.hi)
+    }
+}
+
+impl Range {
+    #[track_caller]
+    fn float(self) -> Interval {
+        match self {
+            Range::Float(x) => x,
+            _ => panic!("expected float, found {self:?}"),
+        }
+    }
+
+    #[track_caller]
+    fn vec3(self) -> [Interval; 3] {
+        match self {
+            Range::Vec3(x) => x,
+            _ => panic!("expected vec3, found {self:?}"),
+        }
+    }
+}
+
+struct Analyzer {
+    ranges: Vec<Range>,
+    warnings: Vec<Diagnostic>,
+}
+
+impl Analyzer {
+    fn kernel(&mut self, kernel: &Kernel, input: Option<Range>) -> Range {
+        let mut locals = vec![Range::Bool; kernel.locals];
+        for statement in &kernel.statements {
+            locals[statement.local] = self.node(&statement.value, &locals, input);
+        }
+        self.node(&kernel.value, &locals, input)
+    }
+
+    fn warn(&mut self, message: String, node: &Node) {
+        self.warnings.push(Diagnostic::new(message, node.span));
+    }
+
+    fn node(&mut self, node: &Node, locals: &[Range], input: Option<Range>) -> Range {
+        let mut eval = |node: &Node| self.node(node, locals, input);
+        match &node.kind {
+            NodeKind::Constant(Value::Float(x)) => Range::Float(Interval::point(*x)),
+            NodeKind::Constant(Value::Bool(_)) => Range::Bool,
+            NodeKind::Constant(Value::Vec3(v)) => Range::Vec3([v.x, v.y, v.z].map(Interval::point)),
+            NodeKind::Local(slot) => locals[*slot],
+            NodeKind::Field(index) => self.ranges[*index],
+            NodeKind::Coordinate(_) => Range::Float(Interval::REALS),
+            NodeKind::Input => input.unwrap(),
+            NodeKind::Component(inner, axis) => Range::Float(eval(inner).vec3()[axis.index()]),
+            NodeKind::Unary(op, operand) => match (op, eval(operand)) {
+                (UnaryOp::Neg, Range::Float(x)) => Range::Float(x.neg()),
+                (UnaryOp::Neg, Range::Vec3(v)) => Range::Vec3(v.map(Interval::neg)),
+                _ => Range::Bool,
+            },
+            NodeKind::Binary(op, lhs, rhs) => {
+                let (a, b) = (eval(lhs), eval(rhs));
+                self.binary(*op, a, b, rhs)
+            }
+            NodeKind::Call(builtin, args) => {
+                let ranges = args.iter().map(eval).collect::<Vec<_>>();
+                self.call(*builtin, &ranges, args, node)
+            }
+        }
+    }
+
+    fn binary(&mut self, op: BinaryOp, lhs: Range, rhs: Range, divisor: &Node) -> Range {
+        use Range::{Float, Vec3};
+        match (op, lhs, rhs) {
+            (BinaryOp::Add, Float(a), Float(b)) => Float(a.add(b)),
+            (BinaryOp::Sub, Float(a), Float(b)) => Float(a.sub(b)),
+            (BinaryOp::Mul, Float(a), Float(b)) => Float(a.mul(b)),
+            (BinaryOp::Div, Float(a), Float(b)) => Float(self.div(a, b, divisor)),
+            (BinaryOp::Rem, Float(a), Float(b)) => {
+                if b.contains(0.0) {
+                    self.warn(
+                        format!(
+                            "remainder of a division by zero is NaN: the divisor ranges over {b}"
+                        ),
+                        divisor,
+                    );
+                }
+                // The remainder is smaller than the divisor and has the sign of the dividend.
+                let bound = b.abs().hi;
+                Float(Interval::new(
+                    a.lo.max(-bound).min(0.0),
+                    a.hi.min(bound).max(0.0),
+                ))
+            }
+            (BinaryOp::Add, Vec3(a), Vec3(b)) => Vec3([0, 1, 2].map(|i| a[i].add(b[i]))),
+            (BinaryOp::Sub, Vec3(a), Vec3(b)) => Vec3([0, 1, 2].map(|i| a[i].sub(b[i]))),
+            (BinaryOp::Mul, Vec3(a), Vec3(b)) => Vec3([0, 1, 2].map(|i| a[i].mul(b[i]))),
+            (BinaryOp::Div, Vec3(a), Vec3(b)) => {
+                Vec3([0, 1, 2].map(|i| self.div(a[i], b[i], divisor)))
+            }
+            (BinaryOp::Mul, Vec3(a), Float(b)) | (BinaryOp::Mul, Float(b), Vec3(a)) => {
+                Vec3(a.map(|a| a.mul(b)))
+            }
+            (BinaryOp::Div, Vec3(a), Float(b)) => {
+                let b = self.div(Interval::point(1.0), b, divisor);
+                Vec3(a.map(|a| a.mul(b)))
+            }
+            _ => Range::Bool,
+        }
+    }
+
+    fn div(&mut self, a: Interval, b: Interval, divisor: &Node) -> Interval {
+        if b.contains(0.0) {
+            self.warn(
+                format!("division by zero: the divisor ranges over {b}"),
+                divisor,
+            );
+            return Interval::REALS;
+        }
+        a.div(b)
+    }
+
+    fn call(&mut self, builtin: Builtin, ranges: &[Range], args: &[Node], call: &Node) -> Range {
+        let f = |i: usize| ranges[i].float();
+        let v = |i: usize| ranges[i].vec3();
+        Range::Float(match builtin {
+            Builtin::Perlin => Interval::new(-1.0, 1.0),
+            // The sum of 8 octaves with halving amplitudes.
+            Builtin::Fbm => Interval::new(-2.0, 2.0),
+            // The nearest seed is at most in a corner of the cell containing the point.
+            Builtin::Worley => Interval::new(0.0, std::f32::consts::SQRT_2),
+            Builtin::Abs => f(0).abs(),
+            Builtin::Sqrt => {
+                if f(0).lo < 0.0 {
+                    self.warn(
+                        format!(
+                            "`sqrt` of a negative number is NaN: the argument ranges over {}",
+                            f(0)
+                        ),
+                        &args[0],
+                    );
+                }
+                if f(0).hi < 0.0 {
+                    return Range::Float(Interval::point(0.0));
+                }
+                f(0).at_least(0.0).monotone(f32::sqrt)
+            }
+            Builtin::Exp => {
+                if f(0).hi > f32::MAX.ln() {
+                    self.warn(
+                        format!(
+                            "`exp` may overflow to infinity: the argument ranges over {}",
+                            f(0)
+                        ),
+                        &args[0],
+                    );
+                }
+                f(0).monotone(f32::exp).min(Interval::point(f32::MAX))
+            }
+            Builtin::Ln => {
+                if f(0).lo < 0.0 {
+                    self.warn(
+                        format!(
+                            "`ln` of a negative number is NaN: the argument ranges over {}",
+                            f(0)
+                        ),
+                        &args[0],
+                    );
+                } else if f(0).lo == 0.0 {
+                    self.warn(
+                        format!(
+                            "`ln` of zero is negative infinity: the argument ranges over {}",
+                            f(0)
+                        ),
+                        &args[0],
+                    );
+                }
+                f(0).at_least(f32::MIN_POSITIVE).monotone(f32::ln)
+            }
+            Builtin::Sin | Builtin::Cos => Interval::new(-1.0, 1.0),
+            Builtin::Floor => f(0).monotone(f32::floor),
+            Builtin::Ceil => f(0).monotone(f32::ceil),
+            Builtin::Fract => Interval::new(0.0, 1.0),
+            Builtin::Sign => f(0).monotone(|x| if x == 0.0 { 0.0 } else { x.signum() }),
+            Builtin::Powf => self.powf(f(0), f(1), &args[0]),
+            Builtin::Copysign => {
+                let magnitude = f(0).abs();
+                if f(1).lo >= 0.0 {
+                    magnitude
+                } else if f(1).hi < 0.0 {
+                    magnitude.neg()
+                } else {
+                    magnitude.hull(magnitude.neg())
+                }
+            }
+            Builtin::Min => f(0).min(f(1)),
+            Builtin::Max => f(0).max(f(1)),
+            Builtin::Clamp => f(0).max(f(1)).min(f(2)),
+            Builtin::Mix => f(0).add(f(1).sub(f(0)).mul(f(2))),
+            Builtin::Smoothstep => {
+                let width = f(1).sub(f(0));
+                if width.contains(0.0) {
+                    self.warn(
+                        format!(
+                            "`smoothstep` divides by zero if both edges are equal: \
+                             their difference ranges over {width}"
+                        ),
+                        call,
+                    );
+                }
+                Interval::new(0.0, 1.0)
+            }
+            Builtin::Rescale => {
+                let width = f(2).sub(f(1));
+                if width.contains(0.0) {
+                    self.warn(
+                        format!(
+                            "`rescale` divides by zero if the source range is empty: \
+                             its width ranges over {width}"
+                        ),
+                        call,
+                    );
+                    Interval::REALS
+                } else {
+                    f(0).sub(f(1)).mul(f(4).sub(f(3))).div(width).add(f(3))
+                }
+            }
+            Builtin::Select => f(1).hull(f(2)),
+            Builtin::Vec3 => return Range::Vec3([f(0), f(1), f(2)]),
+            Builtin::Length => length(v(0)),
+            Builtin::Normalize => {
+                let length = length(v(0));
+                if length.contains(0.0) {
+                    self.warn(
+                        format!(
+                            "normalizing a zero vector is NaN: the length ranges over {length}"
+                        ),
+                        &args[0],
+                    );
+                }
+                return Range::Vec3([Interval::new(-1.0, 1.0); 3]);
+            }
+            Builtin::Dot => {
+                let (a, b) = (v(0), v(1));
+                a[0].mul(b[0]).add(a[1].mul(b[1])).add(a[2].mul(b[2]))
+            }
+        })
+    }
+
+    fn powf(&mut self, base: Interval, exponent: Interval, node: &Node) -> Interval {
+        let integer = exponent.is_point() && exponent.lo.fract() == 0.0;
+        if integer {
+            if base.contains(0.0) && exponent.lo < 0.0 {
+                self.warn(
+                    format!(
+                        "`powf` of zero with a negative exponent is infinite: the base ranges over {base}"
+                    ),
+                    node,
+                );
+            }
+            // Monotone on either side of zero.
+            let mut candidates = vec![base.lo.powf(exponent.lo), base.hi.powf(exponent.lo)];
+            if base.contains(0.0) && exponent.lo >= 0.0 {
+                candidates.push(0.0f32.powf(exponent.lo));
+            }
+            // Unbounded towards zero, with the sign of the base for odd exponents.
+            if base.contains(0.0) && exponent.lo < 0.0 {
+                candidates.push(f32::INFINITY);
+                if base.lo < 0.0 && exponent.lo % 2.0 != 0.0 {
+                    candidates.push(f32::NEG_INFINITY);
+                }
+            }
+            return Interval::hull_of(candidates.into_iter().filter(|x| !x.is_nan()));
+        }
+
+        if base.lo < 0.0 {
+            self.warn(
+                format!(
+                    "`powf` of a negative number with a non-integer exponent is NaN: \
+                     the base ranges over {base}"
+                ),
+                node,
+            );
+            if base.hi < 0.0 {
+                return Interval::REALS;
+            }
+        }
+        let base = base.at_least(0.0);
+        if base.contains(0.0) && exponent.lo < 0.0 {
+            self.warn(
+                format!(
+                    "`powf` of zero with a negative exponent is infinite: the base ranges over {base}"
+                ),
+                node,
+            );
+        }
+        // For a fixed exponent, the extremes are at the ends of the base range, and
+        // for a fixed base, the power is monotone in the exponent.
+        Interval::hull_of(
+            [
+                base.lo.powf(exponent.lo),
+                base.lo.powf(exponent.hi),
+                base.hi.powf(exponent.lo),
+                base.hi.powf(exponent.hi),
+            ]
+            .into_iter()
+            .filter(|x| !x.is_nan()),
+        )
+    }
+}
+
+fn length(v: [Interval; 3]) -> Interval {
+    v[0].square()
+        .add(v[1].square())
+        .add(v[2].square())
+        .monotone(f32::sqrt)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::terrain_lang::compile;
+
+    fn warnings(source: &str) -> Vec<String> {
+        analyze(&compile(source).unwrap())
+            .warnings
+            .iter()
+            .map(|warning| {
+                let (line, column) = warning.location(source);
+                format!("{line}:{column}: {}", warning.message)
+            })
+            .collect()
+    }
+
+    fn range(source: &str, name: &str) -> Range {
+        let module = compile(source).unwrap();
+        analyze(&module).ranges[module.field(name).unwrap()]
+    }
+
+    #[test]
+    fn readme_is_clean() {
+        let source = "height: field<float, 2>
+|> {
+    n = fbm(@x, @y);
+    n = n.abs().powf(1.5).copysign(n)
+    n *= 50
+    n
+}
+
+blurred_normal: field<float, 2>
+|> normal
+|> {
+    $x
+}
+|> blur
+";
+        assert_eq!(warnings(source), Vec::<String>::new());
+        let height = range(source, "height").float();
+        assert_eq!(height.hi, -height.lo);
+        assert!((height.hi - 2.0f32.powf(1.5) * 50.0).abs() < 1e-3);
+        assert_eq!(
+            range(source, "blurred_normal"),
+            Range::Float(Interval::new(-1.0, 1.0))
+        );
+    }
+
+    #[test]
+    fn powf_of_negative_base() {
+        assert_eq!(
+            warnings("h: field<float, 2> |> { fbm(@x, @y).powf(1.5) }"),
+            vec![
+                "1:25: `powf` of a negative number with a non-integer exponent is NaN: \
+                 the base ranges over [-2, 2]"
+            ]
+        );
+        assert_eq!(
+            warnings("h: field<float, 2> |> { fbm(@x, @y).powf(3) }"),
+            Vec::<String>::new()
+        );
+        assert_eq!(
+            range("h: field<float, 2> |> { perlin(@x, @y).powf(2) }", "h"),
+            Range::Float(Interval::new(0.0, 1.0))
+        );
+        assert_eq!(
+            range("h: field<float, 2> |> { perlin(@x, @y).powf(-2) }", "h"),
+            Range::Float(Interval::new(1.0, f32::INFINITY))
+        );
+        assert_eq!(
+            range("h: field<float, 2> |> { perlin(@x, @y).powf(-1) }", "h"),
+            Range::Float(Interval::REALS)
+        );
+        assert_eq!(
+            range(
+                "h: field<float, 2> |> { perlin(@x, @y).min(0).powf(-1) }",
+                "h"
+            ),
+            Range::Float(Interval::REALS)
+        );
+    }
+
+    #[test]
+    fn division_by_zero() {
+        assert_eq!(
+            warnings(
+                "h: field<float, 2>
+                |> { a = perlin(@x, @y); 1 / a + 1 / (a + 2) }"
+            ),
+            vec!["2:46: division by zero: the divisor ranges over [-1, 1]"]
+        );
+        assert_eq!(
+            warnings("h: field<float, 2> |> { @x % worley(@x, @y) }"),
+            vec![
+                "1:30: remainder of a division by zero is NaN: \
+                 the divisor ranges over [0, 1.4142135]"
+            ]
+        );
+        assert_eq!(
+            warnings("h: field<vec3, 2> |> { vec3(@x, @y, 1) / @z }"),
+            vec!["1:42: division by zero: the divisor ranges over [-inf, inf]"]
+        );
+    }
+
+    #[test]
+    fn domain_errors() {
+        assert_eq!(
+            warnings(
+                "h: field<float, 2>
+                |> { sqrt(perlin(@x, @y)) + sqrt(worley(@x, @y)) }
+                |> { ln($) + ln($ + 1) + exp(@x) + exp($) }
+                |> { vec3($, 0, 0).normalize().x + vec3($, 1, 0).normalize().x }"
+            ),
+            vec![
+                "2:27: `sqrt` of a negative number is NaN: the argument ranges over [-1, 1]",
+                "3:25: `ln` of zero is negative infinity: the argument ranges over [0, 2.189207]",
+                "3:46: `exp` may overflow to infinity: the argument ranges over [-inf, inf]",
+                "4:22: normalizing a zero vector is NaN: the length ranges over [0, inf]",
+            ]
+        );
+    }
+
+    #[test]
+    fn valid_part_is_propagated() {
+        assert_eq!(
+            warnings("h: field<float, 2> |> { sqrt(sqrt(perlin(@x, @y)) - 1) }"),
+            vec![
+                "1:35: `sqrt` of a negative number is NaN: the argument ranges over [-1, 1]",
+                "1:30: `sqrt` of a negative number is NaN: the argument ranges over [-1, 0]",
+            ]
+        );
+    }
+
+    #[test]
+    fn ranges_flow_between_fields() {
+        let source = "a: field<float, 2> |> { worley(@x, @y) + 1 }
+b: field<float, 3> |> { 1 / a + smoothstep(0, a, @z) + smoothstep(1, a, @z) }";
+        assert_eq!(
+            warnings(source),
+            vec![
+                "2:56: `smoothstep` divides by zero if both edges are equal: \
+                 their difference ranges over [0, 1.4142137]"
+            ]
+        );
+    }
+}
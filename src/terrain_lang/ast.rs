@@ -0,0 +1,202 @@
+use super::diagnostic::Span;
+
+/// A terrain program is a sequence of field declarations.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    pub declarations: Vec<Declaration>,
+}
+
+impl Program {
+    pub fn declaration(&self, name: &str) -> Option<&Declaration> {
+        self.declarations.iter().find(|d| d.name.name == name)
+    }
+}
+
+/// `name: field<float, 2> |> stage |> stage ...`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Declaration {
+    pub name: Ident,
+    pub ty: Type,
+    pub stages: Vec<Stage>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Ident {
+    pub name: String,
+    pub span: Span,
+}
+
+/// `field<element, dimension>`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Type {
+    pub element: Element,
+    pub dimension: usize,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Element {
+    Float,
+    Bool,
+    Vec3,
+}
+
+impl Element {
+    pub fn name(self) -> &'static str {
+        match self {
+            Element::Float => "float",
+            Element::Bool => "bool",
+            Element::Vec3 => "vec3",
+        }
+    }
+}
+
+/// One stage of a pipeline, separated by `|>`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Stage {
+    /// An expression block evaluated once per voxel.
+    Block(Block),
+    /// A built-in operation over the whole field, e.g. `normal` or `blur`.
+    Named(Ident),
+}
+
+impl Stage {
+    pub fn span(&self) -> Span {
+        match self {
+            Stage::Block(block) => block.span,
+            Stage::Named(ident) => ident.span,
+        }
+    }
+}
+
+/// `{ statements; value }`
+#[derive(Debug, Clone, PartialEq)]
+pub struct Block {
+    pub statements: Vec<Statement>,
+    pub value: Expr,
+    pub span: Span,
+}
+
+/// `target = value` or a compound assignment such as `target *= value`,
+/// in which case `operator` holds the binary operator.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statement {
+    pub target: Ident,
+    pub operator: Option<BinaryOp>,
+    pub value: Expr,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expr {
+    pub kind: ExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ExprKind {
+    Number(f32),
+    Bool(bool),
+    /// A local variable or another declaration.
+    Variable(Ident),
+    /// `@x`, `@y` or `@z`
+    Coordinate(Axis),
+    /// `$`, the value of the previous pipeline stage.
+    Input,
+    /// `v.x` or `$x`
+    Component(Box<Expr>, Axis),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    /// `f(x, y)`. Method calls `x.f(y)` are desugared into `f(x, y)`.
+    Call(Ident, Vec<Expr>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Axis {
+    X,
+    Y,
+    Z,
+}
+
+impl Axis {
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "x" => Some(Axis::X),
+            "y" => Some(Axis::Y),
+            "z" => Some(Axis::Z),
+            _ => None,
+        }
+    }
+
+    pub fn index(self) -> usize {
+        match self {
+            Axis::X => 0,
+            Axis::Y => 1,
+            Axis::Z => 2,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Axis::X => "x",
+            Axis::Y => "y",
+            Axis::Z => "z",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnaryOp {
+    Neg,
+    Not,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Rem,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+    And,
+    Or,
+}
+
+impl BinaryOp {
+    pub fn symbol(self) -> &'static str {
+        match self {
+            BinaryOp::Add => "+",
+            BinaryOp::Sub => "-",
+            BinaryOp::Mul => "*",
+            BinaryOp::Div => "/",
+            BinaryOp::Rem => "%",
+            BinaryOp::Lt => "<",
+            BinaryOp::Le => "<=",
+            BinaryOp::Gt => ">",
+            BinaryOp::Ge => ">=",
+            BinaryOp::Eq => "==",
+            BinaryOp::Ne => "!=",
+            BinaryOp::And => "&&",
+            BinaryOp::Or => "||",
+        }
+    }
+
+    /// Binding strength, higher binds tighter.
+    pub fn precedence(self) -> u8 {
+        match self {
+            BinaryOp::Or => 1,
+            BinaryOp::And => 2,
+            BinaryOp::Eq | BinaryOp::Ne => 3,
+            BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge => 4,
+            BinaryOp::Add | BinaryOp::Sub => 5,
+            BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem => 6,
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use cgmath::{vec2, vec3, InnerSpace};
+
+use super::{ast::Element, ir::Value};
+use crate::util;
+
+/// Functions callable from terrain programs, either as `f(x, y)` or `x.f(y)`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Builtin {
+    Perlin,
+    Fbm,
+    Worley,
+    Abs,
+    Sqrt,
+    Exp,
+    Ln,
+    Sin,
+    Cos,
+    Floor,
+    Ceil,
+    Fract,
+    Sign,
+    Powf,
+    Copysign,
+    Min,
+    Max,
+    Clamp,
+    Mix,
+    Smoothstep,
+    Rescale,
+    Select,
+    Vec3,
+    Length,
+    Normalize,
+    Dot,
+}
+
+use Element::{Bool, Float};
+
+impl Builtin {
+    pub const ALL: [Builtin; 26] = [
+        Builtin::Perlin,
+        Builtin::Fbm,
+        Builtin::Worley,
+        Builtin::Abs,
+        Builtin::Sqrt,
+        Builtin::Exp,
+        Builtin::Ln,
+        Builtin::Sin,
+        Builtin::Cos,
+        Builtin::Floor,
+        Builtin::Ceil,
+        Builtin::Fract,
+        Builtin::Sign,
+        Builtin::Powf,
+        Builtin::Copysign,
+        Builtin::Min,
+        Builtin::Max,
+        Builtin::Clamp,
+        Builtin::Mix,
+        Builtin::Smoothstep,
+        Builtin::Rescale,
+        Builtin::Select,
+        Builtin::Vec3,
+        Builtin::Length,
+        Builtin::Normalize,
+        Builtin::Dot,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|b| b.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Builtin::Perlin => "perlin",
+            Builtin::Fbm => "fbm",
+            Builtin::Worley => "worley",
+            Builtin::Abs => "abs",
+            Builtin::Sqrt => "sqrt",
+            Builtin::Exp => "exp",
+            Builtin::Ln => "ln",
+            Builtin::Sin => "sin",
+            Builtin::Cos => "cos",
+            Builtin::Floor => "floor",
+            Builtin::Ceil => "ceil",
+            Builtin::Fract => "fract",
+            Builtin::Sign => "sign",
+            Builtin::Powf => "powf",
+            Builtin::Copysign => "copysign",
+            Builtin::Min => "min",
+            Builtin::Max => "max",
+            Builtin::Clamp => "clamp",
+            Builtin::Mix => "mix",
+            Builtin::Smoothstep => "smoothstep",
+            Builtin::Rescale => "rescale",
+            Builtin::Select => "select",
+            Builtin::Vec3 => "vec3",
+            Builtin::Length => "length",
+            Builtin::Normalize => "normalize",
+            Builtin::Dot => "dot",
+        }
+    }
+
+    /// Parameter types and return type.
+    pub fn signature(self) -> (&'static [Element], Element) {
+        match self {
+            Builtin::Perlin | Builtin::Fbm | Builtin::Worley => (&[Float, Float], Float),
+            Builtin::Abs
+            | Builtin::Sqrt
+            | Builtin::Exp
+            | Builtin::Ln
+            | Builtin::Sin
+            | Builtin::Cos
+            | Builtin::Floor
+            | Builtin::Ceil
+            | Builtin::Fract
+            | Builtin::Sign => (&[Float], Float),
+            Builtin::Powf | Builtin::Copysign | Builtin::Min | Builtin::Max => {
+                (&[Float, Float], Float)
+            }
+            Builtin::Clamp | Builtin::Mix | Builtin::Smoothstep => (&[Float, Float, Float], Float),
+            Builtin::Rescale => (&[Float, Float, Float, Float, Float], Float),
+            Builtin::Select => (&[Bool, Float, Float], Float),
+            Builtin::Vec3 => (&[Float, Float, Float], Element::Vec3),
+            Builtin::Length => (&[Element::Vec3], Float),
+            Builtin::Normalize => (&[Element::Vec3], Element::Vec3),
+            Builtin::Dot => (&[Element::Vec3, Element::Vec3], Float),
+        }
+    }
+
+    /// Apply the function to type-checked arguments.
+    pub fn evaluate(self, args: &[Value]) -> Value {
+        let f = |i: usize| args[i].float();
+        let v = |i: usize| args[i].vec3();
+        Value::Float(match self {
+            Builtin::Perlin => util::perlin(vec2(f(0), f(1))),
+            Builtin::Fbm => util::fbm(vec2(f(0), f(1)), util::perlin),
+            Builtin::Worley => util::worley(vec2(f(0), f(1))),
+            Builtin::Abs => f(0).abs(),
+            Builtin::Sqrt => f(0).sqrt(),
+            Builtin::Exp => f(0).exp(),
+            Builtin::Ln => f(0).ln(),
+            Builtin::Sin => f(0).sin(),
+            Builtin::Cos => f(0).cos(),
+            Builtin::Floor => f(0).floor(),
+            Builtin::Ceil => f(0).ceil(),
+            Builtin::Fract => f(0) - f(0).floor(),
+            // Unlike `f32::signum`, zero has no sign.
+            Builtin::Sign => {
+                if f(0) == 0.0 {
+                    0.0
+                } else {
+                    f(0).signum()
+                }
+            }
+            Builtin::Powf => f(0).powf(f(1)),
+            Builtin::Copysign => f(0).copysign(f(1)),
+            Builtin::Min => f(0).min(f(1)),
+            Builtin::Max => f(0).max(f(1)),
+            Builtin::Clamp => f(0).max(f(1)).min(f(2)),
+            Builtin::Mix => f(0) + (f(1) - f(0)) * f(2),
+            Builtin::Smoothstep => {
+                let t = ((f(2) - f(0)) / (f(1) - f(0))).clamp(0.0, 1.0);
+                t * t * (3.0 - 2.0 * t)
+            }
+            Builtin::Rescale => util::rescale(f(0), f(1)..f(2), f(3)..f(4)),
+            Builtin::Select => {
+                if args[0].bool() {
+                    f(1)
+                } else {
+                    f(2)
+                }
+            }
+            Builtin::Vec3 => return Value::Vec3(vec3(f(0), f(1), f(2))),
+            Builtin::Length => v(0).magnitude(),
+            Builtin::Normalize => return Value::Vec3(v(0).normalize()),
+            Builtin::Dot => v(0).dot(v(1)),
+        })
+    }
+}
@@ -0,0 +1,207 @@
+// Terrain language built-in functions, see `Builtin::evaluate`.
+// Every built-in is wrapped so that arguments are evaluated exactly once.
+
+// Noise, matching its CPU counterparts in `util`.
+
+fn knuth(n: u32) -> u32 {
+    let m = n * 2654435769u;
+    return (m >> 17u) | (m << 15u);
+}
+
+/// Hash two keys, like `util::hash` does on the CPU.
+fn hash2(a: u32, b: u32) -> u32 {
+    let h = knuth(knuth(a) ^ b);
+    var s = vec4(h);
+    for (var i = 0u; i < 3u; i = i + 1u) {
+        let t = s.y << 9u;
+        s.z = s.z ^ s.x;
+        s.w = s.w ^ s.y;
+        s.y = s.y ^ s.z;
+        s.x = s.x ^ s.w;
+        s.z = s.z ^ t;
+        s.w = (s.w << 11u) | (s.w >> 21u);
+    }
+    return s.x + s.w;
+}
+
+/// Pseudo-random number in [0, 1), see `util::random`.
+fn random(p: vec2<f32>) -> f32 {
+    let k = hash2(bitcast<u32>(p.x), bitcast<u32>(p.y));
+    return bitcast<f32>(0x3F800000u | (k & 0x007FFFFFu)) - 1.0;
+}
+
+fn perlin_interpolate(a: f32, b: f32, t: f32) -> f32 {
+    return ((t * (t * 6.0 - 15.0) + 10.0) * t * t * t) * (b - a) + a;
+}
+
+/// Perlin noise in (-1, 1), see `util::perlin`.
+fn perlin(p: vec2<f32>) -> f32 {
+    let tau = 6.283185307179586;
+
+    let p0 = floor(p);
+    let p1 = p0 + vec2(1.0, 0.0);
+    let p2 = p0 + vec2(0.0, 1.0);
+    let p3 = p0 + vec2(1.0, 1.0);
+
+    let d0 = tau * random(p0);
+    let d1 = tau * random(p1);
+    let d2 = tau * random(p2);
+    let d3 = tau * random(p3);
+
+    let g0 = vec2(cos(d0), sin(d0));
+    let g1 = vec2(cos(d1), sin(d1));
+    let g2 = vec2(cos(d2), sin(d2));
+    let g3 = vec2(cos(d3), sin(d3));
+
+    let delta = p - p0;
+
+    let i = dot(p - p0, g0);
+    let j = dot(p - p1, g1);
+    let k = dot(p - p2, g2);
+    let l = dot(p - p3, g3);
+
+    let u = perlin_interpolate(i, j, delta.x);
+    let v = perlin_interpolate(k, l, delta.x);
+
+    return perlin_interpolate(u, v, delta.y);
+}
+
+/// Distance to the closest cell seed, see `util::worley`.
+fn worley(p: vec2<f32>) -> f32 {
+    let h = floor(p);
+    var d = 1e30;
+    for (var y = -1; y <= 1; y = y + 1) {
+        for (var x = -1; x <= 1; x = x + 1) {
+            let c = h + vec2(f32(x), f32(y));
+            let seed = c + vec2(random(c), random(c));
+            d = min(d, distance(seed, p));
+        }
+    }
+    return d;
+}
+
+/// Fractal Brownian motion over Perlin noise, see `util::fbm`.
+fn fbm(p: vec2<f32>) -> f32 {
+    var amplitude = 1.0;
+    var frequency = 1.0;
+    var sum = 0.0;
+    for (var i = 0; i < 8; i = i + 1) {
+        sum = sum + amplitude * perlin(p * frequency);
+        amplitude = amplitude * 0.5;
+        frequency = frequency * 2.0;
+    }
+    return sum;
+}
+
+fn builtin_perlin(x: f32, y: f32) -> f32 {
+    return perlin(vec2(x, y));
+}
+
+fn builtin_fbm(x: f32, y: f32) -> f32 {
+    return fbm(vec2(x, y));
+}
+
+fn builtin_worley(x: f32, y: f32) -> f32 {
+    return worley(vec2(x, y));
+}
+
+fn builtin_abs(x: f32) -> f32 {
+    return abs(x);
+}
+
+fn builtin_sqrt(x: f32) -> f32 {
+    return sqrt(x);
+}
+
+fn builtin_exp(x: f32) -> f32 {
+    return exp(x);
+}
+
+fn builtin_ln(x: f32) -> f32 {
+    return log(x);
+}
+
+fn builtin_sin(x: f32) -> f32 {
+    return sin(x);
+}
+
+fn builtin_cos(x: f32) -> f32 {
+    return cos(x);
+}
+
+fn builtin_floor(x: f32) -> f32 {
+    return floor(x);
+}
+
+fn builtin_ceil(x: f32) -> f32 {
+    return ceil(x);
+}
+
+fn builtin_fract(x: f32) -> f32 {
+    return x - floor(x);
+}
+
+fn builtin_sign(x: f32) -> f32 {
+    return sign(x);
+}
+
+fn builtin_powf(x: f32, y: f32) -> f32 {
+    // Like `f32::powf`, negative bases only have integer powers.
+    if x < 0.0 {
+        if y != floor(y) {
+            return bitcast<f32>(0x7FC00000u);
+        }
+        let p = pow(-x, y);
+        return select(p, -p, y % 2.0 != 0.0);
+    }
+    return pow(x, y);
+}
+
+fn builtin_copysign(x: f32, y: f32) -> f32 {
+    return bitcast<f32>((bitcast<u32>(x) & 0x7FFFFFFFu) | (bitcast<u32>(y) & 0x80000000u));
+}
+
+fn builtin_min(x: f32, y: f32) -> f32 {
+    return min(x, y);
+}
+
+fn builtin_max(x: f32, y: f32) -> f32 {
+    return max(x, y);
+}
+
+fn builtin_clamp(x: f32, lo: f32, hi: f32) -> f32 {
+    return min(max(x, lo), hi);
+}
+
+fn builtin_mix(a: f32, b: f32, t: f32) -> f32 {
+    return a + (b - a) * t;
+}
+
+fn builtin_smoothstep(e0: f32, e1: f32, x: f32) -> f32 {
+    let t = clamp((x - e0) / (e1 - e0), 0.0, 1.0);
+    return t * t * (3.0 - 2.0 * t);
+}
+
+fn builtin_rescale(x: f32, from_start: f32, from_end: f32, to_start: f32, to_end: f32) -> f32 {
+    return (x - from_start) * (to_end - to_start) / (from_end - from_start) + to_start;
+}
+
+fn builtin_select(condition: bool, a: f32, b: f32) -> f32 {
+    return select(b, a, condition);
+}
+
+fn builtin_vec3(x: f32, y: f32, z: f32) -> vec3<f32> {
+    return vec3(x, y, z);
+}
+
+fn builtin_length(v: vec3<f32>) -> f32 {
+    return length(v);
+}
+
+fn builtin_normalize(v: vec3<f32>) -> vec3<f32> {
+    return normalize(v);
+}
+
+fn builtin_dot(a: vec3<f32>, b: vec3<f32>) -> f32 {
+    return dot(a, b);
+}
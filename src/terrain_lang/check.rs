@@ -0,0 +1,447 @@
+use super::{
+    ast::{self, BinaryOp, Element, ExprKind, Program, UnaryOp},
+    builtin::Builtin,
+    diagnostic::{Diagnostic, Span},
+    ir::{Assignment, FieldDef, Kernel, Module, Node, NodeKind, Stage, Value},
+};
+
+/// Resolve names and check types, lowering the program into a [`Module`].
+pub fn check(program: &Program) -> Result<Module, Diagnostic> {
+    let mut fields: Vec<FieldDef> = Vec::new();
+
+    for declaration in &program.declarations {
+        let dimension = declaration.ty.dimension;
+        let mut source = None;
+        let mut input: Option<Element> = None;
+        let mut stages = Vec::new();
+
+        for (i, stage) in declaration.stages.iter().enumerate() {
+            match stage {
+                ast::Stage::Block(block) => {
+                    let kernel = Checker {
+                        fields: &fields,
+                        dimension,
+                        input,
+                        locals: Vec::new(),
+                        slots: 0,
+                    }
+                    .kernel(block)?;
+                    input = Some(kernel.value.ty);
+                    stages.push(Stage::Kernel(kernel));
+                }
+                ast::Stage::Named(name) => {
+                    if i == 0 {
+                        // A pipeline starting with a named stage operates on the previous declaration.
+                        let Some(previous) = fields.last() else {
+                            return Err(Diagnostic::new(
+                                format!(
+                                    "`{}` has no input, since there is no declaration before `{}`",
+                                    name.name, declaration.name.name
+                                ),
+                                name.span,
+                            ));
+                        };
+                        if previous.dimension != dimension {
+                            return Err(Diagnostic::new(
+                                format!(
+                                    "`{}` operates on `{}`, which has dimension {} instead of {dimension}",
+                                    name.name, previous.name, previous.dimension
+                                ),
+                                name.span,
+                            ));
+                        }
+                        source = Some(fields.len() - 1);
+                        input = Some(previous.element);
+                    }
+
+                    let (stage, output) = match name.name.as_str() {
+                        "normal" => (Stage::Normal, Element::Vec3),
+                        "blur" => (Stage::Blur, Element::Float),
+                        _ => {
+                            return Err(Diagnostic::new(
+                                format!(
+                                    "unknown stage `{}`, expected `normal`, `blur` or a block",
+                                    name.name
+                                ),
+                                name.span,
+                            ))
+                        }
+                    };
+                    if input != Some(Element::Float) || dimension != 2 {
+                        return Err(Diagnostic::new(
+                            format!(
+                                "`{}` requires a field<float, 2> input, found field<{}, {dimension}>",
+                                name.name,
+                                input.map(Element::name).unwrap_or("?"),
+                            ),
+                            name.span,
+                        ));
+                    }
+                    input = Some(output);
+                    stages.push(stage);
+                }
+            }
+        }
+
+        let output = input.unwrap();
+        if output != declaration.ty.element {
+            return Err(Diagnostic::new(
+                format!(
+                    "`{}` is declared as field<{}, {dimension}>, but its pipeline produces {} values",
+                    declaration.name.name,
+                    declaration.ty.element.name(),
+                    output.name()
+                ),
+                declaration.ty.span,
+            ));
+        }
+
+        fields.push(FieldDef {
+            name: declaration.name.name.clone(),
+            element: output,
+            dimension,
+            source,
+            stages,
+            span: declaration.span,
+        });
+    }
+
+    Ok(Module { fields })
+}
+
+struct Checker<'a> {
+    /// Fields declared so far.
+    fields: &'a [FieldDef],
+    dimension: usize,
+    input: Option<Element>,
+    /// Visible local variables with their slot and type.
+    locals: Vec<(String, usize, Element)>,
+    slots: usize,
+}
+
+impl Checker<'_> {
+    fn kernel(mut self, block: &ast::Block) -> Result<Kernel, Diagnostic> {
+        let mut statements = Vec::new();
+        for statement in &block.statements {
+            let mut value = self.expr(&statement.value)?;
+
+            let existing = self
+                .locals
+                .iter()
+                .rev()
+                .find(|(name, _, _)| *name == statement.target.name)
+                .cloned();
+
+            if let Some(op) = statement.operator {
+                // `a op= b` is `a = a op b`
+                let Some((_, slot, ty)) = existing.clone() else {
+                    return Err(Diagnostic::new(
+                        format!("unknown variable `{}`", statement.target.name),
+                        statement.target.span,
+                    ));
+                };
+                let target = Node {
+                    kind: NodeKind::Local(slot),
+                    ty,
+                    span: statement.target.span,
+                };
+                value = self.binary(op, target, value, statement.span)?;
+            }
+
+            let local = match existing {
+                Some((_, slot, ty)) if ty == value.ty => slot,
+                Some((_, _, ty)) => {
+                    return Err(Diagnostic::new(
+                        format!(
+                            "`{}` has type {}, but is assigned a {} value",
+                            statement.target.name,
+                            ty.name(),
+                            value.ty.name()
+                        ),
+                        statement.span,
+                    ))
+                }
+                None => {
+                    let slot = self.slots;
+                    self.slots += 1;
+                    self.locals
+                        .push((statement.target.name.clone(), slot, value.ty));
+                    slot
+                }
+            };
+
+            statements.push(Assignment { local, value });
+        }
+
+        let value = self.expr(&block.value)?;
+
+        Ok(Kernel {
+            input: self.input,
+            locals: self.slots,
+            statements,
+            value,
+        })
+    }
+
+    fn expr(&self, expr: &ast::Expr) -> Result<Node, Diagnostic> {
+        let span = expr.span;
+        let node = |kind, ty| Node { kind, ty, span };
+
+        Ok(match &expr.kind {
+            ExprKind::Number(n) => node(NodeKind::Constant(Value::Float(*n)), Element::Float),
+            ExprKind::Bool(b) => node(NodeKind::Constant(Value::Bool(*b)), Element::Bool),
+            ExprKind::Variable(ident) => {
+                if let Some((_, slot, ty)) = self
+                    .locals
+                    .iter()
+                    .rev()
+                    .find(|(name, _, _)| *name == ident.name)
+                {
+                    node(NodeKind::Local(*slot), *ty)
+                } else if let Some(index) = self.fields.iter().position(|f| f.name == ident.name) {
+                    let field = &self.fields[index];
+                    if field.dimension > self.dimension {
+                        return Err(Diagnostic::new(
+                            format!(
+                                "`{}` is a {}D field and can not be read from a {}D field",
+                                ident.name, field.dimension, self.dimension
+                            ),
+                            span,
+                        ));
+                    }
+                    node(NodeKind::Field(index), field.element)
+                } else {
+                    return Err(Diagnostic::new(
+                        format!("unknown variable `{}`", ident.name),
+                        span,
+                    ));
+                }
+            }
+            ExprKind::Coordinate(axis) => node(NodeKind::Coordinate(*axis), Element::Float),
+            ExprKind::Input => {
+                let Some(input) = self.input else {
+                    return Err(Diagnostic::new(
+                        "`$` has no value in the first stage of a pipeline",
+                        span,
+                    ));
+                };
+                node(NodeKind::Input, input)
+            }
+            ExprKind::Component(inner, axis) => {
+                let inner = self.expr(inner)?;
+                expect(&inner, Element::Vec3)?;
+                node(NodeKind::Component(Box::new(inner), *axis), Element::Float)
+            }
+            ExprKind::Unary(op, operand) => {
+                let operand = self.expr(operand)?;
+                let ty = match (op, operand.ty) {
+                    (UnaryOp::Neg, Element::Float | Element::Vec3) => operand.ty,
+                    (UnaryOp::Not, Element::Bool) => Element::Bool,
+                    (UnaryOp::Neg, ty) | (UnaryOp::Not, ty) => {
+                        return Err(Diagnostic::new(
+                            format!("can not apply `{}` to {}", unary_symbol(*op), ty.name()),
+                            span,
+                        ))
+                    }
+                };
+                node(NodeKind::Unary(*op, Box::new(operand)), ty)
+            }
+            ExprKind::Binary(op, lhs, rhs) => {
+                let lhs = self.expr(lhs)?;
+                let rhs = self.expr(rhs)?;
+                self.binary(*op, lhs, rhs, span)?
+            }
+            ExprKind::Call(name, args) => {
+                let Some(builtin) = Builtin::from_name(&name.name) else {
+                    return Err(Diagnostic::new(
+                        format!("unknown function `{}`", name.name),
+                        name.span,
+                    ));
+                };
+                let (parameters, ty) = builtin.signature();
+                if args.len() != parameters.len() {
+                    return Err(Diagnostic::new(
+                        format!(
+                            "`{}` takes {} arguments, but {} were given",
+                            name.name,
+                            parameters.len(),
+                            args.len()
+                        ),
+                        span,
+                    ));
+                }
+                let args = args
+                    .iter()
+                    .zip(parameters)
+                    .map(|(arg, &parameter)| {
+                        let arg = self.expr(arg)?;
+                        expect(&arg, parameter)?;
+                        Ok(arg)
+                    })
+                    .collect::<Result<_, Diagnostic>>()?;
+                node(NodeKind::Call(builtin, args), ty)
+            }
+        })
+    }
+
+    fn binary(&self, op: BinaryOp, lhs: Node, rhs: Node, span: Span) -> Result<Node, Diagnostic> {
+        use Element::*;
+        let ty = match (op, lhs.ty, rhs.ty) {
+            (BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div, Vec3, Vec3) => Vec3,
+            (BinaryOp::Mul | BinaryOp::Div, Vec3, Float) => Vec3,
+            (BinaryOp::Mul, Float, Vec3) => Vec3,
+            (
+                BinaryOp::Add | BinaryOp::Sub | BinaryOp::Mul | BinaryOp::Div | BinaryOp::Rem,
+                Float,
+                Float,
+            ) => Float,
+            (BinaryOp::Lt | BinaryOp::Le | BinaryOp::Gt | BinaryOp::Ge, Float, Float) => Bool,
+            (BinaryOp::Eq | BinaryOp::Ne, Float, Float) => Bool,
+            (BinaryOp::Eq | BinaryOp::Ne | BinaryOp::And | BinaryOp::Or, Bool, Bool) => Bool,
+            (op, a, b) => {
+                return Err(Diagnostic::new(
+                    format!(
+                        "can not apply `{}` to {} and {}",
+                        op.symbol(),
+                        a.name(),
+                        b.name()
+                    ),
+                    span,
+                ))
+            }
+        };
+        Ok(Node {
+            kind: NodeKind::Binary(op, Box::new(lhs), Box::new(rhs)),
+            ty,
+            span,
+        })
+    }
+}
+
+fn expect(node: &Node, ty: Element) -> Result<(), Diagnostic> {
+    if node.ty == ty {
+        Ok(())
+    } else {
+        Err(Diagnostic::new(
+            format!("expected {}, found {}", ty.name(), node.ty.name()),
+            node.span,
+        ))
+    }
+}
+
+fn unary_symbol(op: UnaryOp) -> &'static str {
+    match op {
+        UnaryOp::Neg => "-",
+        UnaryOp::Not => "!",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::terrain_lang::compile;
+
+    fn error(source: &str) -> String {
+        let diagnostic = compile(source).unwrap_err();
+        let (line, column) = diagnostic.location(source);
+        format!("{line}:{column}: {}", diagnostic.message)
+    }
+
+    #[test]
+    fn readme_program_checks() {
+        let module = compile(
+            "height: field<float, 2>
+            |> {
+                n = fbm(@x, @y);
+                n = n.abs().powf(1.5).copysign(n)
+                n *= 50
+                n
+            }
+
+            blurred_normal: field<float, 2>
+            |> normal
+            |> {
+                $x
+            }
+            |> blur",
+        )
+        .unwrap();
+
+        assert_eq!(module.fields.len(), 2);
+        assert_eq!(module.fields[1].source, Some(0));
+        assert_eq!(module.field("blurred_normal"), Some(1));
+    }
+
+    #[test]
+    fn fields_refer_to_earlier_fields() {
+        let module = compile(
+            "height: field<float, 2> |> { @x }
+            density: field<float, 3> |> { height - @z }",
+        )
+        .unwrap();
+        assert_eq!(module.fields[1].dimension, 3);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            error("a: field<float, 2> |> { b }"),
+            "1:25: unknown variable `b`"
+        );
+        assert_eq!(
+            error("a: field<float, 2> |> { a }"),
+            "1:25: unknown variable `a`"
+        );
+        assert_eq!(
+            error("a: field<float, 3> |> { @z }\nb: field<float, 2> |> { a }"),
+            "2:25: `a` is a 3D field and can not be read from a 2D field"
+        );
+        assert_eq!(
+            error("a: field<float, 2> |> { $ }"),
+            "1:25: `$` has no value in the first stage of a pipeline"
+        );
+        assert_eq!(
+            error("a: field<float, 2> |> { 1 < 2 }"),
+            "1:4: `a` is declared as field<float, 2>, but its pipeline produces bool values"
+        );
+        assert_eq!(
+            error("a: field<float, 2> |> { true + 1 }"),
+            "1:25: can not apply `+` to bool and float"
+        );
+        assert_eq!(
+            error("a: field<float, 2> |> { 1.powf() }"),
+            "1:25: `powf` takes 2 arguments, but 1 were given"
+        );
+        assert_eq!(
+            error("a: field<float, 2> |> { foo(1) }"),
+            "1:25: unknown function `foo`"
+        );
+        assert_eq!(
+            error("a: field<float, 2> |> { abs(vec3(1, 2, 3)) }"),
+            "1:29: expected float, found vec3"
+        );
+        assert_eq!(
+            error("a: field<float, 2> |> { n = 1; n = true; n }"),
+            "1:32: `n` has type float, but is assigned a bool value"
+        );
+        assert_eq!(
+            error("a: field<float, 2> |> { n += 1; n }"),
+            "1:25: unknown variable `n`"
+        );
+        assert_eq!(
+            error("a: field<float, 2> |> { 1.x }"),
+            "1:25: expected vec3, found float"
+        );
+        assert_eq!(
+            error("a: field<float, 2> |> normal"),
+            "1:23: `normal` has no input, since there is no declaration before `a`"
+        );
+        assert_eq!(
+            error("a: field<float, 3> |> { 1 }\nb: field<float, 3> |> blur"),
+            "2:23: `blur` requires a field<float, 2> input, found field<float, 3>"
+        );
+        assert_eq!(
+            error("a: field<float, 2> |> { 1 } |> sharpen"),
+            "1:32: unknown stage `sharpen`, expected `normal`, `blur` or a block"
+        );
+    }
+}
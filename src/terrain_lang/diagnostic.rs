@@ -0,0 +1,109 @@
+use std::fmt::Display;
+
+/// A byte range into the program source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+
+    /// The smallest span covering both spans.
+    pub fn join(self, other: Span) -> Span {
+        Span {
+            start: self.start.min(other.start),
+            end: self.end.max(other.end),
+        }
+    }
+}
+
+/// An error or warning attached to a location in the program source.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub message: String,
+    pub span: Span,
+}
+
+impl Diagnostic {
+    pub fn new(message: impl Into<String>, span: Span) -> Self {
+        Self {
+            message: message.into(),
+            span,
+        }
+    }
+
+    /// One-based line and column of the start of the span.
+    /// Columns are counted in characters, not bytes.
+    pub fn location(&self, source: &str) -> (usize, usize) {
+        line_column(source, self.span.start)
+    }
+
+    /// Format the diagnostic together with the offending source line, e.g.:
+    ///
+    /// ```text
+    /// 3:9: expected expression, found `}`
+    ///     n = }
+    ///         ^
+    /// ```
+    pub fn render(&self, source: &str) -> String {
+        let (line, column) = self.location(source);
+        let text = source.lines().nth(line - 1).unwrap_or("");
+
+        // Underline the span, but never past the end of the line.
+        let line_length = text.chars().count();
+        let span_length = source
+            .get(self.span.start..self.span.end)
+            .map(|s| s.chars().count())
+            .unwrap_or(0);
+        let underline = span_length.min(line_length + 1 - column).max(1);
+
+        format!(
+            "{line}:{column}: {}\n{text}\n{}{}",
+            self.message,
+            " ".repeat(column - 1),
+            "^".repeat(underline)
+        )
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+/// Convert a byte offset into a one-based line and column.
+pub fn line_column(source: &str, offset: usize) -> (usize, usize) {
+    let offset = offset.min(source.len());
+    let before = &source[..offset];
+    let line = before.matches('\n').count() + 1;
+    let line_start = before.rfind('\n').map(|i| i + 1).unwrap_or(0);
+    let column = before[line_start..].chars().count() + 1;
+    (line, column)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn line_column_counts_from_one() {
+        let source = "a\nbc\n  d";
+        assert_eq!(line_column(source, 0), (1, 1));
+        assert_eq!(line_column(source, 2), (2, 1));
+        assert_eq!(line_column(source, 3), (2, 2));
+        assert_eq!(line_column(source, 7), (3, 3));
+        assert_eq!(line_column(source, 100), (3, 4));
+    }
+
+    #[test]
+    fn render_underlines_span() {
+        let source = "x\n  foo bar\n";
+        let diagnostic = Diagnostic::new("unknown", Span::new(4, 7));
+        assert_eq!(diagnostic.render(source), "2:3: unknown\n  foo bar\n  ^^^");
+    }
+}
@@ -0,0 +1,428 @@
+use std::fmt::Display;
+
+use cgmath::{vec3, Vector3};
+
+use super::{
+    ast::{BinaryOp, Element, UnaryOp},
+    ir::{Kernel, Module, Node, NodeKind, Stage, Value, BLUR_SIGMA},
+};
+use crate::{field::Field, world};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EvalError {
+    UnknownField(String),
+    WrongType {
+        name: String,
+        expected: String,
+        found: String,
+    },
+}
+
+impl Display for EvalError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvalError::UnknownField(name) => write!(f, "the program does not declare `{name}`"),
+            EvalError::WrongType {
+                name,
+                expected,
+                found,
+            } => write!(f, "`{name}` must be a {expected}, but is a {found}"),
+        }
+    }
+}
+
+/// Values of one field at every voxel of a chunk.
+/// Ordered like [`Field`], i.e. the last coordinate varies fastest.
+struct Grid {
+    dimension: usize,
+    values: Vec<Value>,
+}
+
+impl Module {
+    /// Evaluate a `field<float, D>` declaration for the chunk with the given key and LOD,
+    /// including its apron, see [`world::padded_position`].
+    pub fn evaluate<const D: usize>(
+        &self,
+        name: &str,
+        key: Vector3<isize>,
+        lod: usize,
+    ) -> Result<Field<f32, D>, EvalError> {
+        self.sample(
+            name,
+            world::padded_origin(key, lod),
+            lod,
+            world::padded_extent(lod),
+        )
+    }
+
+    /// Evaluate a `field<float, D>` declaration over a cube of the given extent,
+    /// see [`world::sample_position`].
+    pub fn sample<const D: usize>(
+        &self,
+        name: &str,
+        origin: Vector3<isize>,
+        lod: usize,
+        extent: usize,
+    ) -> Result<Field<f32, D>, EvalError> {
+        puffin::profile_function!();
+
+        let index = self.float_field(name, D)?;
+        let mut evaluator = Evaluator {
+            module: self,
+            origin,
+            lod,
+            extent,
+            grids: (0..self.fields.len()).map(|_| None).collect(),
+        };
+        evaluator.field(index);
+
+        let mut values = evaluator.grids[index].take().unwrap().values.into_iter();
+        Ok(Field::new(evaluator.extent, |_| {
+            values.next().unwrap().float()
+        }))
+    }
+
+    /// Look up a `field<float, dimension>` declaration by name.
+    pub fn float_field(&self, name: &str, dimension: usize) -> Result<usize, EvalError> {
+        let index = self
+            .field(name)
+            .ok_or_else(|| EvalError::UnknownField(name.to_string()))?;
+        let field = &self.fields[index];
+        if field.element != Element::Float || field.dimension != dimension {
+            return Err(EvalError::WrongType {
+                name: name.to_string(),
+                expected: format!("field<float, {dimension}>"),
+                found: format!("field<{}, {}>", field.element.name(), field.dimension),
+            });
+        }
+        Ok(index)
+    }
+}
+
+struct Evaluator<'a> {
+    module: &'a Module,
+    /// Position of the first voxel.
+    origin: Vector3<isize>,
+    lod: usize,
+    extent: usize,
+    /// Fields evaluated so far.
+    grids: Vec<Option<Grid>>,
+}
+
+impl Evaluator<'_> {
+    fn field(&mut self, index: usize) {
+        if self.grids[index].is_some() {
+            return;
+        }
+
+        let module = self.module;
+        let field = &module.fields[index];
+        for dependency in field.dependencies() {
+            self.field(dependency);
+        }
+
+        let mut current = field.source.map(|source| Grid {
+            dimension: field.dimension,
+            values: self.grids[source].as_ref().unwrap().values.clone(),
+        });
+
+        for stage in &field.stages {
+            let input = current.take();
+            current = Some(match stage {
+                Stage::Kernel(kernel) => self.kernel(kernel, field.dimension, input.as_ref()),
+                Stage::Normal => {
+                    let normal = self.planar(input.unwrap()).normal();
+                    Grid {
+                        dimension: 2,
+                        values: normal
+                            .coordinates()
+                            .map(|c| Value::Vec3(normal[c]))
+                            .collect(),
+                    }
+                }
+                Stage::Blur => {
+                    let blurred = self.planar(input.unwrap()).blur(BLUR_SIGMA);
+                    Grid {
+                        dimension: 2,
+                        values: blurred
+                            .coordinates()
+                            .map(|c| Value::Float(blurred[c]))
+                            .collect(),
+                    }
+                }
+            });
+        }
+
+        self.grids[index] = current;
+    }
+
+    /// Convert a 2D float grid into a [`Field`] to run whole-field operations on it.
+    fn planar(&self, grid: Grid) -> Field<f32, 2> {
+        assert_eq!(grid.dimension, 2);
+        let mut values = grid.values.into_iter();
+        Field::new(self.extent, |_| values.next().unwrap().float())
+    }
+
+    fn kernel(&self, kernel: &Kernel, dimension: usize, input: Option<&Grid>) -> Grid {
+        let count = self.extent.pow(dimension as u32);
+        let mut locals = vec![Value::Float(0.0); kernel.locals];
+
+        let values = (0..count)
+            .map(|i| {
+                let coordinate = self.coordinate(i, dimension);
+                let frame = Frame {
+                    evaluator: self,
+                    coordinate,
+                    position: world::sample_position(self.origin, self.lod, coordinate),
+                    input: input.map(|grid| grid.values[i]),
+                };
+                for statement in &kernel.statements {
+                    locals[statement.local] = frame.eval(&statement.value, &locals);
+                }
+                frame.eval(&kernel.value, &locals)
+            })
+            .collect();
+
+        Grid { dimension, values }
+    }
+
+    /// Voxel coordinate of the `i`-th value of a grid. Unused axes are zero.
+    fn coordinate(&self, i: usize, dimension: usize) -> [usize; 3] {
+        let e = self.extent;
+        match dimension {
+            2 => [i / e, i % e, 0],
+            _ => [i / (e * e), (i / e) % e, i % e],
+        }
+    }
+
+    fn linear(&self, [i, j, k]: [usize; 3], dimension: usize) -> usize {
+        let e = self.extent;
+        match dimension {
+            2 => i * e + j,
+            _ => (i * e + j) * e + k,
+        }
+    }
+}
+
+/// State for evaluating a kernel at one voxel.
+struct Frame<'a> {
+    evaluator: &'a Evaluator<'a>,
+    coordinate: [usize; 3],
+    position: Vector3<f32>,
+    input: Option<Value>,
+}
+
+impl Frame<'_> {
+    fn eval(&self, node: &Node, locals: &[Value]) -> Value {
+        match &node.kind {
+            NodeKind::Constant(value) => *value,
+            NodeKind::Local(slot) => locals[*slot],
+            NodeKind::Field(index) => {
+                let grid = self.evaluator.grids[*index].as_ref().unwrap();
+                grid.values[self.evaluator.linear(self.coordinate, grid.dimension)]
+            }
+            NodeKind::Coordinate(axis) => Value::Float(self.position[axis.index()]),
+            NodeKind::Input => self.input.unwrap(),
+            NodeKind::Component(inner, axis) => {
+                Value::Float(self.eval(inner, locals).vec3()[axis.index()])
+            }
+            NodeKind::Unary(op, operand) => match (op, self.eval(operand, locals)) {
+                (UnaryOp::Neg, Value::Float(x)) => Value::Float(-x),
+                (UnaryOp::Neg, Value::Vec3(v)) => Value::Vec3(-v),
+                (UnaryOp::Not, Value::Bool(b)) => Value::Bool(!b),
+                (op, value) => unreachable!("{op:?} {value:?}"),
+            },
+            NodeKind::Binary(op, lhs, rhs) => {
+                binary(*op, self.eval(lhs, locals), self.eval(rhs, locals))
+            }
+            NodeKind::Call(builtin, args) => {
+                let mut values = [Value::Float(0.0); 5];
+                for (value, arg) in values.iter_mut().zip(args) {
+                    *value = self.eval(arg, locals);
+                }
+                builtin.evaluate(&values[..args.len()])
+            }
+        }
+    }
+}
+
+fn binary(op: BinaryOp, lhs: Value, rhs: Value) -> Value {
+    use Value::*;
+    match (op, lhs, rhs) {
+        (BinaryOp::Add, Float(a), Float(b)) => Float(a + b),
+        (BinaryOp::Sub, Float(a), Float(b)) => Float(a - b),
+        (BinaryOp::Mul, Float(a), Float(b)) => Float(a * b),
+        (BinaryOp::Div, Float(a), Float(b)) => Float(a / b),
+        (BinaryOp::Rem, Float(a), Float(b)) => Float(a % b),
+        (BinaryOp::Add, Vec3(a), Vec3(b)) => Vec3(a + b),
+        (BinaryOp::Sub, Vec3(a), Vec3(b)) => Vec3(a - b),
+        (BinaryOp::Mul, Vec3(a), Vec3(b)) => Vec3(vec3(a.x * b.x, a.y * b.y, a.z * b.z)),
+        (BinaryOp::Div, Vec3(a), Vec3(b)) => Vec3(vec3(a.x / b.x, a.y / b.y, a.z / b.z)),
+        (BinaryOp::Mul, Vec3(a), Float(b)) => Vec3(a * b),
+        (BinaryOp::Mul, Float(a), Vec3(b)) => Vec3(b * a),
+        (BinaryOp::Div, Vec3(a), Float(b)) => Vec3(a / b),
+        (BinaryOp::Lt, Float(a), Float(b)) => Bool(a < b),
+        (BinaryOp::Le, Float(a), Float(b)) => Bool(a <= b),
+        (BinaryOp::Gt, Float(a), Float(b)) => Bool(a > b),
+        (BinaryOp::Ge, Float(a), Float(b)) => Bool(a >= b),
+        (BinaryOp::Eq, a, b) => Bool(a == b),
+        (BinaryOp::Ne, a, b) => Bool(a != b),
+        (BinaryOp::And, Bool(a), Bool(b)) => Bool(a && b),
+        (BinaryOp::Or, Bool(a), Bool(b)) => Bool(a || b),
+        (op, a, b) => unreachable!("{a:?} {op:?} {b:?}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cgmath::{vec2, vec3, Vector3};
+
+    use super::*;
+    use crate::{terrain_lang::compile, util, world::N};
+
+    const README: &str = "height: field<float, 2>
+|> {
+    n = fbm(@x / 17.3, @y / 13.1);
+    n = n.abs().powf(1.5).copysign(n)
+    n *= 50
+    n
+}
+
+blurred_normal: field<float, 2>
+|> normal
+|> {
+    $x
+}
+|> blur
+";
+
+    fn expected_height(key: Vector3<isize>, lod: usize) -> Field<f32, 2> {
+        Field::new(world::padded_extent(lod), |[i, j]| {
+            let p = world::padded_position(key, lod, [i, j, 0]);
+            let n = util::fbm(vec2(p.x / 17.3, p.y / 13.1), util::perlin);
+            let n = n.abs().powf(1.5).copysign(n);
+            n * 50.0
+        })
+    }
+
+    #[test]
+    fn readme_height() {
+        let module = compile(README).unwrap();
+        for (key, lod) in [(vec3(0, 0, 0), 0), (vec3(-3, 2, 1), 2)] {
+            let height = module.evaluate::<2>("height", key, lod).unwrap();
+            let expected = expected_height(key, lod);
+            assert_eq!(height.extent(), (N >> lod) + 2);
+            // Noise vanishes at integer coordinates, which would leave nothing to compare.
+            assert!(height.coordinates().any(|c| height[c] != 0.0));
+            for c in height.coordinates() {
+                assert_eq!(height[c], expected[c]);
+            }
+        }
+    }
+
+    #[test]
+    fn readme_blurred_normal() {
+        let module = compile(README).unwrap();
+        let key = vec3(1, -1, 0);
+        let blurred_normal = module.evaluate::<2>("blurred_normal", key, 1).unwrap();
+        let expected = expected_height(key, 1)
+            .normal()
+            .map(|n| n.x)
+            .blur(BLUR_SIGMA);
+        for c in blurred_normal.coordinates() {
+            assert_eq!(blurred_normal[c], expected[c]);
+        }
+    }
+
+    #[test]
+    fn coordinates_are_world_space() {
+        let module = compile(
+            "x: field<float, 3> |> { @x }
+            y: field<float, 3> |> { @y }
+            z: field<float, 3> |> { @z }
+            z2: field<float, 2> |> { @z }",
+        )
+        .unwrap();
+        let key = vec3(-2, 3, 1);
+        let lod = 2;
+        let x = module.evaluate::<3>("x", key, lod).unwrap();
+        let y = module.evaluate::<3>("y", key, lod).unwrap();
+        let z = module.evaluate::<3>("z", key, lod).unwrap();
+        for c in x.coordinates() {
+            let p = world::padded_position(key, lod, c);
+            assert_eq!(vec3(x[c], y[c], z[c]), p);
+        }
+        assert_eq!(x[[1, 0, 0]], -2.0 * N as f32);
+
+        let z2 = module.evaluate::<2>("z2", key, lod).unwrap();
+        assert!(z2.coordinates().all(|c| z2[c] == N as f32 - 4.0));
+    }
+
+    #[test]
+    fn volume_reads_heightmap() {
+        let module = compile(
+            "height: field<float, 2> |> { 0.5 * @x }
+            density: field<float, 3> |> {
+                d = height - @z
+                select(d >= 0, 1, -1)
+            }",
+        )
+        .unwrap();
+        let density = module.evaluate::<3>("density", vec3(0, 0, 0), 0).unwrap();
+        for [i, j, k] in density.coordinates() {
+            // Shifted by the apron.
+            let expected = if 0.5 * (i as f32 - 1.0) >= k as f32 - 1.0 {
+                1.0
+            } else {
+                -1.0
+            };
+            assert_eq!(density[[i, j, k]], expected);
+        }
+    }
+
+    #[test]
+    fn vector_arithmetic() {
+        let module = compile(
+            "v: field<float, 2> |> {
+                a = vec3(1, 2, 2)
+                b = -(a * 2 - vec3(0, 0, 1)) / 2
+                length(a) + b.z + dot(normalize(a), vec3(0, 0, 3))
+            }",
+        )
+        .unwrap();
+        // Evaluate a single voxel and its apron
+        let v = module.evaluate::<2>("v", vec3(0, 0, 0), world::K).unwrap();
+        assert_eq!(v.extent(), 3);
+        assert!((v[[1, 1]] - (3.0 - 1.5 + 2.0)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn errors() {
+        let module = compile(
+            "a: field<float, 3> |> { 1 }
+            b: field<vec3, 2> |> { vec3(1, 2, 3) }",
+        )
+        .unwrap();
+        let origin = vec3(0, 0, 0);
+        assert_eq!(
+            module.evaluate::<2>("c", origin, 0).err().unwrap(),
+            EvalError::UnknownField("c".to_string())
+        );
+        assert_eq!(
+            module
+                .evaluate::<2>("a", origin, 0)
+                .err()
+                .unwrap()
+                .to_string(),
+            "`a` must be a field<float, 2>, but is a field<float, 3>"
+        );
+        assert_eq!(
+            module
+                .evaluate::<2>("b", origin, 0)
+                .err()
+                .unwrap()
+                .to_string(),
+            "`b` must be a field<float, 2>, but is a field<vec3, 2>"
+        );
+    }
+}
@@ -0,0 +1,348 @@
+use std::borrow::Cow;
+
+use cgmath::Vector3;
+use wgpu::util::DeviceExt;
+
+use super::{eval::EvalError, ir::Module, wgsl};
+use crate::{field::Field, world};
+
+/// A terrain program compiled to compute pipelines, evaluating fields on the GPU.
+/// Results match [`Module::evaluate`] up to floating point precision.
+pub struct GpuProgram {
+    module: Module,
+    /// Passes of all stages of each field, in order.
+    passes: Vec<Vec<Pass>>,
+}
+
+struct Pass {
+    shader: wgsl::Shader,
+    pipeline: wgpu::ComputePipeline,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct Params {
+    offset: [i32; 4],
+    lod: u32,
+    extent: u32,
+    _padding: [u32; 2],
+}
+
+unsafe impl bytemuck::Pod for Params {}
+unsafe impl bytemuck::Zeroable for Params {}
+
+/// Size of one value in a field buffer, see [`wgsl::Shader`].
+const VALUE_SIZE: wgpu::BufferAddress = 16;
+
+impl GpuProgram {
+    pub fn new(device: &wgpu::Device, module: Module) -> Self {
+        puffin::profile_function!();
+
+        let passes = (0..module.fields.len())
+            .map(|field| {
+                (0..module.fields[field].stages.len())
+                    .flat_map(|stage| wgsl::stage(&module, field, stage))
+                    .map(|shader| {
+                        let shader_module =
+                            device.create_shader_module(wgpu::ShaderModuleDescriptor {
+                                label: Some(&module.fields[field].name),
+                                source: wgpu::ShaderSource::Wgsl(Cow::Borrowed(&shader.source)),
+                            });
+                        let pipeline =
+                            device.create_compute_pipeline(&wgpu::ComputePipelineDescriptor {
+                                label: Some(&module.fields[field].name),
+                                layout: None,
+                                module: &shader_module,
+                                entry_point: "main",
+                            });
+                        Pass { shader, pipeline }
+                    })
+                    .collect()
+            })
+            .collect();
+
+        Self { module, passes }
+    }
+
+    pub fn module(&self) -> &Module {
+        &self.module
+    }
+
+    /// Evaluate a `field<float, D>` declaration for the chunk with the given key and LOD,
+    /// including its apron. Blocks until the result has been read back.
+    pub fn evaluate<const D: usize>(
+        &self,
+        device: &wgpu::Device,
+        queue: &wgpu::Queue,
+        name: &str,
+        key: Vector3<isize>,
+        lod: usize,
+    ) -> Result<Field<f32, D>, EvalError> {
+        puffin::profile_function!();
+
+        let index = self.module.float_field(name, D)?;
+        let extent = world::padded_extent(lod);
+
+        let offset = world::padded_origin(key, lod);
+        let params = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: None,
+            contents: bytemuck::bytes_of(&Params {
+                offset: [offset.x as i32, offset.y as i32, offset.z as i32, 0],
+                lod: lod as u32,
+                extent: extent as u32,
+                _padding: [0; 2],
+            }),
+            usage: wgpu::BufferUsages::UNIFORM,
+        });
+
+        let mut encoder = device.create_command_encoder(&wgpu::CommandEncoderDescriptor::default());
+        let mut buffers: Vec<Option<wgpu::Buffer>> =
+            (0..self.module.fields.len()).map(|_| None).collect();
+        self.field(device, &mut encoder, &params, extent, index, &mut buffers);
+        let output = buffers[index].take().unwrap();
+
+        let staging = device.create_buffer(&wgpu::BufferDescriptor {
+            label: None,
+            size: output.size(),
+            usage: wgpu::BufferUsages::MAP_READ | wgpu::BufferUsages::COPY_DST,
+            mapped_at_creation: false,
+        });
+        encoder.copy_buffer_to_buffer(&output, 0, &staging, 0, output.size());
+        queue.submit([encoder.finish()]);
+
+        let slice = staging.slice(..);
+        let (sender, receiver) = std::sync::mpsc::channel();
+        slice.map_async(wgpu::MapMode::Read, move |result| {
+            sender.send(result).unwrap();
+        });
+        device.poll(wgpu::Maintain::Wait);
+        receiver.recv().unwrap().expect("failed to read back field");
+
+        let data = slice.get_mapped_range();
+        let values: &[[f32; 4]] = bytemuck::cast_slice(&data);
+        let mut values = values.iter();
+        Ok(Field::new(extent, |_| values.next().unwrap()[0]))
+    }
+
+    /// Record the passes computing a field and its dependencies, unless already recorded.
+    fn field(
+        &self,
+        device: &wgpu::Device,
+        encoder: &mut wgpu::CommandEncoder,
+        params: &wgpu::Buffer,
+        extent: usize,
+        index: usize,
+        buffers: &mut [Option<wgpu::Buffer>],
+    ) {
+        if buffers[index].is_some() {
+            return;
+        }
+
+        let field = &self.module.fields[index];
+        for dependency in field.dependencies() {
+            self.field(device, encoder, params, extent, dependency, buffers);
+        }
+
+        let size = extent.pow(field.dimension as u32) as wgpu::BufferAddress * VALUE_SIZE;
+        let mut current = field.source;
+        let mut previous: Option<wgpu::Buffer> = None;
+
+        for pass in &self.passes[index] {
+            let output = device.create_buffer(&wgpu::BufferDescriptor {
+                label: Some(&field.name),
+                size,
+                usage: wgpu::BufferUsages::STORAGE | wgpu::BufferUsages::COPY_SRC,
+                mapped_at_creation: false,
+            });
+
+            {
+                let input = previous
+                    .as_ref()
+                    .or_else(|| current.and_then(|source| buffers[source].as_ref()));
+                let mut entries = vec![
+                    wgpu::BindGroupEntry {
+                        binding: 0,
+                        resource: params.as_entire_binding(),
+                    },
+                    wgpu::BindGroupEntry {
+                        binding: 1,
+                        resource: output.as_entire_binding(),
+                    },
+                ];
+                if pass.shader.input {
+                    entries.push(wgpu::BindGroupEntry {
+                        binding: 2,
+                        resource: input.expect("stage has no input").as_entire_binding(),
+                    });
+                }
+                for (i, field) in pass.shader.fields.iter().enumerate() {
+                    entries.push(wgpu::BindGroupEntry {
+                        binding: 3 + i as u32,
+                        resource: buffers[*field].as_ref().unwrap().as_entire_binding(),
+                    });
+                }
+                let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+                    label: None,
+                    layout: &pass.pipeline.get_bind_group_layout(0),
+                    entries: &entries,
+                });
+
+                let [x, y, z] = pass.shader.workgroup_size();
+                let groups = |size: u32| (extent as u32).div_ceil(size);
+                let mut compute_pass =
+                    encoder.begin_compute_pass(&wgpu::ComputePassDescriptor::default());
+                compute_pass.set_pipeline(&pass.pipeline);
+                compute_pass.set_bind_group(0, &bind_group, &[]);
+                match pass.shader.dimension {
+                    2 => compute_pass.dispatch_workgroups(groups(x), groups(y), 1),
+                    _ => compute_pass.dispatch_workgroups(groups(x), groups(y), groups(z)),
+                }
+            }
+
+            current = None;
+            previous = Some(output);
+        }
+
+        buffers[index] = previous;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cgmath::vec3;
+    use pollster::FutureExt;
+
+    use super::*;
+    use crate::terrain_lang::{ast::Element, builtin::Builtin, compile};
+
+    /// A device on the software adapter, which the comparisons with the CPU require.
+    fn device() -> (wgpu::Device, wgpu::Queue) {
+        let instance = wgpu::Instance::new(wgpu::InstanceDescriptor::default());
+        let adapter = instance
+            .request_adapter(&wgpu::RequestAdapterOptions {
+                power_preference: wgpu::PowerPreference::default(),
+                force_fallback_adapter: true,
+                compatible_surface: None,
+            })
+            .block_on()
+            .expect("no fallback adapter available");
+        adapter
+            .request_device(
+                &wgpu::DeviceDescriptor {
+                    label: None,
+                    features: wgpu::Features::empty(),
+                    limits: adapter.limits(),
+                },
+                None,
+            )
+            .block_on()
+            .expect("fallback adapter provides a device")
+    }
+
+    fn assert_close<const D: usize>(source: &str, name: &str, key: Vector3<isize>, lod: usize) {
+        let (device, queue) = device();
+        let module = compile(source).unwrap();
+        let cpu = module.evaluate::<D>(name, key, lod).unwrap();
+        let gpu = GpuProgram::new(&device, module)
+            .evaluate::<D>(&device, &queue, name, key, lod)
+            .unwrap();
+
+        for c in cpu.coordinates() {
+            let (a, b) = (cpu[c], gpu[c]);
+            // WGSL does not specify results outside of a function's domain.
+            if a.is_nan() {
+                continue;
+            }
+            let close = a == b || (a - b).abs() <= 1e-3 * a.abs().max(1.0);
+            assert!(close, "{name} at {c:?}: cpu {a}, gpu {b}\n{source}");
+        }
+    }
+
+    const README: &str = "height: field<float, 2>
+|> {
+    n = fbm(@x / 17.3, @y / 13.1);
+    n = n.abs().powf(1.5).copysign(n)
+    n *= 50
+    n
+}
+
+blurred_normal: field<float, 2>
+|> normal
+|> {
+    $x
+}
+|> blur
+";
+
+    #[test]
+    fn readme() {
+        assert_close::<2>(README, "height", vec3(3, -2, 0), 0);
+        assert_close::<2>(README, "blurred_normal", vec3(-1, 4, 1), 1);
+    }
+
+    #[test]
+    fn density() {
+        let source = "
+height: field<float, 2>
+|> { worley(@x / 30, @y / 30) * 20 }
+
+density: field<float, 3>
+|> { height - @z + perlin(@x / 10, @z / 10) * 4 }
+|> { select($ > 0 && !(@z % 7 == 0), $, -1) }
+";
+        assert_close::<3>(source, "density", vec3(1, 1, 0), 1);
+        assert_close::<3>(source, "density", vec3(-1, 0, -1), 2);
+    }
+
+    #[test]
+    fn vectors_and_locals() {
+        let source = "
+v: field<float, 2>
+|> {
+    n = vec3(@x, @y, 1).normalize()
+    n *= 2
+    n = n + vec3(0, 0, 1) / 4
+    -n.dot(n * 0.5) + n.length() + n.x
+}
+";
+        assert_close::<2>(source, "v", vec3(0, 0, 0), 0);
+    }
+
+    #[test]
+    fn builtins() {
+        for builtin in Builtin::ALL {
+            let (parameters, _) = builtin.signature();
+            let args = parameters
+                .iter()
+                .enumerate()
+                .map(|(i, ty)| match ty {
+                    Element::Float => format!("@x / {} - @y / 20", 7 + i),
+                    Element::Bool => "@x > @y".to_string(),
+                    Element::Vec3 => format!("vec3(@x, @y / {}, 3)", 2 + i),
+                })
+                .collect::<Vec<_>>()
+                .join(", ");
+            let call = format!("{}({args})", builtin.name());
+            let value = match builtin.signature().1 {
+                Element::Vec3 => format!("{call}.y"),
+                _ => call,
+            };
+            let source = format!("f: field<float, 2>\n|> {{ {value} }}");
+            assert_close::<2>(&source, "f", vec3(0, -2, 0), 1);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let (device, queue) = device();
+        let program = GpuProgram::new(&device, compile(README).unwrap());
+        assert!(matches!(
+            program.evaluate::<2>(&device, &queue, "depth", vec3(0, 0, 0), 0),
+            Err(EvalError::UnknownField(_))
+        ));
+        assert!(matches!(
+            program.evaluate::<3>(&device, &queue, "height", vec3(0, 0, 0), 0),
+            Err(EvalError::WrongType { .. })
+        ));
+    }
+}
@@ -0,0 +1,167 @@
+use cgmath::Vector3;
+
+use super::{
+    ast::{Axis, BinaryOp, Element, UnaryOp},
+    builtin::Builtin,
+    diagnostic::Span,
+};
+
+/// A type-checked terrain program with names resolved.
+#[derive(Debug, Clone)]
+pub struct Module {
+    /// Fields in declaration order. A field only refers to fields before it.
+    pub fields: Vec<FieldDef>,
+}
+
+impl Module {
+    pub fn field(&self, name: &str) -> Option<usize> {
+        self.fields.iter().position(|f| f.name == name)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct FieldDef {
+    pub name: String,
+    pub element: Element,
+    pub dimension: usize,
+    /// The field feeding the first stage, if that stage is a named stage.
+    pub source: Option<usize>,
+    pub stages: Vec<Stage>,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum Stage {
+    Kernel(Kernel),
+    /// [`crate::field::Field::normal`]
+    Normal,
+    /// [`crate::field::Field::blur`] with [`BLUR_SIGMA`]
+    Blur,
+}
+
+/// Standard deviation of the `blur` stage, in voxels.
+pub const BLUR_SIGMA: f32 = 2.0;
+
+/// An expression block evaluated once per voxel.
+#[derive(Debug, Clone)]
+pub struct Kernel {
+    /// Type of `$`, if the stage has an input.
+    pub input: Option<Element>,
+    /// Number of local variable slots.
+    pub locals: usize,
+    pub statements: Vec<Assignment>,
+    pub value: Node,
+}
+
+#[derive(Debug, Clone)]
+pub struct Assignment {
+    pub local: usize,
+    pub value: Node,
+}
+
+#[derive(Debug, Clone)]
+pub struct Node {
+    pub kind: NodeKind,
+    pub ty: Element,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone)]
+pub enum NodeKind {
+    Constant(Value),
+    Local(usize),
+    /// The value of another field at the same voxel.
+    Field(usize),
+    Coordinate(Axis),
+    Input,
+    Component(Box<Node>, Axis),
+    Unary(UnaryOp, Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+    Call(Builtin, Vec<Node>),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Value {
+    Float(f32),
+    Bool(bool),
+    Vec3(Vector3<f32>),
+}
+
+impl Value {
+    #[track_caller]
+    pub fn float(self) -> f32 {
+        match self {
+            Value::Float(x) => x,
+            _ => panic!("expected float, found {self:?}"),
+        }
+    }
+
+    #[track_caller]
+    pub fn bool(self) -> bool {
+        match self {
+            Value::Bool(x) => x,
+            _ => panic!("expected bool, found {self:?}"),
+        }
+    }
+
+    #[track_caller]
+    pub fn vec3(self) -> Vector3<f32> {
+        match self {
+            Value::Vec3(x) => x,
+            _ => panic!("expected vec3, found {self:?}"),
+        }
+    }
+}
+
+impl Node {
+    /// Call `f` on this node and all nodes below it.
+    pub fn visit(&self, f: &mut impl FnMut(&Node)) {
+        f(self);
+        match &self.kind {
+            NodeKind::Constant(_)
+            | NodeKind::Local(_)
+            | NodeKind::Field(_)
+            | NodeKind::Coordinate(_)
+            | NodeKind::Input => {}
+            NodeKind::Component(inner, _) | NodeKind::Unary(_, inner) => inner.visit(f),
+            NodeKind::Binary(_, lhs, rhs) => {
+                lhs.visit(f);
+                rhs.visit(f);
+            }
+            NodeKind::Call(_, args) => {
+                for arg in args {
+                    arg.visit(f);
+                }
+            }
+        }
+    }
+}
+
+impl Kernel {
+    /// Call `f` on every node of the kernel.
+    pub fn visit(&self, f: &mut impl FnMut(&Node)) {
+        for statement in &self.statements {
+            statement.value.visit(f);
+        }
+        self.value.visit(f);
+    }
+}
+
+impl FieldDef {
+    /// Indices of the fields this field reads from.
+    pub fn dependencies(&self) -> Vec<usize> {
+        let mut dependencies: Vec<usize> = self.source.into_iter().collect();
+        for stage in &self.stages {
+            if let Stage::Kernel(kernel) = stage {
+                kernel.visit(&mut |node| {
+                    if let NodeKind::Field(index) = node.kind {
+                        dependencies.push(index);
+                    }
+                });
+            }
+        }
+        dependencies.sort();
+        dependencies.dedup();
+        dependencies
+    }
+}
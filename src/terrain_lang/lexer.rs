@@ -0,0 +1,376 @@
+use super::{
+    ast::Axis,
+    diagnostic::{Diagnostic, Span},
+};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind {
+    Ident(String),
+    Number(f32),
+    True,
+    False,
+    /// `@x`, `@y` or `@z`
+    Coordinate(Axis),
+    /// `$` optionally directly followed by a component, e.g. `$x`.
+    Input(Option<Axis>),
+    Colon,
+    Comma,
+    Semicolon,
+    Dot,
+    LParen,
+    RParen,
+    LBrace,
+    RBrace,
+    Pipe,
+    Assign,
+    Plus,
+    Minus,
+    Star,
+    Slash,
+    Percent,
+    PlusAssign,
+    MinusAssign,
+    StarAssign,
+    SlashAssign,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    EqEq,
+    Ne,
+    Not,
+    AndAnd,
+    OrOr,
+    Eof,
+}
+
+impl TokenKind {
+    pub fn describe(&self) -> String {
+        match self {
+            TokenKind::Ident(name) => format!("`{name}`"),
+            TokenKind::Number(n) => format!("`{n}`"),
+            TokenKind::True => "`true`".to_string(),
+            TokenKind::False => "`false`".to_string(),
+            TokenKind::Coordinate(axis) => format!("`@{}`", axis.name()),
+            TokenKind::Input(None) => "`$`".to_string(),
+            TokenKind::Input(Some(axis)) => format!("`${}`", axis.name()),
+            TokenKind::Eof => "end of file".to_string(),
+            punctuation => format!("`{}`", punctuation.symbol()),
+        }
+    }
+
+    fn symbol(&self) -> &'static str {
+        match self {
+            TokenKind::Colon => ":",
+            TokenKind::Comma => ",",
+            TokenKind::Semicolon => ";",
+            TokenKind::Dot => ".",
+            TokenKind::LParen => "(",
+            TokenKind::RParen => ")",
+            TokenKind::LBrace => "{",
+            TokenKind::RBrace => "}",
+            TokenKind::Pipe => "|>",
+            TokenKind::Assign => "=",
+            TokenKind::Plus => "+",
+            TokenKind::Minus => "-",
+            TokenKind::Star => "*",
+            TokenKind::Slash => "/",
+            TokenKind::Percent => "%",
+            TokenKind::PlusAssign => "+=",
+            TokenKind::MinusAssign => "-=",
+            TokenKind::StarAssign => "*=",
+            TokenKind::SlashAssign => "/=",
+            TokenKind::Lt => "<",
+            TokenKind::Le => "<=",
+            TokenKind::Gt => ">",
+            TokenKind::Ge => ">=",
+            TokenKind::EqEq => "==",
+            TokenKind::Ne => "!=",
+            TokenKind::Not => "!",
+            TokenKind::AndAnd => "&&",
+            TokenKind::OrOr => "||",
+            _ => "",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+    /// Whether a line break separates this token from the previous one.
+    /// Statements may be terminated by a line break instead of a `;`.
+    pub newline_before: bool,
+}
+
+/// Split the source into tokens. The last token is always [`TokenKind::Eof`].
+pub fn lex(source: &str) -> Result<Vec<Token>, Diagnostic> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    let mut newline_before = false;
+
+    while i < bytes.len() {
+        let c = bytes[i];
+        let start = i;
+
+        // Whitespace and comments
+        if c == b'\n' {
+            newline_before = true;
+            i += 1;
+            continue;
+        }
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        if source[i..].starts_with("//") {
+            while i < bytes.len() && bytes[i] != b'\n' {
+                i += 1;
+            }
+            continue;
+        }
+
+        let kind = if is_ident_start(c) {
+            while i < bytes.len() && is_ident_continue(bytes[i]) {
+                i += 1;
+            }
+            match &source[start..i] {
+                "true" => TokenKind::True,
+                "false" => TokenKind::False,
+                name => TokenKind::Ident(name.to_string()),
+            }
+        } else if c.is_ascii_digit() {
+            i = number_end(bytes, i);
+            let text = &source[start..i];
+            let n = text.parse().map_err(|_| {
+                Diagnostic::new(format!("invalid number `{text}`"), Span::new(start, i))
+            })?;
+            TokenKind::Number(n)
+        } else if c == b'@' || c == b'$' {
+            i += 1;
+            let name_start = i;
+            while i < bytes.len() && is_ident_continue(bytes[i]) {
+                i += 1;
+            }
+            let name = &source[name_start..i];
+            let axis = Axis::from_name(name);
+            match (c, axis) {
+                (b'@', Some(axis)) => TokenKind::Coordinate(axis),
+                (b'$', axis) if name.is_empty() || axis.is_some() => TokenKind::Input(axis),
+                (b'@', _) => {
+                    return Err(Diagnostic::new(
+                        format!("unknown coordinate `@{name}`, expected `@x`, `@y` or `@z`"),
+                        Span::new(start, i),
+                    ))
+                }
+                _ => {
+                    return Err(Diagnostic::new(
+                        format!("unknown input component `${name}`, expected `$x`, `$y` or `$z`"),
+                        Span::new(start, i),
+                    ))
+                }
+            }
+        } else {
+            let two = source.get(i..i + 2).unwrap_or("");
+            let (kind, length) = match two {
+                "|>" => (TokenKind::Pipe, 2),
+                "+=" => (TokenKind::PlusAssign, 2),
+                "-=" => (TokenKind::MinusAssign, 2),
+                "*=" => (TokenKind::StarAssign, 2),
+                "/=" => (TokenKind::SlashAssign, 2),
+                "<=" => (TokenKind::Le, 2),
+                ">=" => (TokenKind::Ge, 2),
+                "==" => (TokenKind::EqEq, 2),
+                "!=" => (TokenKind::Ne, 2),
+                "&&" => (TokenKind::AndAnd, 2),
+                "||" => (TokenKind::OrOr, 2),
+                _ => (
+                    match c {
+                        b':' => TokenKind::Colon,
+                        b',' => TokenKind::Comma,
+                        b';' => TokenKind::Semicolon,
+                        b'.' => TokenKind::Dot,
+                        b'(' => TokenKind::LParen,
+                        b')' => TokenKind::RParen,
+                        b'{' => TokenKind::LBrace,
+                        b'}' => TokenKind::RBrace,
+                        b'=' => TokenKind::Assign,
+                        b'+' => TokenKind::Plus,
+                        b'-' => TokenKind::Minus,
+                        b'*' => TokenKind::Star,
+                        b'/' => TokenKind::Slash,
+                        b'%' => TokenKind::Percent,
+                        b'<' => TokenKind::Lt,
+                        b'>' => TokenKind::Gt,
+                        b'!' => TokenKind::Not,
+                        _ => {
+                            let c = source[i..].chars().next().unwrap();
+                            return Err(Diagnostic::new(
+                                format!("unexpected character `{c}`"),
+                                Span::new(i, i + c.len_utf8()),
+                            ));
+                        }
+                    },
+                    1,
+                ),
+            };
+            i += length;
+            kind
+        };
+
+        tokens.push(Token {
+            kind,
+            span: Span::new(start, i),
+            newline_before,
+        });
+        newline_before = false;
+    }
+
+    tokens.push(Token {
+        kind: TokenKind::Eof,
+        span: Span::new(source.len(), source.len()),
+        newline_before,
+    });
+
+    Ok(tokens)
+}
+
+fn is_ident_start(c: u8) -> bool {
+    c.is_ascii_alphabetic() || c == b'_'
+}
+
+fn is_ident_continue(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+/// Scan `123`, `1.5` and `2e-3`. A dot not followed by a digit is left alone,
+/// so that `2.abs()` is a method call.
+fn number_end(bytes: &[u8], mut i: usize) -> usize {
+    while i < bytes.len() && bytes[i].is_ascii_digit() {
+        i += 1;
+    }
+    if i + 1 < bytes.len() && bytes[i] == b'.' && bytes[i + 1].is_ascii_digit() {
+        i += 1;
+        while i < bytes.len() && bytes[i].is_ascii_digit() {
+            i += 1;
+        }
+    }
+    if i < bytes.len() && (bytes[i] == b'e' || bytes[i] == b'E') {
+        let mut j = i + 1;
+        if j < bytes.len() && (bytes[j] == b'+' || bytes[j] == b'-') {
+            j += 1;
+        }
+        if j < bytes.len() && bytes[j].is_ascii_digit() {
+            i = j;
+            while i < bytes.len() && bytes[i].is_ascii_digit() {
+                i += 1;
+            }
+        }
+    }
+    i
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn kinds(source: &str) -> Vec<TokenKind> {
+        lex(source).unwrap().into_iter().map(|t| t.kind).collect()
+    }
+
+    #[test]
+    fn numbers() {
+        assert_eq!(
+            kinds("1 1.5 2e3 2.5e-1"),
+            [
+                TokenKind::Number(1.0),
+                TokenKind::Number(1.5),
+                TokenKind::Number(2000.0),
+                TokenKind::Number(0.25),
+                TokenKind::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn method_call_on_number() {
+        assert_eq!(
+            kinds("2.abs()"),
+            [
+                TokenKind::Number(2.0),
+                TokenKind::Dot,
+                TokenKind::Ident("abs".to_string()),
+                TokenKind::LParen,
+                TokenKind::RParen,
+                TokenKind::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn coordinates_and_input() {
+        assert_eq!(
+            kinds("@x @y @z $ $x"),
+            [
+                TokenKind::Coordinate(Axis::X),
+                TokenKind::Coordinate(Axis::Y),
+                TokenKind::Coordinate(Axis::Z),
+                TokenKind::Input(None),
+                TokenKind::Input(Some(Axis::X)),
+                TokenKind::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn operators() {
+        assert_eq!(
+            kinds("|> *= += -= /= <= >= == != && || < > ! = %"),
+            [
+                TokenKind::Pipe,
+                TokenKind::StarAssign,
+                TokenKind::PlusAssign,
+                TokenKind::MinusAssign,
+                TokenKind::SlashAssign,
+                TokenKind::Le,
+                TokenKind::Ge,
+                TokenKind::EqEq,
+                TokenKind::Ne,
+                TokenKind::AndAnd,
+                TokenKind::OrOr,
+                TokenKind::Lt,
+                TokenKind::Gt,
+                TokenKind::Not,
+                TokenKind::Assign,
+                TokenKind::Percent,
+                TokenKind::Eof,
+            ]
+        );
+    }
+
+    #[test]
+    fn comments_and_newlines() {
+        let tokens = lex("a // comment\nb").unwrap();
+        assert_eq!(tokens[0].kind, TokenKind::Ident("a".to_string()));
+        assert!(!tokens[0].newline_before);
+        assert_eq!(tokens[1].kind, TokenKind::Ident("b".to_string()));
+        assert!(tokens[1].newline_before);
+        assert_eq!(tokens[1].span, Span::new(13, 14));
+    }
+
+    #[test]
+    fn unknown_coordinate() {
+        let error = lex("n = @w").unwrap_err();
+        assert_eq!(error.span, Span::new(4, 6));
+        assert!(error
+            .render("n = @w")
+            .starts_with("1:5: unknown coordinate `@w`"));
+    }
+
+    #[test]
+    fn unexpected_character() {
+        let error = lex("a\n  #").unwrap_err();
+        assert_eq!(error.location("a\n  #"), (2, 3));
+    }
+}
@@ -0,0 +1,763 @@
+use super::{
+    ast::*,
+    diagnostic::{line_column, Diagnostic, Span},
+    lexer::{lex, Token, TokenKind},
+};
+
+/// Parse a terrain program.
+pub fn parse(source: &str) -> Result<Program, Diagnostic> {
+    let tokens = lex(source)?;
+    Parser {
+        source,
+        tokens,
+        position: 0,
+        nesting: 0,
+    }
+    .program()
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<Token>,
+    position: usize,
+    /// Number of enclosing parentheses. Line breaks do not end statements inside them.
+    nesting: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position]
+    }
+
+    fn peek_kind(&self) -> &TokenKind {
+        &self.peek().kind
+    }
+
+    fn peek_second_kind(&self) -> &TokenKind {
+        let i = (self.position + 1).min(self.tokens.len() - 1);
+        &self.tokens[i].kind
+    }
+
+    fn advance(&mut self) -> Token {
+        let token = self.tokens[self.position].clone();
+        if self.position < self.tokens.len() - 1 {
+            self.position += 1;
+        }
+        token
+    }
+
+    /// The span of the most recently consumed token.
+    fn previous_span(&self) -> Span {
+        self.tokens[self.position.saturating_sub(1)].span
+    }
+
+    fn eat(&mut self, kind: &TokenKind) -> bool {
+        if self.peek_kind() == kind {
+            self.advance();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, kind: TokenKind, context: &str) -> Result<Token, Diagnostic> {
+        if *self.peek_kind() == kind {
+            Ok(self.advance())
+        } else {
+            Err(self.unexpected(&format!("{} {context}", kind.describe())))
+        }
+    }
+
+    fn unexpected(&self, expected: &str) -> Diagnostic {
+        let token = self.peek();
+        Diagnostic::new(
+            format!("expected {expected}, found {}", token.kind.describe()),
+            token.span,
+        )
+    }
+
+    fn ident(&mut self, context: &str) -> Result<Ident, Diagnostic> {
+        match self.peek_kind().clone() {
+            TokenKind::Ident(name) => {
+                let span = self.advance().span;
+                Ok(Ident { name, span })
+            }
+            _ => Err(self.unexpected(context)),
+        }
+    }
+
+    /// Whether the next token is on the same logical line and can continue an expression.
+    fn continues_line(&self) -> bool {
+        self.nesting > 0 || !self.peek().newline_before
+    }
+
+    fn program(&mut self) -> Result<Program, Diagnostic> {
+        let mut declarations: Vec<Declaration> = Vec::new();
+        while *self.peek_kind() != TokenKind::Eof {
+            let declaration = self.declaration()?;
+            if let Some(previous) = declarations
+                .iter()
+                .find(|d| d.name.name == declaration.name.name)
+            {
+                let (line, _) = line_column(self.source, previous.span.start);
+                return Err(Diagnostic::new(
+                    format!(
+                        "`{}` is already declared on line {line}",
+                        declaration.name.name
+                    ),
+                    declaration.name.span,
+                ));
+            }
+            declarations.push(declaration);
+        }
+        Ok(Program { declarations })
+    }
+
+    fn declaration(&mut self) -> Result<Declaration, Diagnostic> {
+        let name = self.ident("a declaration name")?;
+        self.expect(TokenKind::Colon, "after the declaration name")?;
+        let ty = self.ty()?;
+
+        let mut stages = Vec::new();
+        while self.eat(&TokenKind::Pipe) {
+            stages.push(self.stage()?);
+        }
+        if stages.is_empty() {
+            return Err(self.unexpected("`|>` followed by a pipeline stage"));
+        }
+
+        let span = name.span.join(self.previous_span());
+        Ok(Declaration {
+            name,
+            ty,
+            stages,
+            span,
+        })
+    }
+
+    fn ty(&mut self) -> Result<Type, Diagnostic> {
+        let field = self.ident("a type such as `field<float, 2>`")?;
+        if field.name != "field" {
+            return Err(Diagnostic::new(
+                format!("unknown type `{}`, expected `field`", field.name),
+                field.span,
+            ));
+        }
+        self.expect(TokenKind::Lt, "after `field`")?;
+
+        let element = self.ident("an element type")?;
+        let element = match element.name.as_str() {
+            "float" => Element::Float,
+            "bool" => Element::Bool,
+            "vec3" => Element::Vec3,
+            name => {
+                return Err(Diagnostic::new(
+                    format!("unknown element type `{name}`, expected `float`, `bool` or `vec3`"),
+                    element.span,
+                ))
+            }
+        };
+
+        self.expect(TokenKind::Comma, "after the element type")?;
+
+        let dimension = match self.peek_kind().clone() {
+            TokenKind::Number(n) if n == 2.0 || n == 3.0 => {
+                self.advance();
+                n as usize
+            }
+            TokenKind::Number(_) => {
+                return Err(Diagnostic::new(
+                    "field dimension must be 2 or 3",
+                    self.peek().span,
+                ))
+            }
+            _ => return Err(self.unexpected("a field dimension")),
+        };
+
+        let end = self.expect(TokenKind::Gt, "to close the field type")?;
+
+        Ok(Type {
+            element,
+            dimension,
+            span: field.span.join(end.span),
+        })
+    }
+
+    fn stage(&mut self) -> Result<Stage, Diagnostic> {
+        match self.peek_kind() {
+            TokenKind::LBrace => Ok(Stage::Block(self.block()?)),
+            TokenKind::Ident(_) => Ok(Stage::Named(self.ident("a stage")?)),
+            _ => Err(self.unexpected("a block or a named stage")),
+        }
+    }
+
+    fn block(&mut self) -> Result<Block, Diagnostic> {
+        let start = self.expect(TokenKind::LBrace, "to open a block")?.span;
+
+        let mut statements = Vec::new();
+        let value = loop {
+            if *self.peek_kind() == TokenKind::RBrace {
+                return Err(Diagnostic::new(
+                    "block must end with an expression",
+                    start.join(self.peek().span),
+                ));
+            }
+
+            let is_assignment = matches!(self.peek_kind(), TokenKind::Ident(_))
+                && assignment_operator(self.peek_second_kind()).is_some();
+
+            if is_assignment {
+                let target = self.ident("an assignment target")?;
+                let operator = assignment_operator(self.peek_kind()).unwrap();
+                self.advance();
+                let value = self.expression()?;
+                let span = target.span.join(value.span);
+                statements.push(Statement {
+                    target,
+                    operator,
+                    value,
+                    span,
+                });
+                self.statement_end()?;
+            } else {
+                let value = self.expression()?;
+                self.eat(&TokenKind::Semicolon);
+                if *self.peek_kind() != TokenKind::RBrace {
+                    return Err(Diagnostic::new(
+                        "expression result is unused, only the last expression of a block is its value",
+                        value.span,
+                    ));
+                }
+                break value;
+            }
+        };
+
+        let end = self.expect(TokenKind::RBrace, "to close the block")?.span;
+
+        Ok(Block {
+            statements,
+            value,
+            span: start.join(end),
+        })
+    }
+
+    fn statement_end(&mut self) -> Result<(), Diagnostic> {
+        if self.eat(&TokenKind::Semicolon)
+            || self.peek().newline_before
+            || *self.peek_kind() == TokenKind::RBrace
+        {
+            Ok(())
+        } else {
+            Err(self.unexpected("`;` or a line break after the statement"))
+        }
+    }
+
+    fn expression(&mut self) -> Result<Expr, Diagnostic> {
+        self.binary(0)
+    }
+
+    /// Precedence climbing over binary operators binding tighter than `min_precedence`.
+    fn binary(&mut self, min_precedence: u8) -> Result<Expr, Diagnostic> {
+        let mut lhs = self.unary()?;
+        while self.continues_line() {
+            let Some(op) = binary_operator(self.peek_kind()) else {
+                break;
+            };
+            if op.precedence() <= min_precedence {
+                break;
+            }
+            self.advance();
+            let rhs = self.binary(op.precedence())?;
+            let span = lhs.span.join(rhs.span);
+            lhs = Expr {
+                kind: ExprKind::Binary(op, Box::new(lhs), Box::new(rhs)),
+                span,
+            };
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, Diagnostic> {
+        let op = match self.peek_kind() {
+            TokenKind::Minus => UnaryOp::Neg,
+            TokenKind::Not => UnaryOp::Not,
+            _ => return self.postfix(),
+        };
+        let start = self.advance().span;
+        let operand = self.unary()?;
+        let span = start.join(operand.span);
+        Ok(Expr {
+            kind: ExprKind::Unary(op, Box::new(operand)),
+            span,
+        })
+    }
+
+    fn postfix(&mut self) -> Result<Expr, Diagnostic> {
+        let mut expr = self.primary()?;
+
+        // Method chains may continue on the next line, since a line can not start with `.`.
+        while self.eat(&TokenKind::Dot) {
+            let name = self.ident("a method or component name after `.`")?;
+            if *self.peek_kind() == TokenKind::LParen && self.continues_line() {
+                // `x.f(y)` is sugar for `f(x, y)`
+                let mut args = vec![expr];
+                args.extend(self.arguments()?);
+                let span = args[0].span.join(self.previous_span());
+                expr = Expr {
+                    kind: ExprKind::Call(name, args),
+                    span,
+                };
+            } else if let Some(axis) = Axis::from_name(&name.name) {
+                let span = expr.span.join(name.span);
+                expr = Expr {
+                    kind: ExprKind::Component(Box::new(expr), axis),
+                    span,
+                };
+            } else {
+                return Err(Diagnostic::new(
+                    format!(
+                        "unknown component `{}`, expected `x`, `y`, `z` or a method call",
+                        name.name
+                    ),
+                    name.span,
+                ));
+            }
+        }
+
+        Ok(expr)
+    }
+
+    fn primary(&mut self) -> Result<Expr, Diagnostic> {
+        let token = self.peek().clone();
+        let kind = match token.kind {
+            TokenKind::Number(n) => {
+                self.advance();
+                ExprKind::Number(n)
+            }
+            TokenKind::True => {
+                self.advance();
+                ExprKind::Bool(true)
+            }
+            TokenKind::False => {
+                self.advance();
+                ExprKind::Bool(false)
+            }
+            TokenKind::Coordinate(axis) => {
+                self.advance();
+                ExprKind::Coordinate(axis)
+            }
+            TokenKind::Input(None) => {
+                self.advance();
+                ExprKind::Input
+            }
+            TokenKind::Input(Some(axis)) => {
+                self.advance();
+                let input = Expr {
+                    kind: ExprKind::Input,
+                    span: Span::new(token.span.start, token.span.start + 1),
+                };
+                ExprKind::Component(Box::new(input), axis)
+            }
+            TokenKind::Ident(_) => {
+                let name = self.ident("an identifier")?;
+                if *self.peek_kind() == TokenKind::LParen && self.continues_line() {
+                    let args = self.arguments()?;
+                    ExprKind::Call(name, args)
+                } else {
+                    ExprKind::Variable(name)
+                }
+            }
+            TokenKind::LParen => {
+                self.advance();
+                self.nesting += 1;
+                let inner = self.expression()?;
+                self.nesting -= 1;
+                self.expect(TokenKind::RParen, "to close the parenthesis")?;
+                // Keep the inner expression, but widen its span to the parentheses.
+                return Ok(Expr {
+                    kind: inner.kind,
+                    span: token.span.join(self.previous_span()),
+                });
+            }
+            _ => return Err(self.unexpected("an expression")),
+        };
+
+        Ok(Expr {
+            kind,
+            span: token.span.join(self.previous_span()),
+        })
+    }
+
+    fn arguments(&mut self) -> Result<Vec<Expr>, Diagnostic> {
+        self.expect(TokenKind::LParen, "to open the argument list")?;
+        self.nesting += 1;
+        let mut args = Vec::new();
+        while *self.peek_kind() != TokenKind::RParen {
+            args.push(self.expression()?);
+            if !self.eat(&TokenKind::Comma) {
+                break;
+            }
+        }
+        self.nesting -= 1;
+        self.expect(TokenKind::RParen, "to close the argument list")?;
+        Ok(args)
+    }
+}
+
+fn assignment_operator(kind: &TokenKind) -> Option<Option<BinaryOp>> {
+    match kind {
+        TokenKind::Assign => Some(None),
+        TokenKind::PlusAssign => Some(Some(BinaryOp::Add)),
+        TokenKind::MinusAssign => Some(Some(BinaryOp::Sub)),
+        TokenKind::StarAssign => Some(Some(BinaryOp::Mul)),
+        TokenKind::SlashAssign => Some(Some(BinaryOp::Div)),
+        _ => None,
+    }
+}
+
+fn binary_operator(kind: &TokenKind) -> Option<BinaryOp> {
+    match kind {
+        TokenKind::Plus => Some(BinaryOp::Add),
+        TokenKind::Minus => Some(BinaryOp::Sub),
+        TokenKind::Star => Some(BinaryOp::Mul),
+        TokenKind::Slash => Some(BinaryOp::Div),
+        TokenKind::Percent => Some(BinaryOp::Rem),
+        TokenKind::Lt => Some(BinaryOp::Lt),
+        TokenKind::Le => Some(BinaryOp::Le),
+        TokenKind::Gt => Some(BinaryOp::Gt),
+        TokenKind::Ge => Some(BinaryOp::Ge),
+        TokenKind::EqEq => Some(BinaryOp::Eq),
+        TokenKind::Ne => Some(BinaryOp::Ne),
+        TokenKind::AndAnd => Some(BinaryOp::And),
+        TokenKind::OrOr => Some(BinaryOp::Or),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// The example from the Readme.
+    const README: &str = "height: field<float, 2>
+|> {
+    // @x, @y, @z are world-space coordinates of the voxel
+    n = fbm(@x, @y);
+    n = n.abs().powf(1.5).copysign(n)
+    n *= 50
+    n
+}
+
+blurred_normal: field<float, 2>
+|> normal
+|> {
+    // $ is the input value, e.g. the normal in this case
+    $x
+}
+|> blur
+";
+
+    fn number(n: f32) -> ExprKind {
+        ExprKind::Number(n)
+    }
+
+    fn variable(name: &str) -> ExprKind {
+        ExprKind::Variable(Ident {
+            name: name.to_string(),
+            span: Span::default(),
+        })
+    }
+
+    /// Strip spans so that expressions can be compared structurally.
+    fn strip(expr: &Expr) -> ExprKind {
+        match &expr.kind {
+            ExprKind::Variable(ident) => variable(&ident.name),
+            ExprKind::Component(e, axis) => ExprKind::Component(Box::new(bare(strip(e))), *axis),
+            ExprKind::Unary(op, e) => ExprKind::Unary(*op, Box::new(bare(strip(e)))),
+            ExprKind::Binary(op, a, b) => {
+                ExprKind::Binary(*op, Box::new(bare(strip(a))), Box::new(bare(strip(b))))
+            }
+            ExprKind::Call(f, args) => ExprKind::Call(
+                Ident {
+                    name: f.name.clone(),
+                    span: Span::default(),
+                },
+                args.iter().map(|a| bare(strip(a))).collect(),
+            ),
+            kind => kind.clone(),
+        }
+    }
+
+    fn bare(kind: ExprKind) -> Expr {
+        Expr {
+            kind,
+            span: Span::default(),
+        }
+    }
+
+    fn call(name: &str, args: Vec<ExprKind>) -> ExprKind {
+        ExprKind::Call(
+            Ident {
+                name: name.to_string(),
+                span: Span::default(),
+            },
+            args.into_iter().map(bare).collect(),
+        )
+    }
+
+    fn binary(op: BinaryOp, a: ExprKind, b: ExprKind) -> ExprKind {
+        ExprKind::Binary(op, Box::new(bare(a)), Box::new(bare(b)))
+    }
+
+    /// Parse a single expression by wrapping it into a declaration.
+    fn expression(source: &str) -> ExprKind {
+        let program = parse(&format!("f: field<float, 2> |> {{ {source} }}")).unwrap();
+        let Stage::Block(block) = &program.declarations[0].stages[0] else {
+            panic!("expected a block");
+        };
+        strip(&block.value)
+    }
+
+    fn error(source: &str) -> String {
+        let diagnostic = parse(source).unwrap_err();
+        let (line, column) = diagnostic.location(source);
+        format!("{line}:{column}: {}", diagnostic.message)
+    }
+
+    #[test]
+    fn readme_declarations() {
+        let program = parse(README).unwrap();
+        assert_eq!(program.declarations.len(), 2);
+
+        let height = program.declaration("height").unwrap();
+        assert_eq!(height.ty.element, Element::Float);
+        assert_eq!(height.ty.dimension, 2);
+        assert_eq!(
+            &README[height.ty.span.start..height.ty.span.end],
+            "field<float, 2>"
+        );
+        assert_eq!(height.stages.len(), 1);
+
+        let blurred_normal = program.declaration("blurred_normal").unwrap();
+        assert_eq!(blurred_normal.stages.len(), 3);
+        assert!(matches!(&blurred_normal.stages[0], Stage::Named(i) if i.name == "normal"));
+        assert!(matches!(&blurred_normal.stages[1], Stage::Block(_)));
+        assert!(matches!(&blurred_normal.stages[2], Stage::Named(i) if i.name == "blur"));
+    }
+
+    #[test]
+    fn readme_height_block() {
+        let program = parse(README).unwrap();
+        let Stage::Block(block) = &program.declarations[0].stages[0] else {
+            panic!("expected a block");
+        };
+
+        assert_eq!(block.statements.len(), 3);
+
+        // n = fbm(@x, @y);
+        let s = &block.statements[0];
+        assert_eq!(s.target.name, "n");
+        assert_eq!(s.operator, None);
+        assert_eq!(
+            strip(&s.value),
+            call(
+                "fbm",
+                vec![ExprKind::Coordinate(Axis::X), ExprKind::Coordinate(Axis::Y)]
+            )
+        );
+
+        // n = n.abs().powf(1.5).copysign(n)
+        let s = &block.statements[1];
+        assert_eq!(s.operator, None);
+        assert_eq!(
+            strip(&s.value),
+            call(
+                "copysign",
+                vec![
+                    call("powf", vec![call("abs", vec![variable("n")]), number(1.5)]),
+                    variable("n")
+                ]
+            )
+        );
+        assert_eq!(
+            &README[s.span.start..s.span.end],
+            "n = n.abs().powf(1.5).copysign(n)"
+        );
+
+        // n *= 50
+        let s = &block.statements[2];
+        assert_eq!(s.operator, Some(BinaryOp::Mul));
+        assert_eq!(strip(&s.value), number(50.0));
+
+        assert_eq!(strip(&block.value), variable("n"));
+    }
+
+    #[test]
+    fn readme_input_component() {
+        let program = parse(README).unwrap();
+        let Stage::Block(block) = &program.declarations[1].stages[1] else {
+            panic!("expected a block");
+        };
+        assert!(block.statements.is_empty());
+        assert_eq!(
+            strip(&block.value),
+            ExprKind::Component(Box::new(bare(ExprKind::Input)), Axis::X)
+        );
+        assert_eq!(&README[block.value.span.start..block.value.span.end], "$x");
+    }
+
+    #[test]
+    fn method_call_is_sugar_for_call() {
+        assert_eq!(expression("a.f(b, c)"), expression("f(a, b, c)"));
+        assert_eq!(expression("2.abs()"), call("abs", vec![number(2.0)]));
+    }
+
+    #[test]
+    fn components() {
+        assert_eq!(
+            expression("$.y"),
+            ExprKind::Component(Box::new(bare(ExprKind::Input)), Axis::Y)
+        );
+        assert_eq!(
+            expression("v.z"),
+            ExprKind::Component(Box::new(bare(variable("v"))), Axis::Z)
+        );
+        assert_eq!(expression("$"), ExprKind::Input);
+    }
+
+    #[test]
+    fn precedence() {
+        assert_eq!(
+            expression("1 + 2 * 3"),
+            binary(
+                BinaryOp::Add,
+                number(1.0),
+                binary(BinaryOp::Mul, number(2.0), number(3.0))
+            )
+        );
+        assert_eq!(
+            expression("1 - 2 - 3"),
+            binary(
+                BinaryOp::Sub,
+                binary(BinaryOp::Sub, number(1.0), number(2.0)),
+                number(3.0)
+            )
+        );
+        assert_eq!(
+            expression("(1 + 2) * 3"),
+            binary(
+                BinaryOp::Mul,
+                binary(BinaryOp::Add, number(1.0), number(2.0)),
+                number(3.0)
+            )
+        );
+        assert_eq!(
+            expression("@z <= 1 && !true || false"),
+            binary(
+                BinaryOp::Or,
+                binary(
+                    BinaryOp::And,
+                    binary(BinaryOp::Le, ExprKind::Coordinate(Axis::Z), number(1.0)),
+                    ExprKind::Unary(UnaryOp::Not, Box::new(bare(ExprKind::Bool(true))))
+                ),
+                ExprKind::Bool(false)
+            )
+        );
+        assert_eq!(
+            expression("-a.abs()"),
+            ExprKind::Unary(
+                UnaryOp::Neg,
+                Box::new(bare(call("abs", vec![variable("a")])))
+            )
+        );
+    }
+
+    #[test]
+    fn compound_assignments() {
+        let program =
+            parse("f: field<float, 3> |> { a = 1; a += 1; a -= 1; a *= 2; a /= 2; a }").unwrap();
+        let Stage::Block(block) = &program.declarations[0].stages[0] else {
+            panic!("expected a block");
+        };
+        let operators: Vec<_> = block.statements.iter().map(|s| s.operator).collect();
+        assert_eq!(
+            operators,
+            [
+                None,
+                Some(BinaryOp::Add),
+                Some(BinaryOp::Sub),
+                Some(BinaryOp::Mul),
+                Some(BinaryOp::Div)
+            ]
+        );
+        assert_eq!(program.declarations[0].ty.dimension, 3);
+    }
+
+    #[test]
+    fn line_breaks_inside_parentheses() {
+        assert_eq!(
+            expression("f(\n1,\n2\n)"),
+            call("f", vec![number(1.0), number(2.0)])
+        );
+        assert_eq!(
+            expression("(1\n+ 2)"),
+            binary(BinaryOp::Add, number(1.0), number(2.0))
+        );
+    }
+
+    #[test]
+    fn method_chain_across_lines() {
+        assert_eq!(
+            expression("a\n.abs()\n.sqrt()"),
+            call("sqrt", vec![call("abs", vec![variable("a")])])
+        );
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(
+            error("height: field<float, 2>\n|> {\n    n = \n}"),
+            "4:1: expected an expression, found `}`"
+        );
+        assert_eq!(
+            error("height: field<float, 4> |> { 1 }"),
+            "1:22: field dimension must be 2 or 3"
+        );
+        assert_eq!(
+            error("height: field<int, 2> |> { 1 }"),
+            "1:15: unknown element type `int`, expected `float`, `bool` or `vec3`"
+        );
+        assert_eq!(
+            error("height: grid<float, 2> |> { 1 }"),
+            "1:9: unknown type `grid`, expected `field`"
+        );
+        assert_eq!(
+            error("height: field<float, 2>\nfoo"),
+            "2:1: expected `|>` followed by a pipeline stage, found `foo`"
+        );
+        assert_eq!(
+            error("height: field<float, 2> |> {\n  1\n  2\n}"),
+            "2:3: expression result is unused, only the last expression of a block is its value"
+        );
+        assert_eq!(
+            error("height: field<float, 2> |> {\n  n = 1\n}"),
+            "1:28: block must end with an expression"
+        );
+        assert_eq!(
+            error("height: field<float, 2> |> { n = 1 n }"),
+            "1:36: expected `;` or a line break after the statement, found `n`"
+        );
+        assert_eq!(
+            error("a: field<float, 2> |> { 1 }\na: field<float, 2> |> { 2 }"),
+            "2:1: `a` is already declared on line 1"
+        );
+        assert_eq!(
+            error("a: field<float, 2> |> { v.w }"),
+            "1:27: unknown component `w`, expected `x`, `y`, `z` or a method call"
+        );
+        assert_eq!(
+            error("a: field<float, 2> |> { f(1, 2 }"),
+            "1:32: expected `)` to close the argument list, found `}`"
+        );
+    }
+}
@@ -0,0 +1,156 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::SystemTime,
+};
+
+use super::{analysis, compile, ir::Module};
+use crate::world::HEIGHT;
+
+/// A terrain program on disk, recompiled whenever the file is modified.
+pub struct ProgramFile {
+    path: PathBuf,
+    modified: Option<SystemTime>,
+    /// Why the latest version of the file could not be loaded.
+    pub error: Option<String>,
+    /// Analysis warnings of the loaded program.
+    pub warnings: Vec<String>,
+}
+
+impl ProgramFile {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self {
+            path: path.into(),
+            modified: None,
+            error: None,
+            warnings: Vec::new(),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Recompile the program if the file changed since the last poll.
+    /// Returns the new program, unless it is unchanged or fails to compile.
+    pub fn poll(&mut self) -> Option<Arc<Module>> {
+        let modified = match std::fs::metadata(&self.path).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(error) => {
+                self.error = Some(format!("cannot read {}: {error}", self.path.display()));
+                self.modified = None;
+                return None;
+            }
+        };
+        if self.modified == Some(modified) {
+            return None;
+        }
+        self.modified = Some(modified);
+
+        let source = match std::fs::read_to_string(&self.path) {
+            Ok(source) => source,
+            Err(error) => {
+                self.error = Some(format!("cannot read {}: {error}", self.path.display()));
+                return None;
+            }
+        };
+
+        match load(&source) {
+            Ok((module, warnings)) => {
+                self.error = None;
+                self.warnings = warnings;
+                Some(Arc::new(module))
+            }
+            Err(error) => {
+                // Warnings of the previous source would point at the wrong lines.
+                self.error = Some(error);
+                self.warnings.clear();
+                None
+            }
+        }
+    }
+}
+
+/// Compile a program which chunks can be generated from, along with its rendered warnings.
+pub fn load(source: &str) -> Result<(Module, Vec<String>), String> {
+    let module = compile(source).map_err(|diagnostic| diagnostic.render(source))?;
+    module
+        .float_field(HEIGHT, 2)
+        .map_err(|error| error.to_string())?;
+    let warnings = analysis::analyze(&module)
+        .warnings
+        .iter()
+        .map(|warning| warning.render(source))
+        .collect();
+    Ok((module, warnings))
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs::File, io::Write, time::Duration};
+
+    use super::*;
+
+    fn write(path: &Path, source: &str, modified: SystemTime) {
+        let mut file = File::create(path).unwrap();
+        file.write_all(source.as_bytes()).unwrap();
+        file.set_modified(modified).unwrap();
+    }
+
+    #[test]
+    fn reloads_on_change() {
+        let path = std::env::temp_dir().join(format!("endless-watch-{}", std::process::id()));
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        let mut program = ProgramFile::new(&path);
+
+        assert!(program.poll().is_none());
+        assert!(program.error.as_ref().unwrap().starts_with("cannot read"));
+
+        write(&path, "height: field<float, 2> |> { @x }", time);
+        let module = program.poll().unwrap();
+        assert_eq!(module.fields[0].name, "height");
+        assert_eq!(program.error, None);
+        assert!(program.poll().is_none());
+
+        write(
+            &path,
+            "height: field<float, 2> |> { @x + }",
+            time + Duration::from_secs(1),
+        );
+        assert!(program.poll().is_none());
+        assert!(program.error.as_ref().unwrap().starts_with("1:35: "));
+        assert!(program.poll().is_none());
+
+        write(
+            &path,
+            "height: field<float, 2> |> { 1 / @x }",
+            time + Duration::from_secs(2),
+        );
+        assert!(program.poll().is_some());
+        assert_eq!(program.error, None);
+        assert_eq!(program.warnings.len(), 1);
+
+        write(
+            &path,
+            "height: field<float, 2> |> { 1 / }",
+            time + Duration::from_secs(3),
+        );
+        assert!(program.poll().is_none());
+        assert!(program.error.is_some());
+        assert!(program.warnings.is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn requires_height() {
+        assert_eq!(
+            load("depth: field<float, 2> |> { @x }").err().unwrap(),
+            "the program does not declare `height`"
+        );
+        assert_eq!(
+            load("height: field<float, 3> |> { @z }").err().unwrap(),
+            "`height` must be a field<float, 2>, but is a field<float, 3>"
+        );
+    }
+}
@@ -0,0 +1,305 @@
+use std::fmt::Write;
+
+use super::{
+    ast::{Axis, Element, UnaryOp},
+    ir::{FieldDef, Kernel, Module, Node, NodeKind, Stage, Value, BLUR_SIGMA},
+};
+use crate::field::gaussian_kernel;
+
+/// A compute shader for one pass of a pipeline stage.
+///
+/// Every value is stored as a `vec4<f32>`: floats and bools (as 0 or 1) in `x`, vectors in `xyz`.
+/// Bindings are `params` at 0, the output at 1, the stage input at 2 if [`Self::input`] is set,
+/// followed by the fields listed in [`Self::fields`].
+#[derive(Debug, Clone)]
+pub struct Shader {
+    pub source: String,
+    pub dimension: usize,
+    pub input: bool,
+    pub fields: Vec<usize>,
+}
+
+impl Shader {
+    pub fn workgroup_size(&self) -> [u32; 3] {
+        match self.dimension {
+            2 => [8, 8, 1],
+            _ => [4, 4, 4],
+        }
+    }
+}
+
+/// Lower a stage of a field to compute shaders, one per pass.
+pub fn stage(module: &Module, field: usize, stage: usize) -> Vec<Shader> {
+    let def = &module.fields[field];
+    match &def.stages[stage] {
+        Stage::Kernel(kernel) => vec![self::kernel(module, def, kernel)],
+        Stage::Normal => vec![normal()],
+        Stage::Blur => vec![blur(Axis::X), blur(Axis::Y)],
+    }
+}
+
+const PRELUDE: &str = "struct Params {
+    offset: vec4<i32>,
+    lod: u32,
+    extent: u32,
+}
+
+@group(0) @binding(0) var<uniform> params: Params;
+@group(0) @binding(1) var<storage, read_write> output: array<vec4<f32>>;
+
+fn index2(c: vec2<u32>) -> u32 {
+    return c.x * params.extent + c.y;
+}
+
+fn index3(c: vec3<u32>) -> u32 {
+    return (c.x * params.extent + c.y) * params.extent + c.z;
+}
+";
+
+fn source(body: &str) -> String {
+    [
+        PRELUDE,
+        body,
+        include_str!("../renderer/shaders/util.wgsl"),
+        include_str!("builtins.wgsl"),
+    ]
+    .join("\n")
+}
+
+fn kernel(module: &Module, def: &FieldDef, kernel: &Kernel) -> Shader {
+    let mut input = false;
+    let mut fields = Vec::new();
+    kernel.visit(&mut |node| match node.kind {
+        NodeKind::Input => input = true,
+        NodeKind::Field(index) if !fields.contains(&index) => fields.push(index),
+        _ => {}
+    });
+
+    let generator = Generator {
+        module,
+        dimension: def.dimension,
+    };
+
+    let mut body = String::new();
+    if input {
+        body += "@group(0) @binding(2) var<storage, read> input: array<vec4<f32>>;\n";
+    }
+    for (i, field) in fields.iter().enumerate() {
+        writeln!(
+            body,
+            "@group(0) @binding({}) var<storage, read> field_{field}: array<vec4<f32>>;",
+            3 + i
+        )
+        .unwrap();
+    }
+
+    let (workgroup_size, bounds, index) = match def.dimension {
+        2 => (
+            "8, 8, 1",
+            "id.x >= params.extent || id.y >= params.extent || id.z > 0u",
+            "index2(id.xy)",
+        ),
+        _ => ("4, 4, 4", "any(id >= vec3(params.extent))", "index3(id)"),
+    };
+    let z = match def.dimension {
+        2 => "f32(params.offset.z)",
+        _ => "f32(id.z << params.lod) + f32(params.offset.z)",
+    };
+
+    writeln!(
+        body,
+        "
+@compute @workgroup_size({workgroup_size})
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {{
+    if {bounds} {{
+        return;
+    }}
+    let i = {index};
+    let position = vec3(
+        f32(id.x << params.lod) + f32(params.offset.x),
+        f32(id.y << params.lod) + f32(params.offset.y),
+        {z},
+    );"
+    )
+    .unwrap();
+
+    // Locals may be reassigned, so only the first assignment declares the variable.
+    let mut declared = vec![false; kernel.locals];
+    for statement in &kernel.statements {
+        let keyword = if declared[statement.local] {
+            ""
+        } else {
+            "var "
+        };
+        declared[statement.local] = true;
+        writeln!(
+            body,
+            "    {keyword}l{} = {};",
+            statement.local,
+            generator.expr(&statement.value)
+        )
+        .unwrap();
+    }
+
+    writeln!(
+        body,
+        "    output[i] = {};\n}}",
+        encode(&generator.expr(&kernel.value), kernel.value.ty)
+    )
+    .unwrap();
+
+    Shader {
+        source: source(&body),
+        dimension: def.dimension,
+        input,
+        fields,
+    }
+}
+
+/// See [`crate::field::Field::normal`].
+fn normal() -> Shader {
+    let body = "@group(0) @binding(2) var<storage, read> input: array<vec4<f32>>;
+
+@compute @workgroup_size(8, 8, 1)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {
+    let e = params.extent;
+    if id.x >= e || id.y >= e || id.z > 0u {
+        return;
+    }
+    let x = id.x;
+    let y = id.y;
+    let x0 = max(x, 1u) - 1u;
+    let x1 = min(x + 1u, e - 1u);
+    let y0 = max(y, 1u) - 1u;
+    let y1 = min(y + 1u, e - 1u);
+    let dx = (input[index2(vec2(x1, y))].x - input[index2(vec2(x0, y))].x) / f32(x1 - x0);
+    let dy = (input[index2(vec2(x, y1))].x - input[index2(vec2(x, y0))].x) / f32(y1 - y0);
+    output[index2(id.xy)] = vec4(normalize(vec3(-dx, -dy, 1.0)), 0.0);
+}
+";
+    Shader {
+        source: source(body),
+        dimension: 2,
+        input: true,
+        fields: Vec::new(),
+    }
+}
+
+/// One separable pass of [`crate::field::Field::blur`].
+fn blur(axis: Axis) -> Shader {
+    let weights = gaussian_kernel(BLUR_SIGMA);
+    let radius = weights.len() / 2;
+    let weights = weights
+        .iter()
+        .map(|w| float(*w))
+        .collect::<Vec<_>>()
+        .join(", ");
+    let count = 2 * radius + 1;
+    let tap = match axis {
+        Axis::X => "vec2(u32(t), id.y)",
+        _ => "vec2(id.x, u32(t))",
+    };
+    let coordinate = match axis {
+        Axis::X => "id.x",
+        _ => "id.y",
+    };
+
+    let body = format!(
+        "@group(0) @binding(2) var<storage, read> input: array<vec4<f32>>;
+
+@compute @workgroup_size(8, 8, 1)
+fn main(@builtin(global_invocation_id) id: vec3<u32>) {{
+    let e = params.extent;
+    if id.x >= e || id.y >= e || id.z > 0u {{
+        return;
+    }}
+    var weights = array<f32, {count}>({weights});
+    var acc = 0.0;
+    for (var i = 0; i < {count}; i = i + 1) {{
+        let t = clamp(i32({coordinate}) + i - {radius}, 0, i32(e) - 1);
+        acc = acc + input[index2({tap})].x * weights[i];
+    }}
+    output[index2(id.xy)] = vec4(acc, 0.0, 0.0, 0.0);
+}}
+"
+    );
+    Shader {
+        source: source(&body),
+        dimension: 2,
+        input: true,
+        fields: Vec::new(),
+    }
+}
+
+struct Generator<'a> {
+    module: &'a Module,
+    dimension: usize,
+}
+
+impl Generator<'_> {
+    fn expr(&self, node: &Node) -> String {
+        match &node.kind {
+            NodeKind::Constant(Value::Float(x)) => float(*x),
+            NodeKind::Constant(Value::Bool(b)) => b.to_string(),
+            NodeKind::Constant(Value::Vec3(v)) => {
+                format!("vec3({}, {}, {})", float(v.x), float(v.y), float(v.z))
+            }
+            NodeKind::Local(slot) => format!("l{slot}"),
+            NodeKind::Field(index) => {
+                let dimension = self.module.fields[*index].dimension;
+                let i = match (dimension, self.dimension) {
+                    (2, 3) => "index2(id.xy)",
+                    _ => "i",
+                };
+                decode(&format!("field_{index}[{i}]"), node.ty)
+            }
+            NodeKind::Coordinate(axis) => format!("position.{}", axis.name()),
+            NodeKind::Input => decode("input[i]", node.ty),
+            NodeKind::Component(inner, axis) => format!("{}.{}", self.expr(inner), axis.name()),
+            NodeKind::Unary(op, operand) => {
+                let op = match op {
+                    UnaryOp::Neg => "-",
+                    UnaryOp::Not => "!",
+                };
+                format!("({op}{})", self.expr(operand))
+            }
+            // Like Rust, WGSL's `%` on floats truncates towards zero.
+            NodeKind::Binary(op, lhs, rhs) => {
+                format!("({} {} {})", self.expr(lhs), op.symbol(), self.expr(rhs))
+            }
+            NodeKind::Call(builtin, args) => {
+                let args = args
+                    .iter()
+                    .map(|arg| self.expr(arg))
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("builtin_{}({args})", builtin.name())
+            }
+        }
+    }
+}
+
+fn decode(value: &str, ty: Element) -> String {
+    match ty {
+        Element::Float => format!("{value}.x"),
+        Element::Bool => format!("({value}.x != 0.0)"),
+        Element::Vec3 => format!("{value}.xyz"),
+    }
+}
+
+fn encode(value: &str, ty: Element) -> String {
+    match ty {
+        Element::Float => format!("vec4({value}, 0.0, 0.0, 0.0)"),
+        Element::Bool => format!("vec4(select(0.0, 1.0, {value}), 0.0, 0.0, 0.0)"),
+        Element::Vec3 => format!("vec4({value}, 0.0)"),
+    }
+}
+
+/// A WGSL `f32` literal reproducing `x` exactly.
+fn float(x: f32) -> String {
+    if x.is_finite() {
+        format!("{x:?}f")
+    } else {
+        format!("bitcast<f32>({:#010X}u)", x.to_bits())
+    }
+}
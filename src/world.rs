@@ -1,104 +1,751 @@
+pub mod cache;
+pub mod generator;
+pub mod material;
+
 use std::collections::HashMap;
 
 use cgmath::{vec3, Vector3};
-use noise::NoiseFn;
 
-use crate::{field::Field, renderer::voxels::VoxelMesh, util::rescale};
+use crate::{
+    field::{
+        self,
+        bitmask::{Bitmask, Faces},
+        Env, Field,
+    },
+    renderer::voxels::{
+        self, surface_nets, BlockyMeshing, ChunkMesh, MeshOptions, PackedMesh, VoxelMesh,
+    },
+    scheduler::{CancellationToken, Cancelled},
+};
+use cache::ChunkCache;
+use generator::TerrainGenerator;
+use material::{Material, Rules};
 
 pub const K: usize = 6;
 pub const N: usize = 1 << K;
 
-#[derive(Default)]
+/// Name of the terrain program declaration which [`generator::Program`] evaluates.
+pub const HEIGHT: &str = "height";
+
+/// Voxels by which chunk densities extend beyond each side of the chunk, so that faces
+/// between solid voxels of adjacent chunks are culled.
+pub const APRON: usize = 1;
+
+/// Extent of the density of a chunk with the given LOD, including the apron.
+pub fn padded_extent(lod: usize) -> usize {
+    (N >> lod) + 2 * APRON
+}
+
+/// World-space position of the first voxel of a chunk field which includes the apron.
+pub fn padded_origin(key: Vector3<isize>, lod: usize) -> Vector3<isize> {
+    N as isize * key - vec3(1, 1, 1) * (APRON << lod) as isize
+}
+
+/// World-space position of a voxel of a chunk field which includes the apron.
+pub fn padded_position(key: Vector3<isize>, lod: usize, c: [usize; 3]) -> Vector3<f32> {
+    sample_position(padded_origin(key, lod), lod, c)
+}
+
+/// World-space position of a voxel of a field whose first voxel lies at `origin`,
+/// with the voxel spacing of the given LOD.
+pub fn sample_position(origin: Vector3<isize>, lod: usize, [i, j, k]: [usize; 3]) -> Vector3<f32> {
+    vec3(
+        (i << lod) as f32 + origin.x as f32,
+        (j << lod) as f32 + origin.y as f32,
+        (k << lod) as f32 + origin.z as f32,
+    )
+}
+
+/// LODs of the chunks adjacent to the sides of a chunk, in the order -x, +x, -y, +y, -z, +z.
+/// Sides without a chunk take the LOD of the chunk itself.
+pub type Neighbours = [usize; 6];
+
+/// Keys of the chunks adjacent to the given one, in the order of [`Neighbours`].
+pub fn neighbour_keys(key: Vector3<isize>) -> [Vector3<isize>; 6] {
+    [
+        vec3(-1, 0, 0),
+        vec3(1, 0, 0),
+        vec3(0, -1, 0),
+        vec3(0, 1, 0),
+        vec3(0, 0, -1),
+        vec3(0, 0, 1),
+    ]
+    .map(|offset| key + offset)
+}
+
+/// Depth of the skirts at seams between chunks of different LODs, in voxels of the coarser chunk.
+pub const SKIRT_DEPTH: usize = 2;
+
+/// Depth of the skirt at each side of a chunk in its own voxels,
+/// zero where the neighbour has the same LOD.
+pub fn skirts(lod: usize, neighbours: Neighbours) -> [usize; 6] {
+    neighbours.map(|neighbour| {
+        if neighbour == lod {
+            0
+        } else {
+            SKIRT_DEPTH << neighbour.saturating_sub(lod)
+        }
+    })
+}
+
+/// Share of the distance between LOD boundaries by which a chunk has to pass a boundary
+/// before its LOD changes, so that moving back and forth does not regenerate it every time.
+pub const LOD_HYSTERESIS: f32 = 0.25;
+
+/// LOD of a chunk at the given distance from the camera in chunks, where the LOD increases
+/// every `1 << shift` chunks. The current LOD of the chunk is kept within
+/// [`LOD_HYSTERESIS`] of its range.
+pub fn lod(distance: f32, shift: usize, current: Option<usize>) -> usize {
+    let width = (1 << shift) as f32;
+    let lod = (distance / width) as usize;
+    match current {
+        Some(current) if current != lod => {
+            let margin = LOD_HYSTERESIS * width;
+            let range = current as f32 * width - margin..(current + 1) as f32 * width + margin;
+            if range.contains(&distance) {
+                current
+            } else {
+                lod
+            }
+        }
+        _ => lod,
+    }
+}
+
+/// Memory which the chunks take by default, see [`World::budget`].
+pub const DEFAULT_BUDGET: u64 = 1 << 30;
+
+/// Chunks which are shown, and chunks which are kept in case they are needed again while
+/// their memory fits into the budget.
 pub struct World {
-    pub chunks: HashMap<Vector3<isize>, Chunk>,
+    chunks: HashMap<Vector3<isize>, Chunk>,
+    cache: ChunkCache,
+    /// Memory of the shown chunks, see [`Chunk::size`].
+    size: u64,
+    budget: u64,
+}
+
+impl Default for World {
+    fn default() -> Self {
+        Self {
+            chunks: HashMap::new(),
+            cache: ChunkCache::default(),
+            size: 0,
+            budget: DEFAULT_BUDGET,
+        }
+    }
+}
+
+impl World {
+    pub fn chunks(&self) -> &HashMap<Vector3<isize>, Chunk> {
+        &self.chunks
+    }
+
+    pub fn cache(&self) -> &ChunkCache {
+        &self.cache
+    }
+
+    /// Show a chunk, and cache the one it replaces.
+    pub fn insert(&mut self, key: Vector3<isize>, chunk: Chunk) {
+        self.size += chunk.size();
+        if let Some(replaced) = self.chunks.insert(key, chunk) {
+            self.size -= replaced.size();
+            self.cache.insert(key, replaced);
+        }
+        self.evict();
+    }
+
+    /// Show the cached chunk of the key which was built with the given LOD, neighbours
+    /// and options, if there is one.
+    pub fn restore(
+        &mut self,
+        key: Vector3<isize>,
+        lod: usize,
+        neighbours: Neighbours,
+        options: MeshOptions,
+    ) -> bool {
+        match self.cache.take(key, lod, neighbours, options) {
+            Some(chunk) => {
+                self.insert(key, chunk);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Stop showing the chunks of the keys for which the predicate does not hold,
+    /// and cache them.
+    pub fn retain(&mut self, mut f: impl FnMut(Vector3<isize>) -> bool) {
+        let removed: Vec<_> = self.chunks.keys().copied().filter(|&key| !f(key)).collect();
+        for key in removed {
+            let chunk = self.chunks.remove(&key).unwrap();
+            self.size -= chunk.size();
+            self.cache.insert(key, chunk);
+        }
+        self.evict();
+    }
+
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.cache.clear();
+        self.size = 0;
+    }
+
+    /// Memory of the shown and the cached chunks.
+    pub fn size(&self) -> u64 {
+        self.size + self.cache.size()
+    }
+
+    /// Memory in bytes which the chunks may take before the least recently shown of the
+    /// cached ones are dropped. Shown chunks are kept regardless.
+    pub fn budget(&self) -> u64 {
+        self.budget
+    }
+
+    pub fn set_budget(&mut self, budget: u64) {
+        self.budget = budget;
+        self.evict();
+    }
+
+    fn evict(&mut self) {
+        self.cache.evict(self.budget.saturating_sub(self.size));
+    }
 }
 
 pub struct Chunk {
     pub lod: usize,
-    pub voxel_mesh: VoxelMesh,
+    pub neighbours: Neighbours,
+    pub options: MeshOptions,
+    /// Visible voxel faces, see [`Faces`].
+    pub faces: usize,
+    /// See [`ChunkData::surface`].
+    pub surface: [bool; 6],
+    pub contents: Contents<VoxelMesh>,
 }
 
-impl Chunk {
-    pub fn new(key: Vector3<isize>, lod: usize, device: &wgpu::Device) -> Self {
+/// What a chunk holds. Chunks whose voxels including the apron are all empty or all solid
+/// have no surface, and hold neither voxels nor a mesh.
+#[derive(Debug)]
+pub enum Contents<T> {
+    Empty,
+    Full,
+    Mixed(T),
+}
+
+impl<T> Contents<T> {
+    fn uniform(solid: bool) -> Self {
+        if solid {
+            Contents::Full
+        } else {
+            Contents::Empty
+        }
+    }
+
+    pub fn mixed(&self) -> Option<&T> {
+        match self {
+            Contents::Mixed(contents) => Some(contents),
+            _ => None,
+        }
+    }
+
+    pub fn is_uniform(&self) -> bool {
+        !matches!(self, Contents::Mixed(_))
+    }
+}
+
+/// Voxels of a chunk which is neither empty nor full.
+pub struct Voxels {
+    /// Density including the apron, see [`APRON`].
+    pub density: Field<f32, 3>,
+    /// Solid voxels including the apron.
+    pub mask: Field<bool, 3>,
+    /// Materials including the apron, see [`material::materials`].
+    pub materials: Field<Material, 3>,
+    /// Mesh in voxel coordinates, see [`voxels::mesh`] and [`surface_nets::mesh`].
+    pub mesh: ChunkMesh,
+}
+
+/// A generated chunk before it is uploaded to the GPU.
+pub struct ChunkData {
+    pub key: Vector3<isize>,
+    pub lod: usize,
+    /// LODs of the adjacent chunks, which get a skirt towards them if they differ.
+    pub neighbours: Neighbours,
+    pub options: MeshOptions,
+    pub faces: usize,
+    /// Whether the surface may continue into the adjacent chunk at each side,
+    /// in the order of [`Neighbours`], see [`surface_continues`].
+    pub surface: [bool; 6],
+    pub contents: Contents<Voxels>,
+}
+
+impl ChunkData {
+    /// Generate a chunk, unless the token is cancelled before it is done.
+    pub fn new(
+        key: Vector3<isize>,
+        lod: usize,
+        neighbours: Neighbours,
+        generator: &dyn TerrainGenerator,
+        rules: &Rules,
+        options: MeshOptions,
+        token: &CancellationToken,
+    ) -> Result<Self, Cancelled> {
         puffin::profile_function!();
 
-        let noise = {
-            use noise::{Fbm, Perlin, Turbulence};
-            let mut noise = Fbm::<Perlin>::new(0);
-            noise.frequency = 0.01;
-            Turbulence::<_, Perlin>::new(noise)
+        // Coarser LODs are downsampled from the voxels of LOD 0 which they cover.
+        let downsample = options.downsample.filter(|_| lod > 0);
+        let (sample_lod, extent) = match downsample {
+            Some(_) => (0, padded_extent(lod) << lod),
+            None => (lod, padded_extent(lod)),
         };
+        let origin = padded_origin(key, lod);
 
-        let extent = N >> lod;
-        let scale = 1 << lod;
-
-        let offset = N as isize * key.cast().unwrap();
-
-        let height = {
-            puffin::profile_scope!("Height");
-            Field::new(extent, |[i, j]| {
-                // Compute world-space coordinates
-                let [x, y, z] = [
-                    (i << lod) as f32 + offset.x as f32,
-                    (j << lod) as f32 + offset.y as f32,
-                    offset.z as f32,
-                ];
-
-                let mut n = noise.get([x as f64, y as f64]) as f32;
-                n = rescale(n, -1.0..1.0, -0.2..1.0);
-                n = n.abs().powf(1.2).copysign(n);
-                n *= 50.0;
-                n -= z;
-
-                n
-            })
+        let uniform = |solid: bool| Self {
+            key,
+            lod,
+            neighbours,
+            options,
+            faces: 0,
+            surface: std::array::from_fn(|side| match side {
+                4 => !solid,
+                5 => solid,
+                _ => false,
+            }),
+            contents: Contents::uniform(solid),
         };
 
-        let mask = {
+        let mut density = {
+            puffin::profile_scope!("Density");
+            match generator.contents(origin, sample_lod, extent) {
+                Contents::Empty => return Ok(uniform(false)),
+                Contents::Full => return Ok(uniform(true)),
+                Contents::Mixed(density) => density,
+            }
+        };
+        token.check()?;
+        let mut mask = {
             puffin::profile_scope!("Mask");
-            Field::new(extent, |[i, j, k]| {
-                // Compute world-space coordinates
-                let [_x, _y, z] = [
-                    (i << lod) as f32 + offset.x as f32,
-                    (j << lod) as f32 + offset.y as f32,
-                    (k << lod) as f32 + offset.z as f32,
-                ];
-
-                z <= height[[i, j]]
-            })
+            density.map(|d| d >= 0.0)
         };
+        if let Some(policy) = downsample {
+            // Each level of the mask pyramid is reduced from the finer mask, so that
+            // silhouettes do not change between LODs beyond what the policy allows.
+            puffin::profile_scope!("Downsample");
+            for _ in 0..lod {
+                token.check()?;
+                density = density.downsample(policy);
+                mask = mask.downsample(policy);
+            }
+        }
+
+        // The generator may not know about all uniform chunks.
+        let solid = mask[[0; 3]];
+        if mask.coordinates().all(|c| mask[c] == solid) {
+            return Ok(uniform(solid));
+        }
 
-        let env = {
-            puffin::profile_scope!("Env");
-            mask.environment()
+        let materials = rules.materials(origin, lod, &density);
+        token.check()?;
+
+        let skirts = skirts(lod, neighbours);
+        let faces = {
+            puffin::profile_scope!("Faces");
+            let mut bitmask = Bitmask::padded(&mask);
+            for (side, &depth) in skirts.iter().enumerate() {
+                if depth > 0 {
+                    bitmask.erode_apron(side, depth);
+                }
+            }
+            bitmask.faces()
         };
-        let shell = {
-            puffin::profile_scope!("Shell");
-            mask.shell(&env)
+        token.check()?;
+        let mesh = match options.meshing.blocky() {
+            Some(meshing) => ChunkMesh::Blocky(blocky_mesh(
+                &mask,
+                &materials,
+                &faces,
+                meshing,
+                options.ambient_occlusion,
+                token,
+            )?),
+            None => ChunkMesh::Smooth(surface_nets::mesh(&density, &materials, skirts)),
         };
-        let vis = {
-            puffin::profile_scope!("Visibility");
-            env.visibility()
+
+        Ok(Self {
+            key,
+            lod,
+            neighbours,
+            options,
+            faces: faces.count(),
+            surface: surface_continues(&mask),
+            contents: Contents::Mixed(Voxels {
+                density,
+                mask,
+                materials,
+                mesh,
+            }),
+        })
+    }
+}
+
+/// Whether the surface may continue into the adjacent chunk at each side, in the order of
+/// [`Neighbours`], judging by the apron layer at that side. Across the horizontal sides
+/// this is the case where the layer holds both solid and empty voxels. Below, it is the
+/// case where air reaches the layer, and above where solid voxels do, since the terrain
+/// may only end further away.
+pub fn surface_continues(mask: &Field<bool, 3>) -> [bool; 6] {
+    let last = mask.extent() - 1;
+    std::array::from_fn(|side| {
+        let axis = side / 2;
+        let (solid, empty) =
+            field::coordinates(mask.extent()).fold((false, false), |(solid, empty), [u, v]| {
+                let mut c = [0; 3];
+                c[axis] = if side % 2 == 1 { last } else { 0 };
+                c[(axis + 1) % 3] = u;
+                c[(axis + 2) % 3] = v;
+                (solid || mask[c], empty || !mask[c])
+            });
+        match side {
+            4 => empty,
+            5 => solid,
+            _ => solid && empty,
+        }
+    })
+}
+
+/// Voxel faces of a chunk, given its mask including the apron.
+fn blocky_mesh(
+    mask: &Field<bool, 3>,
+    materials: &Field<Material, 3>,
+    faces: &Faces,
+    meshing: BlockyMeshing,
+    ambient_occlusion: bool,
+    token: &CancellationToken,
+) -> Result<PackedMesh, Cancelled> {
+    // Meshes cover the voxels without the apron.
+    let materials = {
+        puffin::profile_scope!("Materials");
+        Field::new(faces.extent(), |c| materials[c.map(|i| i + APRON)])
+    };
+
+    // Neighbourhoods of the voxels with visible faces, for ambient occlusion.
+    let env = ambient_occlusion.then(|| {
+        puffin::profile_scope!("Env");
+        Field::new(faces.extent(), |[x, y, z]| {
+            if faces.any(x, y) >> z & 1 == 1 {
+                mask.environment_at([x, y, z].map(|i| i + APRON))
+            } else {
+                Env::empty()
+            }
+        })
+    });
+    token.check()?;
+
+    let vertices = {
+        puffin::profile_scope!("Voxel Mesh");
+        voxels::mesh(faces, &materials, env.as_ref(), meshing)
+    };
+    token.check()?;
+    puffin::profile_scope!("Pack Mesh");
+    Ok(PackedMesh::new(&vertices))
+}
+
+impl Chunk {
+    /// Upload the mesh of a generated chunk.
+    pub fn new(data: &ChunkData, device: &wgpu::Device) -> Self {
+        puffin::profile_function!();
+
+        let contents = match &data.contents {
+            Contents::Empty => Contents::Empty,
+            Contents::Full => Contents::Full,
+            Contents::Mixed(voxels) => Contents::Mixed(VoxelMesh::new(
+                device,
+                &voxels.mesh,
+                N as f32 * data.key.cast().unwrap(),
+                (1 << data.lod) as f32,
+            )),
         };
 
-        let color = {
-            puffin::profile_scope!("Color");
-            vis.normals()
-                .map(|n| 0.67 * (0.5 * n + vec3(0.5, 0.5, 0.5)))
+        Self {
+            lod: data.lod,
+            neighbours: data.neighbours,
+            options: data.options,
+            faces: data.faces,
+            surface: data.surface,
+            contents,
+        }
+    }
+
+    pub fn voxel_mesh(&self) -> Option<&VoxelMesh> {
+        self.contents.mixed()
+    }
+
+    /// Memory of the chunk in bytes, which is mostly its mesh on the GPU.
+    pub fn size(&self) -> u64 {
+        std::mem::size_of::<Self>() as u64 + self.voxel_mesh().map_or(0, VoxelMesh::size)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{field::Downsample, renderer::voxels::Meshing, world::generator::Flat};
+
+    /// Chunk with the default materials, which is not cancelled.
+    fn generate(
+        key: Vector3<isize>,
+        lod: usize,
+        neighbours: Neighbours,
+        generator: &dyn TerrainGenerator,
+        options: MeshOptions,
+    ) -> ChunkData {
+        let token = CancellationToken::default();
+        ChunkData::new(
+            key,
+            lod,
+            neighbours,
+            generator,
+            &Rules::default(),
+            options,
+            &token,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn flat_chunk() {
+        let data = generate(vec3(0, 0, 0), 0, [0; 6], &Flat, MeshOptions::default());
+        let voxels = data.contents.mixed().unwrap();
+        let vertices = voxels.mesh.triangles();
+
+        // The bottom layer is solid, as is the apron below it.
+        for c in voxels.mask.coordinates() {
+            assert_eq!(voxels.mask[c], c[2] <= APRON);
+        }
+
+        // Only the top faces, since the apron hides those at the bottom and the sides.
+        assert_eq!(vertices.len(), 6 * N * N);
+        assert_eq!(data.faces, N * N);
+        assert!(vertices
+            .iter()
+            .all(|v| v.position.z == 1.0 && v.normal == vec3(0.0, 0.0, -1.0)));
+        // The plane lies at the height of the shore.
+        assert!(vertices.iter().all(|v| v.material == Material::Sand));
+        // Quads share the corners of their neighbours.
+        let ChunkMesh::Blocky(mesh) = &voxels.mesh else {
+            panic!("chunk is not blocky");
         };
+        assert_eq!(mesh.vertices.len(), (N + 1) * (N + 1));
 
-        let voxel_mesh = {
-            puffin::profile_scope!("Voxel Mesh");
-            VoxelMesh::new(
-                device,
-                &shell,
-                &vis,
-                &color,
-                N as f32 * key.cast().unwrap(),
-                scale as f32,
-            )
+        // The chunk below is solid throughout, including its apron towards this one.
+        let below = generate(vec3(0, 0, -1), 0, [0; 6], &Flat, MeshOptions::default());
+        assert!(matches!(below.contents, Contents::Full));
+        assert_eq!(below.faces, 0);
+
+        // Only the surface of this chunk needs to be generated.
+        assert_eq!(data.surface, [true, true, true, true, false, false]);
+        assert_eq!(below.surface, [false, false, false, false, false, true]);
+    }
+
+    #[test]
+    fn smooth_flat_chunk() {
+        let options = MeshOptions {
+            meshing: Meshing::Smooth,
+            ..Default::default()
         };
+        let data = generate(vec3(0, 0, 0), 0, [0; 6], &Flat, options);
+        let voxels = data.contents.mixed().unwrap();
+        let vertices = voxels.mesh.triangles();
+
+        // A plane through the centers of the bottom layer.
+        assert_eq!(vertices.len(), 6 * N * N);
+        assert!(vertices
+            .iter()
+            .all(|v| v.position.z == 0.5 && v.normal == vec3(0.0, 0.0, -1.0)));
+        assert!(matches!(voxels.mesh, ChunkMesh::Smooth(_)));
+    }
+
+    #[test]
+    fn empty_chunk() {
+        let options = MeshOptions {
+            meshing: Meshing::Greedy,
+            ambient_occlusion: true,
+            downsample: Some(Downsample::Majority),
+        };
+        let data = generate(vec3(0, 0, 1), 1, [1; 6], &Flat, options);
+        assert!(matches!(data.contents, Contents::Empty));
+        assert_eq!(data.faces, 0);
+        assert_eq!(data.surface, [false, false, false, false, true, false]);
+    }
+
+    #[test]
+    fn lod_hysteresis() {
+        // Chunks change their LOD every 4 chunks.
+        assert_eq!(lod(3.9, 2, None), 0);
+        assert_eq!(lod(4.1, 2, None), 1);
+        // But not until they are a chunk beyond the boundary.
+        assert_eq!(lod(4.9, 2, Some(0)), 0);
+        assert_eq!(lod(5.1, 2, Some(0)), 1);
+        assert_eq!(lod(7.1, 2, Some(2)), 2);
+        assert_eq!(lod(6.9, 2, Some(2)), 1);
+        // Far away from the range of the current LOD.
+        assert_eq!(lod(20.0, 2, Some(0)), 5);
+        assert_eq!(lod(0.0, 2, Some(3)), 0);
+    }
+
+    #[test]
+    fn out_of_range_chunks_are_cached() {
+        let chunk = |lod| Chunk {
+            lod,
+            neighbours: [lod; 6],
+            options: MeshOptions::default(),
+            faces: 0,
+            surface: [false; 6],
+            contents: Contents::Empty,
+        };
+        let size = chunk(0).size();
+        let mut world = World::default();
+        world.set_budget(3 * size);
+        for x in 0..3 {
+            world.insert(vec3(x, 0, 0), chunk(0));
+        }
+
+        // Chunks which leave the range are kept while they fit into the budget.
+        world.retain(|key| key.x != 1);
+        world.retain(|key| key.x == 0);
+        assert_eq!((world.chunks().len(), world.cache().len()), (1, 2));
+        assert_eq!(world.size(), 3 * size);
+
+        // Shown chunks take precedence over the least recently shown cached ones.
+        world.insert(vec3(0, 0, 0), chunk(1));
+        assert_eq!((world.chunks().len(), world.cache().len()), (1, 2));
+        assert!(!world.restore(vec3(1, 0, 0), 0, [0; 6], MeshOptions::default()));
+        assert!(world.restore(vec3(2, 0, 0), 0, [0; 6], MeshOptions::default()));
+        assert_eq!((world.chunks().len(), world.cache().len()), (2, 1));
+        assert_eq!(world.size(), 3 * size);
+
+        world.set_budget(0);
+        assert_eq!((world.chunks().len(), world.cache().len()), (2, 0));
+        assert_eq!(world.size(), 2 * size);
+    }
+
+    #[test]
+    fn cancelled_chunk() {
+        let token = CancellationToken::default();
+        token.cancel();
+        let data = ChunkData::new(
+            vec3(0, 0, 0),
+            0,
+            [0; 6],
+            &Flat,
+            &Rules::default(),
+            MeshOptions::default(),
+            &token,
+        );
+        assert!(matches!(data, Err(Cancelled)));
+    }
+
+    /// Terrain which is flat like [`Flat`], but leaves uniform chunks to be detected
+    /// from their voxels.
+    struct Plane;
+
+    impl TerrainGenerator for Plane {
+        fn name(&self) -> &str {
+            "Plane"
+        }
+
+        fn sample(&self, origin: Vector3<isize>, lod: usize, extent: usize) -> Field<f32, 3> {
+            Flat.sample(origin, lod, extent)
+        }
+    }
+
+    #[test]
+    fn uniform_chunks_without_voxels() {
+        // Uniform chunks are known in advance to the flat generator, and detected from the
+        // mask for the plane.
+        let origin = |z| padded_origin(vec3(2, -1, z), 0);
+        assert_eq!(Flat.uniform(origin(1), 0, padded_extent(0)), Some(false));
+        assert_eq!(Flat.uniform(origin(0), 0, padded_extent(0)), None);
+        assert_eq!(Plane.uniform(origin(1), 0, padded_extent(0)), None);
+
+        for generator in [&Flat as &dyn TerrainGenerator, &Plane] {
+            for z in -3..4 {
+                let data = generate(vec3(2, -1, z), 0, [0; 6], generator, Default::default());
+                match z {
+                    ..=-1 => assert!(matches!(data.contents, Contents::Full)),
+                    0 => assert!(!data.contents.is_uniform()),
+                    _ => assert!(matches!(data.contents, Contents::Empty)),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn skirt_towards_coarser_neighbour() {
+        let mut neighbours = [0; 6];
+        neighbours[0] = 1;
+        assert_eq!(skirts(0, neighbours), [2 * SKIRT_DEPTH, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            skirts(1, [0, 1, 2, 1, 1, 1]),
+            [SKIRT_DEPTH, 0, 2 * SKIRT_DEPTH, 0, 0, 0]
+        );
+
+        // The bottom layer shows its faces towards the coarser neighbour.
+        let data = generate(vec3(0, 0, 0), 0, neighbours, &Flat, MeshOptions::default());
+        assert_eq!(data.faces, N * N + N);
+        assert!(data
+            .contents
+            .mixed()
+            .unwrap()
+            .mesh
+            .triangles()
+            .iter()
+            .any(|v| v.position.x == 0.0 && v.normal == vec3(1.0, 0.0, 0.0)));
+
+        // The smooth surface extends below the plane along the seam.
+        let options = MeshOptions {
+            meshing: Meshing::Smooth,
+            ..Default::default()
+        };
+        let data = generate(vec3(0, 0, 0), 0, neighbours, &Flat, options);
+        let vertices = data.contents.mixed().unwrap().mesh.triangles();
+        assert!(vertices.len() > 6 * N * N);
+        assert!(vertices
+            .iter()
+            .filter(|v| v.position.z < 0.5)
+            .all(|v| v.position.x == 0.0 && v.position.z == -1.0));
+    }
+
+    #[test]
+    fn downsampled_lod_covers_finest_voxels() {
+        let options = MeshOptions {
+            downsample: Some(Downsample::Conservative),
+            ..Default::default()
+        };
+        let generator = generator::Heightmap::default();
+        let fine = generate(vec3(0, 0, 0), 0, [0; 6], &generator, options);
+        let coarse = generate(vec3(0, 0, 0), 2, [2; 6], &generator, options);
+        let (fine, coarse) = (
+            fine.contents.mixed().unwrap(),
+            coarse.contents.mixed().unwrap(),
+        );
+
+        assert_eq!(coarse.mask.extent(), padded_extent(2));
+        for c in crate::field::coordinates::<3>(N >> 2) {
+            let solid = crate::field::coordinates::<3>(4)
+                .any(|o| fine.mask[std::array::from_fn(|a| APRON + 4 * c[a] + o[a])]);
+            assert_eq!(coarse.mask[c.map(|i| i + APRON)], solid);
+        }
+        assert!(coarse.mask.coordinates().any(|c| coarse.mask[c]));
+    }
 
-        Self { lod, voxel_mesh }
+    #[test]
+    fn surface_continues_along_tunnel() {
+        // Solid rock with a tunnel along x.
+        let mask = Field::new(8, |[_, y, z]| !(3..5).contains(&y) || !(3..5).contains(&z));
+        assert_eq!(
+            surface_continues(&mask),
+            [true, true, false, false, false, true]
+        );
     }
 }
@@ -0,0 +1,145 @@
+//! Chunks which are no longer shown, because they were replaced by another LOD or left the
+//! generation radius, so that they can be shown again right away instead of being generated
+//! anew.
+
+use std::collections::HashMap;
+
+use cgmath::Vector3;
+
+use crate::renderer::voxels::MeshOptions;
+
+use super::{Chunk, Neighbours};
+
+#[derive(Default)]
+pub struct ChunkCache {
+    /// At most one chunk per LOD of each key, with the time it was last shown.
+    chunks: HashMap<Vector3<isize>, Vec<(Chunk, u64)>>,
+    /// Memory of the cached chunks, see [`Chunk::size`].
+    size: u64,
+    time: u64,
+}
+
+impl ChunkCache {
+    /// Keep a chunk which is no longer shown, replacing the cached one of the same LOD.
+    pub fn insert(&mut self, key: Vector3<isize>, chunk: Chunk) {
+        self.time += 1;
+        self.size += chunk.size();
+        let entries = self.chunks.entry(key).or_default();
+        if let Some(i) = entries
+            .iter()
+            .position(|(cached, _)| cached.lod == chunk.lod)
+        {
+            let (replaced, _) = entries.swap_remove(i);
+            self.size -= replaced.size();
+        }
+        entries.push((chunk, self.time));
+    }
+
+    /// Remove the cached chunk of the key which was built with the given LOD, neighbours
+    /// and options.
+    pub fn take(
+        &mut self,
+        key: Vector3<isize>,
+        lod: usize,
+        neighbours: Neighbours,
+        options: MeshOptions,
+    ) -> Option<Chunk> {
+        let entries = self.chunks.get_mut(&key)?;
+        let i = entries.iter().position(|(chunk, _)| {
+            (chunk.lod, chunk.neighbours, chunk.options) == (lod, neighbours, options)
+        })?;
+        let (chunk, _) = entries.swap_remove(i);
+        if entries.is_empty() {
+            self.chunks.remove(&key);
+        }
+        self.size -= chunk.size();
+        Some(chunk)
+    }
+
+    pub fn clear(&mut self) {
+        self.chunks.clear();
+        self.size = 0;
+    }
+
+    /// Number of cached chunks.
+    pub fn len(&self) -> usize {
+        self.chunks.values().map(Vec::len).sum()
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Drop the least recently shown chunks until the cached ones take at most the given
+    /// memory.
+    pub fn evict(&mut self, size: u64) {
+        while self.size > size {
+            let oldest = self
+                .chunks
+                .iter()
+                .flat_map(|(&key, entries)| {
+                    entries
+                        .iter()
+                        .enumerate()
+                        .map(move |(i, &(_, time))| (time, key, i))
+                })
+                .min_by_key(|&(time, ..)| time);
+            let Some((_, key, i)) = oldest else {
+                break;
+            };
+            let entries = self.chunks.get_mut(&key).unwrap();
+            let (chunk, _) = entries.swap_remove(i);
+            if entries.is_empty() {
+                self.chunks.remove(&key);
+            }
+            self.size -= chunk.size();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cgmath::vec3;
+
+    use super::*;
+    use crate::world::Contents;
+
+    fn chunk(lod: usize) -> Chunk {
+        Chunk {
+            lod,
+            neighbours: [lod; 6],
+            options: MeshOptions::default(),
+            faces: 0,
+            surface: [false; 6],
+            contents: Contents::Empty,
+        }
+    }
+
+    #[test]
+    fn least_recently_shown_are_evicted() {
+        let size = chunk(0).size();
+        let mut cache = ChunkCache::default();
+
+        let key = vec3(0, 0, 0);
+        cache.insert(key, chunk(0));
+        cache.insert(key, chunk(1));
+        // Replaces the chunk of the same LOD.
+        cache.insert(key, chunk(0));
+        assert_eq!((cache.len(), cache.size()), (2, 2 * size));
+
+        cache.insert(vec3(1, 0, 0), chunk(2));
+        cache.insert(vec3(2, 0, 0), chunk(2));
+        cache.evict(3 * size);
+        assert_eq!((cache.len(), cache.size()), (3, 3 * size));
+        assert!(cache.take(key, 1, [1; 6], MeshOptions::default()).is_none());
+
+        // Only chunks built for the same neighbours are taken.
+        assert!(cache.take(key, 0, [1; 6], MeshOptions::default()).is_none());
+        let taken = cache.take(key, 0, [0; 6], MeshOptions::default()).unwrap();
+        assert_eq!(taken.lod, 0);
+        assert_eq!((cache.len(), cache.size()), (2, 2 * size));
+
+        cache.evict(0);
+        assert_eq!((cache.len(), cache.size()), (0, 0));
+    }
+}
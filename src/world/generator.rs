@@ -0,0 +1,426 @@
+use std::sync::Arc;
+
+use cgmath::{vec2, Vector3};
+use noise::{Fbm, MultiFractal, NoiseFn, Perlin, Turbulence};
+
+use super::{padded_extent, padded_origin, sample_position, Contents, HEIGHT};
+use crate::{
+    field::Field,
+    terrain_lang::ir::Module,
+    util::{self, rescale},
+};
+
+/// Produces the terrain of a chunk, shared by all chunk workers.
+pub trait TerrainGenerator: Send + Sync {
+    /// Shown in the inspector.
+    fn name(&self) -> &str;
+
+    /// Density of a cube of voxels with the given extent, whose positions are given by
+    /// [`sample_position`]. Voxels with a non-negative density are solid.
+    fn sample(&self, origin: Vector3<isize>, lod: usize, extent: usize) -> Field<f32, 3>;
+
+    /// Whether the voxels of a cube passed to [`sample`](Self::sample) are known to be
+    /// all solid, `Some(true)`, or all empty, `Some(false)`, without sampling each of them.
+    /// Such chunks are neither sampled nor meshed.
+    fn uniform(&self, _origin: Vector3<isize>, _lod: usize, _extent: usize) -> Option<bool> {
+        None
+    }
+
+    /// Density of a cube like [`sample`](Self::sample), unless it is known to be uniform.
+    /// Generators which need to sample a height field to tell override this, so that the
+    /// height field is sampled only once.
+    fn contents(
+        &self,
+        origin: Vector3<isize>,
+        lod: usize,
+        extent: usize,
+    ) -> Contents<Field<f32, 3>> {
+        match self.uniform(origin, lod, extent) {
+            Some(solid) => Contents::uniform(solid),
+            None => Contents::Mixed(self.sample(origin, lod, extent)),
+        }
+    }
+
+    /// Density of every voxel of the chunk with the given key and LOD, including the
+    /// apron, see [`padded_position`](super::padded_position).
+    fn density(&self, key: Vector3<isize>, lod: usize) -> Field<f32, 3> {
+        self.sample(padded_origin(key, lod), lod, padded_extent(lod))
+    }
+}
+
+/// Density of terrain below a height field, i.e. the height above each voxel.
+pub fn density_from_height(
+    origin: Vector3<isize>,
+    lod: usize,
+    height: &Field<f32, 2>,
+) -> Field<f32, 3> {
+    Field::new(height.extent(), |[i, j, k]| {
+        height[[i, j]] - sample_position(origin, lod, [i, j, k]).z
+    })
+}
+
+/// Whether the terrain below a height field is all solid or all empty throughout the cube
+/// of the height field, see [`TerrainGenerator::uniform`]. The terrain may stray from the
+/// height by up to `margin`.
+pub fn uniform_below_height(
+    origin: Vector3<isize>,
+    lod: usize,
+    height: &Field<f32, 2>,
+    margin: f32,
+) -> Option<bool> {
+    let bottom = sample_position(origin, lod, [0, 0, 0]).z;
+    let top = sample_position(origin, lod, [0, 0, height.extent() - 1]).z;
+    let (min, max) = height
+        .coordinates()
+        .map(|c| height[c])
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), h| {
+            (min.min(h), max.max(h))
+        });
+    if min - margin >= top {
+        Some(true)
+    } else if max + margin < bottom {
+        Some(false)
+    } else {
+        None
+    }
+}
+
+/// Density of the terrain below a height field, unless it is uniform, see
+/// [`TerrainGenerator::contents`].
+pub fn contents_below_height(
+    origin: Vector3<isize>,
+    lod: usize,
+    height: &Field<f32, 2>,
+) -> Contents<Field<f32, 3>> {
+    match uniform_below_height(origin, lod, height, 0.0) {
+        Some(solid) => Contents::uniform(solid),
+        None => Contents::Mixed(density_from_height(origin, lod, height)),
+    }
+}
+
+/// Turbulent Perlin noise heightmap.
+pub struct Heightmap {
+    noise: Turbulence<Fbm<Perlin>, Perlin>,
+}
+
+impl Default for Heightmap {
+    fn default() -> Self {
+        let mut noise = Fbm::<Perlin>::new(0);
+        noise.frequency = 0.01;
+        Self {
+            noise: Turbulence::new(noise),
+        }
+    }
+}
+
+impl Heightmap {
+    fn height(&self, origin: Vector3<isize>, lod: usize, extent: usize) -> Field<f32, 2> {
+        Field::new(extent, |[i, j]| {
+            let Vector3 { x, y, .. } = sample_position(origin, lod, [i, j, 0]);
+
+            let mut n = self.noise.get([x as f64, y as f64]) as f32;
+            n = rescale(n, -1.0..1.0, -0.2..1.0);
+            n = n.abs().powf(1.2).copysign(n);
+            n *= 50.0;
+
+            n
+        })
+    }
+}
+
+impl TerrainGenerator for Heightmap {
+    fn name(&self) -> &str {
+        "Heightmap"
+    }
+
+    fn contents(
+        &self,
+        origin: Vector3<isize>,
+        lod: usize,
+        extent: usize,
+    ) -> Contents<Field<f32, 3>> {
+        puffin::profile_function!();
+        contents_below_height(origin, lod, &self.height(origin, lod, extent))
+    }
+
+    fn sample(&self, origin: Vector3<isize>, lod: usize, extent: usize) -> Field<f32, 3> {
+        puffin::profile_function!();
+        density_from_height(origin, lod, &self.height(origin, lod, extent))
+    }
+}
+
+/// Heightmap of Perlin noise warped by Worley noise.
+pub struct Warped;
+
+impl Warped {
+    fn height(&self, origin: Vector3<isize>, lod: usize, extent: usize) -> Field<f32, 2> {
+        Field::new(extent, |[i, j]| {
+            let p = sample_position(origin, lod, [i, j, 0]);
+            let n = util::fbm(vec2(p.x, p.y) / 200.0, |p| util::warp(p, util::worley));
+            40.0 * n
+        })
+    }
+}
+
+impl TerrainGenerator for Warped {
+    fn name(&self) -> &str {
+        "Warped"
+    }
+
+    fn contents(
+        &self,
+        origin: Vector3<isize>,
+        lod: usize,
+        extent: usize,
+    ) -> Contents<Field<f32, 3>> {
+        puffin::profile_function!();
+        contents_below_height(origin, lod, &self.height(origin, lod, extent))
+    }
+
+    fn sample(&self, origin: Vector3<isize>, lod: usize, extent: usize) -> Field<f32, 3> {
+        puffin::profile_function!();
+        density_from_height(origin, lod, &self.height(origin, lod, extent))
+    }
+}
+
+/// Hills with overhangs from 3D noise, carved by large caverns and winding tunnels.
+pub struct Caves {
+    hills: Fbm<Perlin>,
+    /// Displaces the surface along all axes, which creates overhangs.
+    overhang: Fbm<Perlin>,
+    /// Caverns where the noise is high, like the holes in cheese.
+    cavern: Perlin,
+    /// Tunnels where both noises are near zero, along the intersection of their zero sets.
+    tunnels: [Fbm<Perlin>; 2],
+}
+
+impl Caves {
+    /// Amplitude of the height noise.
+    const HEIGHT: f32 = 60.0;
+    /// Amplitude of the overhang noise, by which the surface strays from the height.
+    const OVERHANG: f32 = 20.0;
+    /// Caves close off below this height, such that there are none 20 voxels further down.
+    const FLOOR: f32 = -150.0;
+    /// Radius of tunnels, in units of the noise.
+    const TUNNEL_RADIUS: f64 = 0.05;
+}
+
+impl Default for Caves {
+    fn default() -> Self {
+        Self {
+            hills: Fbm::new(1).set_octaves(4).set_frequency(0.004),
+            overhang: Fbm::new(2).set_octaves(3).set_frequency(0.02),
+            cavern: Perlin::new(3),
+            tunnels: [
+                Fbm::new(4).set_octaves(2).set_frequency(0.008),
+                Fbm::new(5).set_octaves(2).set_frequency(0.008),
+            ],
+        }
+    }
+}
+
+impl Caves {
+    fn height(&self, origin: Vector3<isize>, lod: usize, extent: usize) -> Field<f32, 2> {
+        Field::new(extent, |[i, j]| {
+            let p = sample_position(origin, lod, [i, j, 0]);
+            Self::HEIGHT * self.hills.get([p.x as f64, p.y as f64]) as f32
+        })
+    }
+
+    /// See [`TerrainGenerator::uniform`], given the height of the cube.
+    fn uniform_below(
+        &self,
+        origin: Vector3<isize>,
+        lod: usize,
+        height: &Field<f32, 2>,
+    ) -> Option<bool> {
+        // Noise may slightly exceed its nominal amplitude.
+        let solid = uniform_below_height(origin, lod, height, 1.5 * Self::OVERHANG)?;
+        // Cubes below the surface may still contain caves above the floor.
+        let top = sample_position(origin, lod, [0, 0, height.extent() - 1]).z;
+        (!solid || top < Self::FLOOR - 40.0).then_some(solid)
+    }
+
+    /// See [`TerrainGenerator::sample`], given the height of the cube.
+    fn density_below(
+        &self,
+        origin: Vector3<isize>,
+        lod: usize,
+        height: &Field<f32, 2>,
+    ) -> Field<f32, 3> {
+        Field::new(height.extent(), |[i, j, k]| {
+            let p = sample_position(origin, lod, [i, j, k]);
+            let ground = height[[i, j]] - p.z;
+            // Noise is only sampled where it can change the sign of the density.
+            if ground < -Self::OVERHANG || (ground > Self::OVERHANG && p.z < Self::FLOOR - 20.0) {
+                return ground;
+            }
+            let q = [p.x as f64, p.y as f64, p.z as f64];
+
+            // The surface gradient is mostly vertical, the 3D noise bends it sideways.
+            let surface = ground + Self::OVERHANG * self.overhang.get(q) as f32;
+
+            // Cave densities are negative within caves, in roughly voxels to their boundary.
+            // Below the floor they are raised, so that caves close off.
+            let floor = 2.0 * (Self::FLOOR - p.z).max(0.0);
+            let cavern = 0.35 - self.cavern.get(q.map(|x| x * 0.015)) as f32;
+            let [a, b] = self.tunnels.each_ref().map(|n| n.get(q).abs());
+            let tunnel = (a.hypot(b) - Self::TUNNEL_RADIUS) as f32;
+
+            surface
+                .min(60.0 * cavern + floor)
+                .min(150.0 * tunnel + floor)
+        })
+    }
+}
+
+impl TerrainGenerator for Caves {
+    fn name(&self) -> &str {
+        "Caves"
+    }
+
+    fn contents(
+        &self,
+        origin: Vector3<isize>,
+        lod: usize,
+        extent: usize,
+    ) -> Contents<Field<f32, 3>> {
+        puffin::profile_function!();
+        let height = self.height(origin, lod, extent);
+        match self.uniform_below(origin, lod, &height) {
+            Some(solid) => Contents::uniform(solid),
+            None => Contents::Mixed(self.density_below(origin, lod, &height)),
+        }
+    }
+
+    fn sample(&self, origin: Vector3<isize>, lod: usize, extent: usize) -> Field<f32, 3> {
+        puffin::profile_function!();
+        self.density_below(origin, lod, &self.height(origin, lod, extent))
+    }
+}
+
+/// A plane at zero height.
+pub struct Flat;
+
+impl TerrainGenerator for Flat {
+    fn name(&self) -> &str {
+        "Flat"
+    }
+
+    fn uniform(&self, origin: Vector3<isize>, lod: usize, extent: usize) -> Option<bool> {
+        let bottom = sample_position(origin, lod, [0, 0, 0]).z;
+        let top = sample_position(origin, lod, [0, 0, extent - 1]).z;
+        if top <= 0.0 {
+            Some(true)
+        } else if bottom > 0.0 {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    fn sample(&self, origin: Vector3<isize>, lod: usize, extent: usize) -> Field<f32, 3> {
+        Field::new(extent, |c| -sample_position(origin, lod, c).z)
+    }
+}
+
+/// Terrain below the `height` declaration of a terrain program.
+pub struct Program(pub Arc<Module>);
+
+impl Program {
+    fn height(&self, origin: Vector3<isize>, lod: usize, extent: usize) -> Field<f32, 2> {
+        self.0
+            .sample::<2>(HEIGHT, origin, lod, extent)
+            .expect("programs are validated when loaded")
+    }
+}
+
+impl TerrainGenerator for Program {
+    fn name(&self) -> &str {
+        "Program"
+    }
+
+    fn contents(
+        &self,
+        origin: Vector3<isize>,
+        lod: usize,
+        extent: usize,
+    ) -> Contents<Field<f32, 3>> {
+        puffin::profile_function!();
+        contents_below_height(origin, lod, &self.height(origin, lod, extent))
+    }
+
+    fn sample(&self, origin: Vector3<isize>, lod: usize, extent: usize) -> Field<f32, 3> {
+        puffin::profile_function!();
+        density_from_height(origin, lod, &self.height(origin, lod, extent))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cgmath::vec3;
+
+    use super::*;
+    use crate::{terrain_lang::compile, world::padded_position};
+
+    #[test]
+    fn flat_is_solid_below_zero() {
+        for key in [vec3(0, 0, 0), vec3(2, -1, -1)] {
+            let density = Flat.density(key, 1);
+            for c in density.coordinates() {
+                let z = padded_position(key, 1, c).z;
+                assert_eq!(density[c] >= 0.0, z <= 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn program_density_is_height_above_voxel() {
+        let module = compile("height: field<float, 2> |> { @x / 4 + @y }").unwrap();
+        let density = Program(Arc::new(module)).density(vec3(1, -1, 0), 2);
+        for c in density.coordinates() {
+            let p = padded_position(vec3(1, -1, 0), 2, c);
+            assert_eq!(density[c], p.x / 4.0 + p.y - p.z);
+        }
+    }
+
+    #[test]
+    fn program_contents_are_uniform_away_from_the_surface() {
+        let module = compile("height: field<float, 2> |> { @x / 4 }").unwrap();
+        let program = Program(Arc::new(module));
+        let contents = |z| program.contents(padded_origin(vec3(0, 0, z), 0), 0, padded_extent(0));
+        assert!(matches!(contents(1), Contents::Empty));
+        assert!(matches!(contents(-2), Contents::Full));
+        let Contents::Mixed(density) = contents(0) else {
+            panic!("surface chunk is uniform");
+        };
+        let expected = program.density(vec3(0, 0, 0), 0);
+        assert!(density.coordinates().all(|c| density[c] == expected[c]));
+    }
+
+    #[test]
+    fn caves_have_ceilings_within_bounds() {
+        let caves = Caves::default();
+        let density = caves.sample(vec3(0, 0, -192), 2, 96);
+        let solid = |[x, y, z]: [usize; 3]| density[[x, y, z]] >= 0.0;
+
+        // Some air lies below solid voxels, under overhangs and cave ceilings.
+        let ceilings = density
+            .coordinates()
+            .filter(|&[x, y, z]| z + 1 < 96 && !solid([x, y, z]) && solid([x, y, z + 1]))
+            .count();
+        assert!(ceilings > 100, "{ceilings} ceilings");
+
+        // Solid far below the surface, and empty far above it. Noise may slightly exceed
+        // its nominal amplitude.
+        let p = |c| sample_position(vec3(0, 0, -192), 2, c).z;
+        for c in density.coordinates() {
+            if p(c) < Caves::FLOOR - 40.0 {
+                assert!(solid(c));
+            }
+            if p(c) > 1.5 * (Caves::HEIGHT + Caves::OVERHANG) {
+                assert!(!solid(c));
+            }
+        }
+    }
+}
@@ -0,0 +1,402 @@
+use std::path::Path;
+
+use cgmath::{vec2, Vector3};
+
+use super::sample_position;
+use crate::{
+    field::{self, Field},
+    util,
+};
+
+/// What a voxel is made of, which determines its color through the [`Palette`].
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Material {
+    #[default]
+    Stone,
+    Dirt,
+    Grass,
+    Sand,
+    Snow,
+    Water,
+}
+
+impl Material {
+    pub const ALL: [Material; 6] = [
+        Material::Stone,
+        Material::Dirt,
+        Material::Grass,
+        Material::Sand,
+        Material::Snow,
+        Material::Water,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Material::Stone => "Stone",
+            Material::Dirt => "Dirt",
+            Material::Grass => "Grass",
+            Material::Sand => "Sand",
+            Material::Snow => "Snow",
+            Material::Water => "Water",
+        }
+    }
+
+    /// Inverse of `material as u32`, as stored in mesh vertices.
+    pub fn from_index(index: u32) -> Self {
+        Self::ALL[index as usize]
+    }
+}
+
+/// Linear RGB color of each material, indexed by `material as usize`.
+/// Meshes only store materials, so the palette can change without rebuilding them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Palette(pub [Vector3<f32>; Material::ALL.len()]);
+
+impl Default for Palette {
+    fn default() -> Self {
+        Self(Material::ALL.map(|material| match material {
+            Material::Stone => util::rgb(90, 90, 96),
+            Material::Dirt => util::rgb(94, 62, 36),
+            Material::Grass => util::rgb(58, 120, 30),
+            Material::Sand => util::rgb(200, 180, 110),
+            Material::Snow => util::rgb(235, 240, 245),
+            Material::Water => util::rgb(30, 80, 160),
+        }))
+    }
+}
+
+impl Palette {
+    pub fn color(&self, material: Material) -> Vector3<f32> {
+        self.0[material as usize]
+    }
+}
+
+/// Quantities which the conditions of a [`Rule`] compare, evaluated for each voxel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Quantity {
+    /// Height of the voxel.
+    Altitude,
+    /// Of the smoothed surface above the voxel, from 0 where it is flat to 1 where it is
+    /// vertical, see [`Field::steepness`].
+    Steepness,
+    /// Distance below the surface above the voxel, 0 for the voxels at the surface.
+    Depth,
+    /// Perlin noise of the horizontal position of the voxel, see [`util::perlin`].
+    Noise,
+}
+
+impl Quantity {
+    pub const ALL: [Quantity; 4] = [
+        Quantity::Altitude,
+        Quantity::Steepness,
+        Quantity::Depth,
+        Quantity::Noise,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Quantity::Altitude => "altitude",
+            Quantity::Steepness => "steepness",
+            Quantity::Depth => "depth",
+            Quantity::Noise => "noise",
+        }
+    }
+}
+
+/// Compares a quantity of a voxel with a threshold.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Condition {
+    pub quantity: Quantity,
+    /// Whether the quantity has to be below the threshold instead of above it.
+    pub below: bool,
+    pub threshold: f32,
+}
+
+/// Assigns a material to the voxels which satisfy all of its conditions.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub material: Material,
+    pub conditions: Vec<Condition>,
+}
+
+/// Ordered rules which assign materials to solid voxels, where the first matching rule wins.
+/// Rules are read from lines of the form `material: quantity < threshold, ...`, see the
+/// default rules in `materials.txt`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rules(pub Vec<Rule>);
+
+impl Default for Rules {
+    fn default() -> Self {
+        Self::parse(include_str!("materials.txt")).expect("default rules are valid")
+    }
+}
+
+impl Rules {
+    /// Wavelength of the noise, in voxels.
+    const NOISE_SCALE: f32 = 24.0;
+    /// Standard deviation of the blur applied to the surface before computing its steepness,
+    /// in voxels of the chunk.
+    const SMOOTHING: f32 = 1.0;
+
+    /// Parse rules, one per line. Empty lines and those starting with `#` are ignored.
+    pub fn parse(source: &str) -> Result<Self, String> {
+        let mut rules = Vec::new();
+        for (number, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let error = |message: String| format!("line {}: {message}", number + 1);
+
+            let (name, conditions) = line.split_once(':').unwrap_or((line, ""));
+            let name = name.trim();
+            let material = Material::ALL
+                .into_iter()
+                .find(|material| material.name().eq_ignore_ascii_case(name))
+                .ok_or_else(|| error(format!("unknown material `{name}`")))?;
+
+            let conditions = conditions
+                .split(',')
+                .map(str::trim)
+                .filter(|condition| !condition.is_empty())
+                .map(|condition| {
+                    let expected =
+                        || error(format!("expected `quantity < number` or `quantity > number`, found `{condition}`"));
+                    let (quantity, below, threshold) = match condition.split_once('<') {
+                        Some((quantity, threshold)) => (quantity, true, threshold),
+                        None => {
+                            let (quantity, threshold) =
+                                condition.split_once('>').ok_or_else(expected)?;
+                            (quantity, false, threshold)
+                        }
+                    };
+                    let quantity = quantity.trim();
+                    let quantity = Quantity::ALL
+                        .into_iter()
+                        .find(|q| q.name() == quantity)
+                        .ok_or_else(|| error(format!("unknown quantity `{quantity}`")))?;
+                    let threshold = threshold.trim().parse().map_err(|_| expected())?;
+                    Ok(Condition {
+                        quantity,
+                        below,
+                        threshold,
+                    })
+                })
+                .collect::<Result<_, String>>()?;
+
+            rules.push(Rule {
+                material,
+                conditions,
+            });
+        }
+        Ok(Self(rules))
+    }
+
+    /// Read rules from a file, see [`Rules::parse`].
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, String> {
+        let path = path.as_ref();
+        std::fs::read_to_string(path)
+            .map_err(|error| error.to_string())
+            .and_then(|source| Self::parse(&source))
+            .map_err(|error| format!("cannot load {}: {error}", path.display()))
+    }
+
+    /// Material of the first rule whose conditions hold for the quantities.
+    pub fn material(&self, quantity: impl Fn(Quantity) -> f32) -> Material {
+        self.0
+            .iter()
+            .find(|rule| {
+                rule.conditions.iter().all(|condition| {
+                    let value = quantity(condition.quantity);
+                    if condition.below {
+                        value < condition.threshold
+                    } else {
+                        value > condition.threshold
+                    }
+                })
+            })
+            .map_or(Material::default(), |rule| rule.material)
+    }
+
+    /// Materials of the voxels of a density sampled at [`sample_position`]s. The surface
+    /// above each voxel is the topmost one within its column of the density, whose height
+    /// is estimated from the density of the solid voxel below it, like in
+    /// [`density_from_height`](super::generator::density_from_height).
+    pub fn materials(
+        &self,
+        origin: Vector3<isize>,
+        lod: usize,
+        density: &Field<f32, 3>,
+    ) -> Field<Material, 3> {
+        puffin::profile_function!();
+
+        let extent = density.extent();
+        let size = (1 << lod) as f32;
+        let position = |c| sample_position(origin, lod, c);
+
+        // Depth of each solid voxel below the empty voxel above it. The density of a voxel at
+        // the top of the field stands in for its depth, since the voxels above are unknown.
+        let mut depth = Field::new(extent, |_: [usize; 3]| 0.0);
+        for [x, y] in field::coordinates(extent) {
+            for z in (0..extent).rev() {
+                depth[[x, y, z]] = match z + 1 < extent {
+                    _ if density[[x, y, z]] < 0.0 => 0.0,
+                    true if density[[x, y, z + 1]] >= 0.0 => depth[[x, y, z + 1]] + size,
+                    true => 0.0,
+                    false => density[[x, y, z]],
+                };
+            }
+        }
+
+        // Height of the topmost surface in each column, in voxels of the chunk.
+        let surface = Field::new(extent, |[x, y]| {
+            let z = (0..extent)
+                .rev()
+                .find(|&z| density[[x, y, z]] >= 0.0)
+                .unwrap_or(0);
+            (position([x, y, z]).z + density[[x, y, z]]) / size
+        });
+        let steepness = surface.blur(Self::SMOOTHING).normal().steepness();
+
+        Field::new(extent, |c @ [x, y, _]| {
+            if density[c] < 0.0 {
+                return Material::default();
+            }
+            let p = position(c);
+            self.material(|quantity| match quantity {
+                Quantity::Altitude => p.z,
+                Quantity::Steepness => steepness[[x, y]],
+                Quantity::Depth => depth[c],
+                Quantity::Noise => util::perlin(vec2(p.x, p.y) / Self::NOISE_SCALE),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use cgmath::vec3;
+
+    use super::*;
+    use crate::world::generator::density_from_height;
+
+    /// Materials of a chunk at LOD 0 below a synthetic height field, whose first voxel lies at
+    /// the given height.
+    fn below_height(
+        rules: &Rules,
+        z: isize,
+        height: impl Fn(f32, f32) -> f32,
+    ) -> Field<Material, 3> {
+        let origin = vec3(0, 0, z);
+        let height = Field::new(16, |[x, y]| height(x as f32, y as f32));
+        rules.materials(origin, 0, &density_from_height(origin, 0, &height))
+    }
+
+    #[test]
+    fn parse_rules() {
+        let rules = Rules::parse("# Comment\n\n Snow: altitude > 10, depth<1.5 \nstone").unwrap();
+        assert_eq!(
+            rules,
+            Rules(vec![
+                Rule {
+                    material: Material::Snow,
+                    conditions: vec![
+                        Condition {
+                            quantity: Quantity::Altitude,
+                            below: false,
+                            threshold: 10.0,
+                        },
+                        Condition {
+                            quantity: Quantity::Depth,
+                            below: true,
+                            threshold: 1.5,
+                        },
+                    ],
+                },
+                Rule {
+                    material: Material::Stone,
+                    conditions: vec![],
+                },
+            ])
+        );
+        assert_eq!(rules.material(|_| 20.0), Material::Stone);
+        assert_eq!(
+            rules.material(|q| if q == Quantity::Depth { 1.0 } else { 20.0 }),
+            Material::Snow
+        );
+
+        assert_eq!(
+            Rules::parse("mud: depth < 1"),
+            Err("line 1: unknown material `mud`".into())
+        );
+        assert_eq!(
+            Rules::parse("\nsand: height < 1"),
+            Err("line 2: unknown quantity `height`".into())
+        );
+        assert_eq!(
+            Rules::parse("sand: depth = 1"),
+            Err(
+                "line 1: expected `quantity < number` or `quantity > number`, found `depth = 1`"
+                    .into()
+            )
+        );
+        assert!(Rules::parse("sand: depth < one").is_err());
+        assert!(!Rules::default().0.is_empty());
+    }
+
+    #[test]
+    fn layers_below_flat_surface() {
+        let rules = Rules::parse("grass: depth < 1\ndirt: depth < 3").unwrap();
+        let materials = below_height(&rules, -8, |_, _| 0.5);
+        let column: Vec<_> = (0..10).map(|z| materials[[3, 5, z]]).collect();
+
+        // The surface voxel at height 0 is followed by two voxels of dirt.
+        assert_eq!(column[8], Material::Grass);
+        assert_eq!(column[6..8], [Material::Dirt; 2]);
+        assert_eq!(column[..6], [Material::Stone; 6]);
+    }
+
+    #[test]
+    fn default_rules_on_synthetic_terrain() {
+        let rules = Rules::default();
+        // The voxel at the surface of the column.
+        let surface =
+            |materials: &Field<Material, 3>, height: f32, z: isize, [x, y]: [usize; 2]| {
+                materials[[x, y, (height.floor() as isize - z) as usize]]
+            };
+
+        // A gentle slope is grassy apart from patches of dirt, a plateau above the snow line
+        // is covered in snow and the shore is sandy.
+        let grass = match util::perlin(vec2(8.0, 8.0) / Rules::NOISE_SCALE) < -0.5 {
+            true => Material::Dirt,
+            false => Material::Grass,
+        };
+        for (height, material) in [(10.5, grass), (40.5, Material::Snow), (1.5, Material::Sand)] {
+            let z = height as isize - 8;
+            let materials = below_height(&rules, z, |x, _| height + 0.1 * (x - 8.0));
+            assert_eq!(
+                surface(&materials, height, z, [8, 8]),
+                material,
+                "at height {height}"
+            );
+        }
+
+        // A steep ramp is rocky.
+        let materials = below_height(&rules, 10, |x, _| 10.0 + 1.5 * x);
+        assert_eq!(surface(&materials, 22.0, 10, [8, 8]), Material::Stone);
+        let materials = below_height(&rules, 10, |x, _| 10.0 + 0.5 * x);
+        assert_eq!(surface(&materials, 14.0, 10, [8, 8]), grass);
+    }
+
+    #[test]
+    fn palette_indices() {
+        assert!(Material::ALL
+            .iter()
+            .all(|&m| Material::from_index(m as u32) == m));
+        assert_eq!(
+            Palette::default().color(Material::Grass),
+            vec3(58.0, 120.0, 30.0) / 255.0
+        );
+    }
+}